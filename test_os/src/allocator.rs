@@ -1,5 +1,5 @@
 use x86_64::{
-    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB},
     VirtAddr,
 };
 
@@ -12,7 +12,9 @@ use x86_64::{
 //use linked_list::LinkedListAllocator;   // Use the linked_list_allocator crate instead
 
 pub mod fixed_size_block;
-use fixed_size_block::FixedSizeBlockAllocator;
+use fixed_size_block::{FixedSizeBlockAllocator, AllocatorStats, SizeClass, PREPOPULATED_BLOCKS};
+
+pub mod paging;
 
 #[global_allocator]
 //static ALLOCATOR: LockedHeap = LockedHeap::empty(); // uses a spinlock, so do not allocate in interrupt handlers
@@ -20,25 +22,67 @@ use fixed_size_block::FixedSizeBlockAllocator;
 //static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
-use crate::memory;
+use crate::{memory, serial_println, hlt_loop};
+use alloc::alloc::Layout;
+use core::fmt;
+use spin::Mutex;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 64 * 1024 * 1024; // Heap has total size of 64MiB
-pub const PAGE_TOTAL: usize = HEAP_SIZE / 4096;
+pub const HEAP_SIZE: usize = 64 * 1024 * 1024; // Default heap has total size of 64MiB
+
+pub const LARGE_HEAP_START: usize = 0x_6666_0000_0000;
+pub const LARGE_HEAP_SIZE: usize = 1024 * 1024 * 1024;  // Virtual only; frames are mapped on first access
+
+/// Where the kernel heap is mapped, and how large it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapConfig {
+    pub start: usize,   // must be 4KiB aligned
+    pub size: usize,    // must be a nonzero multiple of 4KiB
+}
+
+impl HeapConfig {
+    pub const DEFAULT: HeapConfig = HeapConfig { start: HEAP_START, size: HEAP_SIZE };
 
+    /// The number of 4KiB pages in the heap.
+    pub fn page_total(&self) -> usize {
+        self.size / 4096
+    }
+
+    /// Panics if the heap is not made of whole 4KiB pages.
+    fn check(&self) {
+        assert!(self.size > 0 && self.size % 4096 == 0, "heap size {:#x} is not a multiple of 4KiB", self.size);
+        assert!(self.start % 4096 == 0, "heap start {:#x} is not 4KiB aligned", self.start);
+    }
+}
+
+static HEAP_CONFIG: Mutex<Option<HeapConfig>> = Mutex::new(None);
+
+/// Returns the configuration of the heap, or None before it is initialized.
+pub fn heap_config() -> Option<HeapConfig> {
+    *HEAP_CONFIG.lock()
+}
+
+/// Maps the heap described by config with 4KiB pages, and initializes the
+/// allocator. Returns MapToError::FrameAllocationFailed without mapping
+/// anything if the memory map has too few usable frames left for the heap.
 pub fn init_heap(
+    config: HeapConfig,
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut memory::BootInfoFrameAllocator,
 ) -> Result<(), MapToError<Size4KiB>> {
+    config.check();
+    if frame_allocator.free_frame_count() < config.page_total() {
+        return Err(MapToError::FrameAllocationFailed);
+    }
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(config.start as u64);
+        let heap_end = heap_start + config.size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
-    let mut frames = frame_allocator.allocate_n_frames(PAGE_TOTAL);
+    let mut frames = frame_allocator.allocate_n_frames(config.page_total());
 
     for page in page_range {
         let frame = frames
@@ -50,13 +94,157 @@ pub fn init_heap(
         };
     }
 
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    init_allocator(config);
+    Ok(())
+}
+
+/// Maps the heap like init_heap, but with 2MiB pages wherever the heap is
+/// aligned to them, which takes a 512th of the page table entries. The
+/// unaligned start and end of the heap are mapped with 4KiB pages, as is any
+/// 2MiB page for which no aligned, contiguous physical memory remains.
+pub fn init_heap_huge(
+    config: HeapConfig,
+    mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
+    frame_allocator: &mut memory::BootInfoFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+    config.check();
+    if frame_allocator.free_frame_count() < config.page_total() {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+    let heap_start = VirtAddr::new(config.start as u64);
+    let heap_end = heap_start + config.size;    // exclusive
+    // clamped so that a heap within a single 2MiB page has no huge pages
+    let huge_start = heap_start.align_up(Size2MiB::SIZE).min(heap_end);
+    let huge_end = heap_end.align_down(Size2MiB::SIZE).max(huge_start);
+
+    map_small_pages(heap_start, huge_start, mapper, frame_allocator)?;
+    let huge_pages = Page::<Size2MiB>::range(Page::containing_address(huge_start), Page::containing_address(huge_end));
+    for page in huge_pages {
+        let frame: PhysFrame<Size2MiB> = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                map_small_pages(page.start_address(), page.start_address() + page.size(), mapper, frame_allocator)?;
+                continue;
+            }
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;   // the mapper adds HUGE_PAGE
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator as &mut dyn FrameAllocator<Size4KiB>)
+                .map_err(small_page_error)?
+                .flush()
+        };
     }
+    map_small_pages(huge_end, heap_end, mapper, frame_allocator)?;
 
+    init_allocator(config);
+    Ok(())
+}
+
+/// Maps the 4KiB pages from start to end (exclusive), both of which must be
+/// 4KiB aligned, to fresh frames.
+fn map_small_pages(
+    start: VirtAddr,
+    end: VirtAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut memory::BootInfoFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+    for page in Page::<Size4KiB>::range(Page::containing_address(start), Page::containing_address(end)) {
+        let frame = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator as &mut dyn FrameAllocator<Size4KiB>)?.flush()
+        };
+    }
     Ok(())
 }
 
+/// Reports a failure to map a 2MiB page as the equivalent 4KiB error, so
+/// that both heap initializations return the same error type.
+fn small_page_error(error: MapToError<Size2MiB>) -> MapToError<Size4KiB> {
+    match error {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) =>
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address())),
+    }
+}
+
+/// Initializes the allocator once the heap is mapped.
+fn init_allocator(config: HeapConfig) {
+    *HEAP_CONFIG.lock() = Some(config);
+    memory::register_lazy_region(VirtAddr::new(LARGE_HEAP_START as u64), LARGE_HEAP_SIZE as u64);
+    unsafe {
+        ALLOCATOR.lock().init(config.start, config.size, PREPOPULATED_BLOCKS);
+        ALLOCATOR.lock().init_large_region(LARGE_HEAP_START, LARGE_HEAP_SIZE);
+    }
+}
+
+/// Returns the current usage of the heap. Locks the allocator, so must not
+/// be called from interrupt handlers.
+pub fn stats() -> AllocatorStats {
+    ALLOCATOR.lock().stats()
+}
+
+static ALLOC_ERROR_ACTION: Mutex<fn(Layout) -> !> = Mutex::new(halt_on_alloc_error);
+static ALLOC_ERROR_WRITER: Mutex<fn(fmt::Arguments)> = Mutex::new(crate::serial::_print);
+
+/// What went wrong in a failed allocation, as printed by handle_alloc_error.
+pub struct AllocErrorReport {
+    pub layout: Layout,
+    pub size_class: SizeClass,
+    pub stats: AllocatorStats,
+}
+
+impl AllocErrorReport {
+    pub fn new(layout: Layout, stats: AllocatorStats) -> Self {
+        AllocErrorReport { layout, size_class: fixed_size_block::size_class(&layout), stats }
+    }
+}
+
+impl fmt::Display for AllocErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "allocation error: {} bytes aligned to {} from the {}",
+                 self.layout.size(), self.layout.align(), self.size_class)?;
+        writeln!(f, "  {} bytes outstanding, {} allocated and {} freed in total",
+                 self.stats.bytes_outstanding, self.stats.bytes_allocated, self.stats.bytes_freed)?;
+        writeln!(f, "  {} fallback allocations", self.stats.fallback_allocations)?;
+        write!(f, "  free blocks:")?;
+        for (size, count) in fixed_size_block::BLOCK_SIZES.iter().zip(self.stats.free_blocks.iter()) {
+            write!(f, " {}x{}", count, size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sets the action called by handle_alloc_error once the report is printed.
+pub fn set_alloc_error_action(action: fn(Layout) -> !) {
+    *ALLOC_ERROR_ACTION.lock() = action;
+}
+
+/// Sets where handle_alloc_error writes the report, which is serial by
+/// default. The writer must not allocate.
+pub fn set_alloc_error_writer(writer: fn(fmt::Arguments)) {
+    *ALLOC_ERROR_WRITER.lock() = writer;
+}
+
+/// The default action: stops the kernel, leaving the report on serial.
+pub fn halt_on_alloc_error(_layout: Layout) -> ! {
+    serial_println!("allocation error: halting");
+    hlt_loop();
+}
+
+/// Writes the failed layout, its size class and the heap usage to the
+/// configured writer, then calls the configured action. The allocator has
+/// already released its lock when it returns null, so the stats can be read
+/// here.
+pub fn handle_alloc_error(layout: Layout) -> ! {
+    let write = *ALLOC_ERROR_WRITER.lock();
+    write(format_args!("{}\n", AllocErrorReport::new(layout, stats())));
+    let action = *ALLOC_ERROR_ACTION.lock();
+    action(layout)
+}
+
 /// A wrapper around spin::Mutex to permit trait implementation.
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
@@ -81,3 +269,22 @@ impl<A> Locked<A> {
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
+
+#[test_case]
+fn test_alloc_error_report() {
+    use alloc::format;
+
+    let stats = AllocatorStats {
+        bytes_allocated: 3000,
+        bytes_freed: 1000,
+        bytes_outstanding: 2000,
+        fallback_allocations: 5,
+        free_blocks: [1, 2, 3, 4, 5, 6, 7, 8],
+    };
+    let report = AllocErrorReport::new(Layout::from_size_align(100, 8).unwrap(), stats);
+    assert_eq!(format!("{}", report),
+               "allocation error: 100 bytes aligned to 8 from the 128-byte blocks\n\
+                \x20 2000 bytes outstanding, 3000 allocated and 1000 freed in total\n\
+                \x20 5 fallback allocations\n\
+                \x20 free blocks: 1x8 2x16 3x32 4x64 5x128 6x256 7x1024 8x2048");
+}
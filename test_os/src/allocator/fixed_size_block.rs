@@ -4,20 +4,23 @@
  * The fixed size block allocator provides good performance for a range of uses,
  * at the cost of greater internal fragmentation than other allocation schemes.
  * This implementation relies on the linked_list_allocator crate to provide a
- * fallback allocator. Several improvements could be made:
- *  1.  replace this linked list fallback allocator with an allocator based on
- *      paging, which maps a continuous block of virtual memory to non-
- *      continuous physical frames
- *  2.  pre-populate the list heads array with available blocks, ideally in a
- *      way which leverages doing so in bulk and which is specialized to the
- *      application running on the kernel
- *  3.  optimize the block sizes according to the most frequently allocated
- *      data structures or types
+ * fallback allocator, and on a paging allocator for large allocations, which
+ * maps a continuous block of virtual memory to non-continuous physical frames.
+ *
+ * The free lists can be pre-populated in bulk when the allocator is
+ * initialized, carving one region per block size out of the heap, so that the
+ * first allocations of each size need not go through the fallback allocator.
+ * For example, allocating 1000 32-byte nodes takes 1000 fallback allocations
+ * from empty lists, but 936 with PREPOPULATED_BLOCKS (64) blocks per size.
+ *
+ * A further improvement would be to optimize the block sizes, and the number
+ * of blocks pre-populated for each, according to the most frequently
+ * allocated data structures or types.
  */
 
 use alloc::alloc::Layout;
-use core::{mem, ptr::{self, NonNull}};
-use super::Locked;
+use core::{fmt, mem, ptr::{self, NonNull}};
+use super::{Locked, paging::PagingAllocator};
 use alloc::alloc::GlobalAlloc;
 
 /// The block sizes to use.
@@ -25,7 +28,19 @@ use alloc::alloc::GlobalAlloc;
 /// The sizes must each be a power of 2 because they are also used as the block
 /// alignment (alignments must always be powers of 2). If wishing to use blocks
 /// that are not powers of 2, define a second BLOCK_ALIGNMENT array.
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 1024, 2048];
+
+/// The number of blocks of each size with which the kernel heap's free lists
+/// are pre-populated.
+pub const PREPOPULATED_BLOCKS: usize = 64;
+
+/// Allocations of at least this many bytes are served by the paging allocator
+/// rather than the linked list allocator.
+const LARGE_ALLOCATION_SIZE: usize = 64 * 1024;
+
+/// With the poison-freed-blocks feature, fills the bytes of free blocks after
+/// their ListNode, so that reading freed memory gives obviously invalid data.
+pub const POISON_BYTE: u8 = 0xde;
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
@@ -34,6 +49,50 @@ struct ListNode {
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    large_allocator: PagingAllocator,
+    stats: AllocatorStats,  // updated under the same lock as the lists
+}
+
+/// A snapshot of heap usage, counting the sizes requested by each layout
+/// rather than the sizes of the blocks which served them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorStats {
+    pub bytes_allocated: usize,     // in total, since the heap was initialized
+    pub bytes_freed: usize,
+    pub bytes_outstanding: usize,
+    /// The number of allocations which no free block could serve.
+    pub fallback_allocations: usize,
+    /// The number of free blocks of each size in BLOCK_SIZES.
+    pub free_blocks: [usize; BLOCK_SIZES.len()],
+}
+
+/// The part of the allocator which serves a layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeClass {
+    Block(usize),   // the block size
+    Fallback,
+    Large,
+}
+
+impl fmt::Display for SizeClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SizeClass::Block(size) => write!(f, "{}-byte blocks", size),
+            SizeClass::Fallback => write!(f, "linked list fallback"),
+            SizeClass::Large => write!(f, "paging allocator"),
+        }
+    }
+}
+
+/// Returns the part of the allocator which serves the layout. Block sizes
+/// whose free list is empty are still served by the block size, whose block
+/// is then taken from the fallback allocator.
+pub fn size_class(layout: &Layout) -> SizeClass {
+    match list_index(layout) {
+        Some(index) => SizeClass::Block(BLOCK_SIZES[index]),
+        None if layout.size() >= LARGE_ALLOCATION_SIZE => SizeClass::Large,
+        None => SizeClass::Fallback,
+    }
 }
 
 impl FixedSizeBlockAllocator {
@@ -43,20 +102,90 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            large_allocator: PagingAllocator::new(),
+            stats: AllocatorStats {
+                bytes_allocated: 0,
+                bytes_freed: 0,
+                bytes_outstanding: 0,
+                fallback_allocations: 0,
+                free_blocks: [0; BLOCK_SIZES.len()],
+            },
         }
     }
 
-    /// Initialize the allocator with the given heap bounds.
+    /// Returns the current heap usage.
+    pub fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+
+    fn record_alloc(&mut self, size: usize) {
+        self.stats.bytes_allocated += size;
+        self.stats.bytes_outstanding += size;
+    }
+
+    fn record_dealloc(&mut self, size: usize) {
+        self.stats.bytes_freed += size;
+        self.stats.bytes_outstanding -= size;
+    }
+
+    /// Initialize the allocator with the given heap bounds, pre-populating the
+    /// free list of each block size with up to `blocks_per_size` blocks.
     ///
     /// This function is unsafe because the caller must guarantee that the given
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
-    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize, blocks_per_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
+        if blocks_per_size == 0 {
+            return;
+        }
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            // one allocation per block size, rather than one per block
+            let layout = Layout::from_size_align(block_size * blocks_per_size, block_size).unwrap();
+            let region = match self.fallback_allocator.allocate_first_fit(layout) {
+                Ok(region) => region.as_ptr(),
+                Err(_) => return,   // the heap is too small, so leave the remaining lists empty
+            };
+            for block in 0..blocks_per_size {
+                self.push_free_block(index, region.add(block * block_size));
+            }
+        }
     }
 
-    /// Allocates using the fallback allocator.
+    /// Initialize the virtual region used for large allocations.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// region is unused and mapped on demand. This method must be called only
+    /// once.
+    pub unsafe fn init_large_region(&mut self, region_start: usize, region_size: usize) {
+        self.large_allocator.init(region_start, region_size);
+    }
+
+    /// Adds the block to the free list of the given block size.
+    ///
+    /// Unsafe since the block must be unused and of that size.
+    unsafe fn push_free_block(&mut self, index: usize, block: *mut u8) {
+        let new_node = ListNode {
+            next: self.list_heads[index].take()
+        };
+        // verify that block has size and alignment required for storing node
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+        let new_node_ptr = block as *mut ListNode;
+        new_node_ptr.write(new_node);
+        #[cfg(feature = "poison-freed-blocks")]
+        poison(block, BLOCK_SIZES[index]);
+        self.list_heads[index] = Some(&mut *new_node_ptr);
+        self.stats.free_blocks[index] += 1;
+    }
+
+    /// Allocates using the fallback allocator, or the paging allocator for
+    /// large allocations.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.stats.fallback_allocations += 1;
+        if layout.size() >= LARGE_ALLOCATION_SIZE {
+            return self.large_allocator.alloc(layout);
+        }
         match self.fallback_allocator.allocate_first_fit(layout) {
             // allocate_first_fit() returns a Result<NonNull<u8>, AllocErr>
             Ok(ptr) => ptr.as_ptr(),    // returns ptr.pointer as *mut u8
@@ -68,12 +197,16 @@ impl FixedSizeBlockAllocator {
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
                 match allocator.list_heads[index].take() {
                     Some(node) => {
                         allocator.list_heads[index] = node.next.take(); // take() sets pointer to null and returns previous value
-                        node as *mut ListNode as *mut u8
+                        allocator.stats.free_blocks[index] -= 1;
+                        let ptr = node as *mut ListNode as *mut u8;
+                        #[cfg(feature = "poison-freed-blocks")]
+                        check_poison(ptr, BLOCK_SIZES[index]);
+                        ptr
                     }
                     None => {
                         // no block exists in list => allocate new block
@@ -86,22 +219,20 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 }
             }
             None => allocator.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            allocator.record_alloc(layout.size());
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.record_dealloc(layout.size());
         match list_index(&layout) {
-            Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take()
-                };
-                // verify that block has size and alignment required for storing node
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+            Some(index) => allocator.push_free_block(index, ptr),
+            None if allocator.large_allocator.contains(ptr) => {
+                allocator.large_allocator.dealloc(ptr, layout);
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
@@ -109,6 +240,28 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             }
         }
     }
+
+    /// Keeps the block in place if the new size needs the same block size,
+    /// rather than allocating, copying, and freeing as the default does.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (list_index(&layout), list_index(&new_layout)) {
+            (Some(index), Some(new_index)) if index == new_index => {
+                let mut allocator = self.lock();
+                allocator.record_dealloc(layout.size());    // counted as freeing and allocating again
+                allocator.record_alloc(new_size);
+                ptr
+            }
+            _ => {
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
 }
 
 /// Choose an appropriate block size for the given layout.
@@ -118,3 +271,55 @@ fn list_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
+
+/// Fills the block, except for its ListNode, with POISON_BYTE.
+#[cfg(feature = "poison-freed-blocks")]
+unsafe fn poison(block: *mut u8, block_size: usize) {
+    let node_size = mem::size_of::<ListNode>();
+    ptr::write_bytes(block.add(node_size), POISON_BYTE, block_size - node_size);
+}
+
+/// Panics if anything wrote to the block while it was free.
+#[cfg(feature = "poison-freed-blocks")]
+unsafe fn check_poison(block: *mut u8, block_size: usize) {
+    let node_size = mem::size_of::<ListNode>();
+    let poisoned = core::slice::from_raw_parts(block.add(node_size), block_size - node_size);
+    if let Some(offset) = poisoned.iter().position(|&byte| byte != POISON_BYTE) {
+        panic!("freed block at {:p} was written at offset {}", block, node_size + offset);
+    }
+}
+
+#[test_case]
+fn test_prepopulated_lists_avoid_fallback() {
+    const HEAP_SIZE: usize = 512 * 1024;
+    #[repr(align(4096))]
+    struct Heap([u8; HEAP_SIZE]);
+    static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+
+    // allocates 1000 32-byte nodes from a fresh allocator, returning the number of fallback allocations
+    fn fallback_allocations_for_nodes(blocks_per_size: usize) -> usize {
+        let allocator = Locked::new(FixedSizeBlockAllocator::new());
+        unsafe { allocator.lock().init(ptr::addr_of_mut!(HEAP) as usize, HEAP_SIZE, blocks_per_size); }
+        let free_blocks = allocator.lock().stats().free_blocks;
+        assert_eq!(free_blocks, [blocks_per_size; BLOCK_SIZES.len()]);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        for _ in 0..1000 {
+            assert!(!unsafe { allocator.alloc(layout) }.is_null());
+        }
+        let stats = allocator.lock().stats();
+        stats.fallback_allocations
+    }
+
+    assert_eq!(fallback_allocations_for_nodes(0), 1000);
+    assert_eq!(fallback_allocations_for_nodes(PREPOPULATED_BLOCKS), 1000 - PREPOPULATED_BLOCKS);
+}
+
+#[test_case]
+fn test_size_class() {
+    let class = |size, align| size_class(&Layout::from_size_align(size, align).unwrap());
+    assert_eq!(class(1, 1), SizeClass::Block(8));
+    assert_eq!(class(100, 8), SizeClass::Block(128));
+    assert_eq!(class(8, 512), SizeClass::Block(1024));
+    assert_eq!(class(4096, 8), SizeClass::Fallback);
+    assert_eq!(class(LARGE_ALLOCATION_SIZE, 8), SizeClass::Large);
+}
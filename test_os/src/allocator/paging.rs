@@ -0,0 +1,108 @@
+/* Paging Allocator
+ *
+ * The paging allocator serves large allocations from a dedicated region of
+ * virtual memory which is registered for lazy mapping, so that each page is
+ * backed by a physical frame only when it is first touched. Since the region
+ * is contiguous in virtual memory, large buffers (such as full-image pixel
+ * arrays) never need contiguous physical frames, and they do not fragment the
+ * linked list heap used for medium-sized allocations.
+ *
+ * Allocations are rounded up to whole pages. Freed ranges keep their frames
+ * mapped and are reused first-fit by later allocations, with adjacent freed
+ * ranges coalesced. The allocator must not allocate itself, so freed ranges
+ * are tracked in a fixed-size array; if it is full, the freed range is leaked.
+ */
+
+use super::align_up;
+use alloc::alloc::Layout;
+use core::ptr;
+
+const PAGE_SIZE: usize = 4096;
+const MAX_FREE_RANGES: usize = 32;
+
+pub struct PagingAllocator {
+    region_start: usize,
+    next: usize,    // start of the virtual memory which has never been allocated
+    region_end: usize,
+    free_ranges: [Option<(usize, usize)>; MAX_FREE_RANGES], // (start, size) of freed ranges
+}
+
+impl PagingAllocator {
+    /// Creates an empty PagingAllocator.
+    pub const fn new() -> Self {
+        PagingAllocator {
+            region_start: 0,
+            next: 0,
+            region_end: 0,
+            free_ranges: [None; MAX_FREE_RANGES],
+        }
+    }
+
+    /// Initialize the allocator with the given virtual region.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// region is unused and that its pages are mapped on demand (see
+    /// memory::register_lazy_region). This method must be called only once.
+    pub unsafe fn init(&mut self, region_start: usize, region_size: usize) {
+        self.region_start = region_start;
+        self.next = region_start;
+        self.region_end = region_start + region_size;
+    }
+
+    /// Returns whether the given pointer was allocated by this allocator.
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        let addr = ptr as usize;
+        self.region_start <= addr && addr < self.region_end
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        if layout.align() > PAGE_SIZE {
+            return ptr::null_mut();
+        }
+        let size = align_up(layout.size(), PAGE_SIZE);
+        for slot in self.free_ranges.iter_mut() {
+            if let Some((start, free_size)) = *slot {
+                if free_size >= size {
+                    *slot = if free_size > size {
+                        Some((start + size, free_size - size))
+                    } else {
+                        None
+                    };
+                    return start as *mut u8;
+                }
+            }
+        }
+        match self.next.checked_add(size) {
+            Some(end) if end <= self.region_end => {
+                let start = self.next;
+                self.next = end;
+                start as *mut u8
+            }
+            _ => ptr::null_mut(),   // out of virtual memory
+        }
+    }
+
+    pub fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut start = ptr as usize;
+        let mut size = align_up(layout.size(), PAGE_SIZE);
+        // absorb any freed ranges which are adjacent to this one
+        for slot in self.free_ranges.iter_mut() {
+            if let Some((free_start, free_size)) = *slot {
+                if free_start + free_size == start {
+                    start = free_start;
+                    size += free_size;
+                    *slot = None;
+                } else if start + size == free_start {
+                    size += free_size;
+                    *slot = None;
+                }
+            }
+        }
+        if start + size == self.next {
+            self.next = start;  // return the range to the never-allocated memory
+        } else if let Some(slot) = self.free_ranges.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((start, size));
+        }
+        // otherwise, leak the range rather than allocating to track it
+    }
+}
@@ -0,0 +1,71 @@
+/* Benchmarks
+ *
+ * Measures elapsed time in timer ticks, so results are coarse (one tick per
+ * timer interrupt) and only meaningful for work spanning many ticks; repeat
+ * short operations to compensate. Results are reported over serial so that
+ * the host can record them. Run the benchmarks with `cargo test`.
+ */
+
+use crate::{interrupts::{ticks, ticks_to_ms}, serial_println};
+
+/// Runs the closure once, returning the number of timer ticks it took.
+pub fn time_it<F: FnOnce()>(f: F) -> u64 {
+    let start = ticks();
+    f();
+    ticks() - start
+}
+
+/// Runs the closure `iterations` times and reports the total number of ticks
+/// over serial, returning that total.
+pub fn bench<F: FnMut()>(name: &str, iterations: usize, mut f: F) -> u64 {
+    let ticks = time_it(|| {
+        for _ in 0..iterations {
+            f();
+        }
+    });
+    serial_println!("bench {}: {} iterations in {} ticks (~{} ms)", name, iterations, ticks, ticks_to_ms(ticks));
+    ticks
+}
+
+#[cfg(test)]
+fn bench_thumbnail(name: &str, png: &[u8]) {
+    use crate::png;
+    use alloc::vec::Vec;
+
+    bench(name, 10, || {
+        let options = png::ThumbnailOptions {
+            max_width: 50, max_height: 50, zoom_to_fill: true, ..Default::default()
+        };
+        png::generate_thumbnail(Vec::from(png), &options).expect("thumbnail generation failed");
+    });
+}
+
+#[test_case]
+fn bench_thumbnail_small() {
+    bench_thumbnail("thumbnail 75x75", include_bytes!("../75x75.png"));
+}
+
+#[test_case]
+fn bench_thumbnail_medium() {
+    bench_thumbnail("thumbnail 150x75", include_bytes!("../150x75.png"));
+}
+
+#[test_case]
+fn bench_thumbnail_interlaced() {
+    bench_thumbnail("thumbnail 100x100 interlaced", include_bytes!("../100x100_interlaced.png"));
+}
+
+#[test_case]
+fn bench_thumbnail_large() {
+    bench_thumbnail("thumbnail 2982x32", include_bytes!("../larger.png"));
+}
+
+#[test_case]
+fn bench_allocator() {
+    use alloc::boxed::Box;
+
+    bench("allocate and free 32-byte boxes", 10000, || {
+        let value = Box::new([0u64; 4]);
+        core::hint::black_box(&value);
+    });
+}
@@ -4,6 +4,9 @@ use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector
 use lazy_static::lazy_static;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+// the page fault handler may map pages and print, so needs more stack than a double fault
+pub const PAGE_FAULT_STACK_SIZE: usize = 4096 * 5;
 
 pub fn init() {
     use x86_64::registers::segmentation::CS;
@@ -33,10 +36,23 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            // a known-good stack, since the fault may be an overflow of the current one
+            static mut STACK: [u8; PAGE_FAULT_STACK_SIZE] = [0; PAGE_FAULT_STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + PAGE_FAULT_STACK_SIZE
+        };
         tss
     };
 }
 
+/// Returns the top of the stack which the CPU switches to for interrupts
+/// whose IDT entries use the given interrupt stack table index.
+pub fn interrupt_stack_top(ist_index: u16) -> VirtAddr {
+    TSS.interrupt_stack_table[ist_index as usize]
+}
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
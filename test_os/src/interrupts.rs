@@ -2,13 +2,14 @@
 #![allow(unused_imports)]
 
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::{gdt, print, println, serial_println, hlt_loop, vga_buffer, serial::SERIAL1, png, QemuExitCode, exit_qemu};
+use crate::{gdt, memory, log, print, println, serial_println, hlt_loop, vga_buffer, serial::{SERIAL1, SERIAL2, RawSender}, png, QemuExitCode, exit_qemu};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 use miniz_oxide;
 use alloc::vec::Vec;
 use uart_16550::SerialPort;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -33,19 +34,34 @@ impl InterruptIndex {
     pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// Returns the bit corresponding to this interrupt in the primary PIC's
+    /// mask register, where a set bit disables (masks) the interrupt.
+    fn as_pic_mask_bit(self) -> u8 {
+        1 << (self.as_u8() - PIC_1_OFFSET)
+    }
+
     pub fn as_pic_enable_mask(self) -> u8 {
-        let diff = self.as_usize() - InterruptIndex::LegacyTimer.as_usize();
-        let mask = 0xff & !(1 << diff);
-        mask as u8
+        InterruptIndex::pic_enable_mask(&[self])
+    }
+
+    /// Builds a mask for the primary PIC which enables exactly the given
+    /// interrupts and masks off all others.
+    pub fn pic_enable_mask(enabled: &[InterruptIndex]) -> u8 {
+        enabled.iter().fold(0xff, |mask, index| mask & !index.as_pic_mask_bit())
     }
 }
 
 lazy_static! {  // IDT will be initialized when it is referenced the first time
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         unsafe {
+            idt.page_fault.set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+                // a fault on the guard page below an overflowing stack cannot push to that stack
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
                 // assumes that the IST index is valid and not already used for another exception
@@ -54,7 +70,11 @@ lazy_static! {  // IDT will be initialized when it is referenced the first time
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
         idt[InterruptIndex::Serial1.as_usize()].set_handler_fn(serial_interrupt_handler);
         idt[InterruptIndex::Serial2.as_usize()].set_handler_fn(serial_interrupt_handler_two);
-        idt[0x80].set_handler_fn(syscall_interrupt_handler);
+        unsafe {
+            idt[0x80].set_handler_addr(x86_64::VirtAddr::new(crate::syscall::syscall_entry as u64))
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+                // the entry stub preserves registers and returns with iretq; ring 3 may invoke it
+        }
         idt
     };
 }
@@ -65,22 +85,42 @@ pub fn init_idt() {
 
 pub unsafe fn init_pics() {
     PICS.lock().initialize();
-    let keyboard_enable = InterruptIndex::Keyboard.as_pic_enable_mask();
-    let serial_enable = InterruptIndex::Serial1.as_pic_enable_mask()
-        & InterruptIndex::Serial2.as_pic_enable_mask();
+    let primary_mask = InterruptIndex::pic_enable_mask(&[
+        InterruptIndex::LegacyTimer,    // drives the tick counter
+        InterruptIndex::Keyboard,
+        InterruptIndex::Serial1,
+        InterruptIndex::Serial2,
+    ]);
     SERIAL1.lock().init();
-    PICS.lock().write_masks(keyboard_enable & serial_enable, 0xff);
+    SERIAL2.lock().init();
+    PICS.lock().write_masks(primary_mask, 0xff);  // all secondary PIC interrupts remain masked
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+// Returning from a fault re-executes the faulting instruction, so these two
+// handlers panic, reporting the fault rather than looping on it
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
     use x86_64::registers::control::Cr2;    // CR2 register is set by CPU on page fault
 
+    let address = Cr2::read();
+    if memory::handle_page_fault(address, error_code) {
+        return; // page was lazily mapped, so the faulting instruction can be retried
+    }
+
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {:?}", address);
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
     hlt_loop();
@@ -93,9 +133,42 @@ extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame,
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Input clock of the programmable interval timer (PIT), in Hz.
+pub const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+/// The PIT is left at its power-on divisor of 65536, so the timer interrupt
+/// fires at 1193182 / 65536, about 18.2 Hz, or once every 54.9 ms.
+pub const PIT_DIVISOR: u64 = 65536;
+
+/// Returns the number of timer interrupts since the PICs were initialized.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Converts a number of timer ticks to milliseconds, rounding down.
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * PIT_DIVISOR * 1000 / PIT_FREQUENCY_HZ
+}
+
+/// Converts a number of milliseconds to timer ticks, rounding up so that a
+/// timeout of that many ticks lasts at least as long as requested.
+pub const fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * PIT_FREQUENCY_HZ + PIT_DIVISOR * 1000 - 1) / (PIT_DIVISOR * 1000)
+}
+
+/// Returns the time since the PICs were initialized in milliseconds, with the
+/// resolution of one tick.
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(ticks())
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     // print!(".");
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
     unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::LegacyTimer.as_u8()); }  // using the wrong interrupt index is dangerous
+    crate::task::timer::wake_expired(ticks);
+    crate::task::watchdog::check(ticks);    // after EOI, since a firing watchdog does not return
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
@@ -110,103 +183,65 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     // using the wrong interrupt index is dangerous
 }
 
-fn read_serial_png() -> Option<Vec<u8>> {
-    let mut raw_data: Vec<u8> = Vec::new();
-    // Verify that first 8 bytes match the png signature
-    for i in 0..8 {
-        let serial_byte = SERIAL1.lock().receive();
-        raw_data.push(serial_byte);
-        if serial_byte != png::PNG_SIGNATURE[i] {
-            // Invalid png, so print what it was and then return
-            println!("Invalid byte {:02x?}", serial_byte);
-            return None;
-        }
-    }
-    println!("Valid PNG signature");
-    loop {
-        let mut length: u32 = 0;
-        let mut type_arr: [u8; 4] = [0; 4];
-        for _ in 0..4 {
-            let new_byte: u8 = SERIAL1.lock().receive();
-            raw_data.push(new_byte);
-            length <<= 8;
-            length += new_byte as u32;
-        }
-        for i in 0..4 {
-            let new_byte: u8 = SERIAL1.lock().receive();
-            raw_data.push(new_byte);
-            type_arr[i] = new_byte;
-        }
-        for _ in 0..length+4 {  // include the four crc bytes
-            raw_data.push(SERIAL1.lock().receive());
-        }
-        if &type_arr == "IEND".as_bytes() {
-            println!("Read IEND chunk, break from loop");
-            break;
-        } else if &type_arr == "IHDR".as_bytes() {
-            println!("Read IHDR chunk");
-        } else if &type_arr == "IDAT".as_bytes() {
-            print!("Read IDAT chunk... ");
-        } else {
-            println!("Read chunk with unexpected type: {:?}", type_arr);
-        }
-    }
-    return Some(raw_data);
-}
-
 extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    println!("Serial interrupt");
-    let raw_data: Vec<u8>;
-    match read_serial_png() {
-        Some(data) => raw_data = data,
-        None => {
-            unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Serial1.as_u8()); };
-            return;
-        },
-    }
-    let max_width: usize = 150;
-    let max_height: usize = 150;
-    let zoom_to_fill: bool = true;
-    let new_png: Vec<u8> = match png::generate_thumbnail(raw_data, max_width, max_height, zoom_to_fill) {
-        Ok(data) => data,
-        Err(e) => {
-            serial_println!("Error when generating thumbnail: {:?}", e);
-            exit_qemu(QemuExitCode::Failed);
-            Vec::new()
-        }
-    };
-    for byte in new_png {
-        SERIAL1.lock().send_raw(byte);
+    use x86_64::instructions::port::Port;
+
+    let mut data_port: Port<u8> = Port::new(0x3f8);     // COM1 data register
+    let mut line_status_port: Port<u8> = Port::new(0x3fd);
+    // the receive FIFO may hold several bytes, so read until it is empty
+    while unsafe { line_status_port.read() } & 1 != 0 {    // bit 0: data ready
+        let byte: u8 = unsafe { data_port.read() };
+        crate::task::serial::COM1_QUEUE.add_byte(byte);
     }
-    exit_qemu(QemuExitCode::Success);
+
     unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Serial1.as_u8()); }
     // using the wrong interrupt index is dangerous
 }
 
 extern "x86-interrupt" fn serial_interrupt_handler_two(_stack_frame: InterruptStackFrame) {
-    println!("Serial Interrupt Two!");
-
-    // use x86_64::instructions::port::Port;
-    //
-    // let mut port = Port::new(0x3f8); // PS/2 data port is I/O port 0x60
-    //
-    // println!("Serial Interrupt Two!");
-    // let scancode: u8 = unsafe { port.read() };  // must read scancode from the port before another keyboard interrupt can be handled
-    // println!("{:?}", scancode);
-    //
-    // //
-    // unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8()); }
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x2f8);    // COM2 data register is at the base of I/O port 0x2f8
+    let byte: u8 = unsafe { port.read() };  // must read the byte to clear the interrupt and receive the next one
+
+    crate::task::serial::add_byte(byte);
+
+    unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Serial2.as_u8()); }
     // using the wrong interrupt index is dangerous
 }
 
+#[test_case]
+fn test_pic_enable_mask_single() {
+    assert_eq!(InterruptIndex::LegacyTimer.as_pic_enable_mask(), 0b1111_1110);
+    assert_eq!(InterruptIndex::Keyboard.as_pic_enable_mask(), 0b1111_1101);
+    assert_eq!(InterruptIndex::Serial1.as_pic_enable_mask(), 0b1110_1111);
+}
 
+#[test_case]
+fn test_pic_enable_mask_combined() {
+    let mask = InterruptIndex::pic_enable_mask(&[
+        InterruptIndex::Keyboard,
+        InterruptIndex::Serial1,
+        InterruptIndex::Serial2,
+    ]);
+    assert_eq!(mask, 0b1110_0101);  // only IRQ 1 (keyboard), 3 (COM2), and 4 (COM1) enabled
+    assert_eq!(InterruptIndex::pic_enable_mask(&[]), 0xff);
+}
 
-extern "x86-interrupt" fn syscall_interrupt_handler(_stack_frame: InterruptStackFrame,) {
-    unsafe {
-        println!("{:?} {:?}", _stack_frame.stack_pointer, (*_stack_frame.stack_pointer.as_ptr::<*const i32>()));
-    }
-    println!("TRIGGERED SYSCALL");
-    hlt_loop();
+#[test_case]
+fn test_ticks_to_ms() {
+    assert_eq!(ticks_to_ms(0), 0);
+    assert_eq!(ticks_to_ms(1), 54);         // 54.9 ms per tick
+    assert_eq!(ticks_to_ms(182), 9996);     // about 18.2 ticks per second
+}
+
+#[test_case]
+fn test_ms_to_ticks() {
+    assert_eq!(ms_to_ticks(0), 0);
+    assert_eq!(ms_to_ticks(1), 1);          // shorter than a tick still waits for one
+    assert_eq!(ms_to_ticks(2_000), 37);
+    assert_eq!(ms_to_ticks(10_000), 183);
+    assert!(ticks_to_ms(ms_to_ticks(10_000)) >= 10_000);
 }
 
 #[test_case]
@@ -21,31 +21,45 @@ pub mod memory;
 pub mod allocator;
 pub mod task;
 pub mod png;
+pub mod log;
+pub mod bench;
+pub mod random;
+pub mod syscall;
 use bootloader::BootInfo;
 use x86_64::VirtAddr;
 
 #[cfg(test)]
-use bootloader::{BootInfo, entry_point};
+use bootloader::entry_point;
 
 #[cfg(test)]
 entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-    init();
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
     test_main();
     hlt_loop();
 }
 
 pub fn init(boot_info: &'static BootInfo) {
+    init_with_heap(boot_info, allocator::HeapConfig::DEFAULT);
+}
+
+/// Initializes the kernel like init, but with the given heap rather than the
+/// default, e.g. a smaller one to test running out of memory.
+pub fn init_with_heap(boot_info: &'static BootInfo, heap: allocator::HeapConfig) {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::init_pics()};    // unsafe because PICS must be configured correctly
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap_huge(heap, &mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    task::serial::init_queue();     // must follow heap initialization, since the queue is heap allocated
+    task::thumbnail::init_queue();
+    log::init_queue();
+    memory::init_manager(mapper, frame_allocator);  // retained for on-demand mapping
     x86_64::instructions::interrupts::enable();         // Enable interrupts
 }
 
@@ -85,6 +99,58 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();
 }
 
+/// Collects the start of formatted text, such as a panic message, without
+/// allocating, since formatting is the only way to inspect it. Text past the
+/// capacity is discarded.
+pub struct MessageBuffer {
+    bytes: [u8; 512],
+    len: usize,
+}
+
+impl MessageBuffer {
+    pub const fn new() -> Self {
+        MessageBuffer { bytes: [0; 512], len: 0 }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Returns true if the collected text contains the given text.
+    pub fn contains(&self, text: &str) -> bool {
+        let text = text.as_bytes();
+        text.is_empty() || self.as_bytes().windows(text.len()).any(|window| window == text)
+    }
+}
+
+impl core::fmt::Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len < self.bytes.len() {
+                self.bytes[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Panic handler for tests which are meant to panic: succeeds if the panic
+/// message contains the expected text, and otherwise fails like
+/// test_panic_handler.
+pub fn expected_panic_handler(info: &PanicInfo, expected: &str) -> ! {
+    use core::fmt::Write;
+
+    let mut message = MessageBuffer::new();
+    let _ = write!(message, "{}", info);
+    if !message.contains(expected) {
+        test_panic_handler(info);
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop();
+}
+
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -93,7 +159,7 @@ fn panic(info: &PanicInfo) -> ! {
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    allocator::handle_alloc_error(layout)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -103,6 +169,22 @@ pub enum QemuExitCode {
     Failed = 0x11,
 }
 
+impl QemuExitCode {
+    /// Maps the result of an application to an exit code, where only a result
+    /// of 0 indicates success.
+    pub fn from_result(result: u32) -> QemuExitCode {
+        match result {
+            0 => QemuExitCode::Success,
+            _ => QemuExitCode::Failed,
+        }
+    }
+}
+
+/// Exits qemu with the given exit code, which qemu reports to the host as
+/// `(exit_code << 1) | 1`.
+///
+/// Requires the isa-debug-exit device at port 0xf4, which is configured by both
+/// the test-args and run-args in Cargo.toml; without it, this has no effect.
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
@@ -111,3 +193,26 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
         port.write(exit_code as u32);
     }
 }
+
+#[test_case]
+fn test_exit_code_from_result() {
+    assert_eq!(QemuExitCode::from_result(0), QemuExitCode::Success);
+    assert_eq!(QemuExitCode::from_result(1), QemuExitCode::Failed);
+    assert_eq!(QemuExitCode::from_result(u32::MAX), QemuExitCode::Failed);
+}
+
+#[test_case]
+fn test_message_buffer() {
+    use core::fmt::Write;
+
+    let mut message = MessageBuffer::new();
+    write!(message, "EXCEPTION: {} at {:#x}", "DIVIDE ERROR", 0x1234).unwrap();
+    assert!(message.contains("DIVIDE ERROR at 0x1234"));
+    assert!(!message.contains("INVALID OPCODE"));
+    // text past the capacity is discarded rather than overflowing
+    for _ in 0..100 {
+        write!(message, "0123456789").unwrap();
+    }
+    assert_eq!(message.as_bytes().len(), 512);
+    assert!(message.as_bytes().starts_with(b"EXCEPTION"));
+}
@@ -0,0 +1,161 @@
+/* Interrupt-safe logging
+ *
+ * Interrupt handlers must not print directly, since printing locks the VGA
+ * writer, which deadlocks if the interrupted code already holds that lock.
+ * Instead, log! formats each message into a fixed-size record on the stack and
+ * pushes it onto a lock-free queue, without locking or allocating. The
+ * drain_log task then prints queued messages from normal (non-interrupt)
+ * context. Messages go to the VGA buffer only, since the serial port may be
+ * carrying binary data such as PNG files.
+ */
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use crate::println;
+use core::{fmt, str, task::{Context, Poll}, sync::atomic::{AtomicU64, Ordering}};
+use futures_util::{future::poll_fn, task::AtomicWaker};
+
+const MAX_MESSAGE_LENGTH: usize = 120;  // longer messages are truncated
+const QUEUE_CAPACITY: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Record {
+    length: usize,
+    bytes: [u8; MAX_MESSAGE_LENGTH],
+}
+
+impl Record {
+    fn new() -> Self {
+        Record {
+            length: 0,
+            bytes: [0; MAX_MESSAGE_LENGTH],
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        let bytes = &self.bytes[..self.length];
+        match str::from_utf8(bytes) {
+            Ok(message) => message,
+            // truncation may have split a multi-byte character, so drop the partial character
+            Err(e) => str::from_utf8(&bytes[..e.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+impl fmt::Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(MAX_MESSAGE_LENGTH - self.length);
+        self.bytes[self.length..self.length + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.length += count;
+        Ok(())
+    }
+}
+
+static LOG_QUEUE: OnceCell<ArrayQueue<Record>> = OnceCell::uninit();
+// as with the input queues, allocated during initialization rather than on first use
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+static DROPPED_RECORDS: AtomicU64 = AtomicU64::new(0);
+
+/// Initializes the log queue. Messages logged before this are dropped.
+pub fn init_queue() {
+    LOG_QUEUE.try_init_once(|| ArrayQueue::new(QUEUE_CAPACITY))
+        .expect("log::init_queue should only be called once");
+}
+
+/// Queues a message for printing. Safe to call from interrupt handlers.
+///
+/// Must not block or allocate.
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let mut record = Record::new();
+    let _ = record.write_fmt(args); // writing to a Record never fails, it only truncates
+    if let Ok(queue) = LOG_QUEUE.try_get() {
+        if queue.push(record).is_err() {
+            DROPPED_RECORDS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            WAKER.wake();
+        }
+    } else {
+        DROPPED_RECORDS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Logs a line to the VGA buffer without locking, so that it is safe to use
+/// in interrupt handlers. Output is deferred until the drain_log task runs.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => ($crate::log::_log(format_args!($($arg)*)));
+}
+
+fn poll_record(cx: &mut Context) -> Poll<Record> {
+    let queue = LOG_QUEUE.try_get().expect("log queue not initialized");
+
+    if let Ok(record) = queue.pop() {
+        return Poll::Ready(record);
+    }
+
+    WAKER.register(&cx.waker());
+    match queue.pop() {
+        Ok(record) => {
+            WAKER.take();
+            Poll::Ready(record)
+        }
+        Err(crossbeam_queue::PopError) => Poll::Pending,
+    }
+}
+
+/// Prints logged messages, in the order they were logged, as they arrive.
+pub async fn drain_log() {
+    loop {
+        let record = poll_fn(poll_record).await;
+        println!("{}", record.as_str());
+        let dropped = DROPPED_RECORDS.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            println!("WARNING: dropped {} log messages", dropped);
+        }
+    }
+}
+
+#[test_case]
+fn test_log_from_interrupt_context_preserves_order() {
+    use x86_64::instructions::interrupts;
+    use futures_util::task::noop_waker;
+
+    let _ = LOG_QUEUE.try_init_once(|| ArrayQueue::new(QUEUE_CAPACITY)); // may already be initialized
+    let queue = LOG_QUEUE.try_get().unwrap();
+    while queue.pop().is_ok() {}
+
+    interrupts::without_interrupts(|| {  // as in a hardware interrupt handler
+        for i in 0..3 {
+            log!("message {}", i);
+        }
+    });
+
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    for expected in &["message 0", "message 1", "message 2"] {
+        match poll_record(&mut context) {
+            Poll::Ready(record) => assert_eq!(record.as_str(), *expected),
+            Poll::Pending => panic!("logged message missing"),
+        }
+    }
+    assert!(poll_record(&mut context).is_pending());
+}
+
+#[test_case]
+fn test_log_truncates_long_messages() {
+    use core::fmt::Write;
+
+    let mut record = Record::new();
+    write!(record, "a").unwrap();   // one byte, so the two-byte characters below end mid-character
+    for _ in 0..MAX_MESSAGE_LENGTH {
+        write!(record, "é").unwrap();
+    }
+    assert_eq!(record.length, MAX_MESSAGE_LENGTH);
+    assert_eq!(record.as_str().len(), MAX_MESSAGE_LENGTH - 1);
+    assert!(record.as_str().ends_with('é'));
+}
@@ -9,7 +9,7 @@ extern crate rlibc;
 extern crate alloc;     // alloc is one of the few crates that needs the `extern crate` syntax
 extern crate miniz_oxide;
 use core::panic::PanicInfo;
-use test_os::{println, task::{Task, keyboard, executor::Executor}, exit_qemu, QemuExitCode, serial_print, serial_println};
+use test_os::{println, task::{Task, keyboard, serial, shell, thumbnail, executor::{Executor, Spawner}}, exit_qemu, QemuExitCode, serial_print, serial_println};
 use bootloader::{BootInfo, entry_point};
 use alloc::vec::Vec;
 
@@ -22,21 +22,19 @@ async fn sample_application(app_input: u32) -> u32 {
 }
 
 /// Asynchronous function to execute the primary application and handle its output
-async fn run_application(qemu_input: u32) {
-    // Handle the input from qemu, and then run the application here using async/await
-    let result = sample_application(qemu_input).await;
+async fn run_application(spawner: Spawner, qemu_input: u32) {
+    // Handle the input from qemu, and then run the application as its own task,
+    // awaiting its result through the JoinHandle
+    let result = spawner.spawn_with_handle(sample_application(qemu_input)).await;
     // Handle the output of the function
-    let exit_code = match result {
-        0 => QemuExitCode::Success,
-        _ => QemuExitCode::Failed,
-    };
+    let exit_code = QemuExitCode::from_result(result);
 
     serial_println!();   // Flush serial output
+    serial_println!("Application exited with result {} ({:?})", result, exit_code);
 
     // Exit qemu with either a success or failure
-    // Note: qemu I/O port is not configured automatically by cargo run outside of test mode, so
-    // the call to exit_qemu will do have no effect
-    // TODO: provide output data to qemu in a more flexible manner
+    // The isa-debug-exit device is configured by the run-args in Cargo.toml as well as the
+    // test-args, so the host receives the exit status from `cargo run` too
     #[cfg(not(test))]   // do not want to trigger automatic successes in main tests
     exit_qemu(exit_code);
 }
@@ -58,7 +56,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap(allocator::HeapConfig::DEFAULT, &mut mapper, &mut frame_allocator).expect("heap initialization failed");
     */
 
     #[cfg(test)]  // Only call test_main in test contexts, since it is not generated on a normal run
@@ -70,11 +68,16 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // it to the heap and pin it, and executor.spawn() adds it to the task_queue
 
     //executor.spawn(Task::new(keyboard::print_keypresses()));
-
-    /*
-    let sample_input = 42;      // TODO: receive input from qemu
-    executor.spawn(Task::new(run_application(sample_input)));
-    */
+    executor.spawn(Task::new(shell::run_shell()));
+    // the shell takes over the keyboard, so cannot run alongside print_keypresses
+    executor.spawn(Task::new(keyboard::report_dropped_scancodes()));
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(serial::handle_commands(move |input| run_application(spawner.clone(), input))));
+    // the host starts the application by sending "run <input>" over the second serial port,
+    // otherwise it runs with the default input once the run timeout passes
+    executor.spawn(Task::new(thumbnail::serve_thumbnails()));
+    // the host requests a thumbnail by sending a PNG over the first serial port
+    executor.spawn(Task::new(test_os::log::drain_log()));
 
     executor.run();
     // pops the task from the front of the task_queue
@@ -83,7 +86,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // example_task does not wait for anything, so it runs directly until the end
     // example_task directly returns Poll::Ready, so is not added back to the task queue
 
-    // executor.run() never returns, so no need for hlt_loop()
+    // executor.run() only returns once a task requests a shutdown
+    exit_qemu(QemuExitCode::Success);
+    test_os::hlt_loop();
 }
 
 async fn async_number() -> u32 {
@@ -1,9 +1,12 @@
 use x86_64::{
-    structures::paging::{PageTable, OffsetPageTable, PhysFrame, Size4KiB, FrameAllocator},
+    structures::paging::{PageTable, OffsetPageTable, PhysFrame, PageSize, Size4KiB, Size2MiB, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags},
+    structures::paging::mapper::{TranslateError, UnmapError},
+    structures::idt::PageFaultErrorCode,
     VirtAddr,
     PhysAddr,
 };
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
 
 /// Initializes a new OffsetPageTable.
 ///
@@ -16,6 +19,48 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Translates the virtual address to the physical address it is mapped to,
+/// i.e. the start of the frame mapped to its page plus its offset into the
+/// page, or None if its page is not mapped as a 4KiB or 2MiB page.
+pub fn translate_addr(addr: VirtAddr, mapper: &(impl Mapper<Size4KiB> + Mapper<Size2MiB>)) -> Option<PhysAddr> {
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    match Mapper::<Size4KiB>::translate_page(mapper, page) {
+        Ok(frame) => Some(frame.start_address() + u64::from(addr.page_offset())),
+        Err(TranslateError::ParentEntryHugePage) => {   // e.g. in the heap, see allocator::init_heap_huge
+            let page: Page<Size2MiB> = Page::containing_address(addr);
+            let frame = Mapper::<Size2MiB>::translate_page(mapper, page).ok()?;
+            Some(frame.start_address() + (addr - page.start_address()))
+        }
+        Err(_) => None,
+    }
+}
+
+/// Unmaps `count` pages starting at `start`, flushing each from the TLB, and
+/// returns their frames to the frame deallocator.
+///
+/// Returns UnmapError::PageNotMapped without unmapping anything if any page
+/// in the range is not mapped, or is part of a huge page.
+///
+/// This function is unsafe because the caller must guarantee that nothing
+/// still uses the pages, nor their frames through other mappings.
+pub unsafe fn unmap_pages(
+    start: Page<Size4KiB>,
+    count: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<(), UnmapError> {
+    let pages = Page::range(start, start + count);
+    if pages.clone().any(|page| mapper.translate_page(page).is_err()) {
+        return Err(UnmapError::PageNotMapped);
+    }
+    for page in pages {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        frame_deallocator.deallocate_frame(frame);
+    }
+    Ok(())
+}
+
 /// Returns a mutable reference to the active level 4 table,
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -34,10 +79,18 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+/// The number of deallocated frames kept for reuse. Further frames are
+/// leaked, since the list is fixed-size so that it never allocates. Large
+/// enough for the frames skipped to reach a 2MiB boundary.
+const FREE_FRAME_CAPACITY: usize = 512;
+
+/// A FrameAllocator that returns usable frames from the bootloader's memory map,
+/// reusing deallocated frames first.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    free_frames: [Option<PhysFrame>; FREE_FRAME_CAPACITY],
+    free_count: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -50,6 +103,8 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_frames: [None; FREE_FRAME_CAPACITY],
+            free_count: 0,
         }
     }
 
@@ -67,6 +122,12 @@ impl BootInfoFrameAllocator {
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 
+    /// Returns the number of frames which can still be allocated, counting
+    /// both unused frames of the memory map and deallocated frames.
+    pub fn free_frame_count(&self) -> usize {
+        self.usable_frames().count().saturating_sub(self.next) + self.free_count
+    }
+
     pub fn allocate_n_frames(&mut self, n: usize) -> impl Iterator<Item = PhysFrame> {
         let frames = self.usable_frames().skip(self.next).take(n);
         self.next += n;
@@ -76,12 +137,63 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_count > 0 {
+            self.free_count -= 1;
+            return self.free_frames[self.free_count].take();
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    /// Takes the next 512 usable 4KiB frames which are physically contiguous
+    /// and start on a 2MiB boundary. The usable frames skipped to reach them
+    /// are returned to the free list, so returns None rather than leaking
+    /// them if there is not room for them all; 4KiB allocations then use up
+    /// the skipped frames, after which the 2MiB frame can be taken.
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frames_per_huge_frame = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+        let mut run_start: (usize, PhysFrame) = (0, PhysFrame::containing_address(PhysAddr::new(0)));
+        let mut run_length: usize = 0;
+        let mut previous: Option<PhysFrame> = None;
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            if run_length > 0 && previous.map(|previous| previous + 1) == Some(frame) {
+                run_length += 1;
+            } else if frame.start_address().is_aligned(Size2MiB::SIZE) {
+                run_start = (index, frame);
+                run_length = 1;
+            } else {
+                run_length = 0;
+            }
+            previous = Some(frame);
+            if run_length == frames_per_huge_frame {
+                let (start_index, start_frame) = run_start;
+                if start_index - self.next > FREE_FRAME_CAPACITY - self.free_count {
+                    return None;
+                }
+                for skipped in self.usable_frames().skip(self.next).take(start_index - self.next) {
+                    unsafe { self.deallocate_frame(skipped); }
+                }
+                self.next = index + 1;
+                return PhysFrame::from_start_address(start_frame.start_address()).ok();
+            }
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    // unsafe because the caller must guarantee that the frame is no longer mapped or otherwise used
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        if self.free_count < FREE_FRAME_CAPACITY {
+            self.free_frames[self.free_count] = Some(frame);
+            self.free_count += 1;
+        }
+    }
+}
+
 /// A FrameAllocator that always returns `None`
 pub struct EmptyFrameAllocator;
 
@@ -91,3 +203,122 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
         None
     }
 }
+
+/// The page table mapper and frame allocator, retained after initialization
+/// so that pages can be mapped on demand, e.g. from the page fault handler.
+pub struct MemoryManager {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+pub static MEMORY_MANAGER: Mutex<Option<MemoryManager>> = Mutex::new(None);
+
+/// Stores the mapper and frame allocator for later on-demand mapping.
+pub fn init_manager(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *MEMORY_MANAGER.lock() = Some(MemoryManager {
+        mapper,
+        frame_allocator,
+    });
+}
+
+const MAX_LAZY_REGIONS: usize = 8;
+
+/// Virtual address ranges (start inclusive, end exclusive) whose pages are
+/// mapped to fresh frames the first time they are accessed.
+static LAZY_REGIONS: Mutex<[Option<(VirtAddr, VirtAddr)>; MAX_LAZY_REGIONS]> =
+    Mutex::new([None; MAX_LAZY_REGIONS]);
+
+/// Marks `size` bytes of virtual memory starting at `start` to be mapped on
+/// demand by the page fault handler.
+///
+/// The caller must ensure that the range is not otherwise in use. Panics if
+/// more than `MAX_LAZY_REGIONS` regions are registered.
+pub fn register_lazy_region(start: VirtAddr, size: u64) {
+    let mut regions = LAZY_REGIONS.lock();
+    let slot = regions.iter_mut().find(|region| region.is_none()).expect("too many lazy regions");
+    *slot = Some((start, start + size));
+}
+
+fn in_lazy_region(addr: VirtAddr) -> bool {
+    match LAZY_REGIONS.try_lock() {
+        Some(regions) => regions.iter().flatten().any(|&(start, end)| start <= addr && addr < end),
+        None => false,  // faulted while registering a region, so do not spin on the lock
+    }
+}
+
+/// Attempts to recover from a page fault at `addr` by mapping a fresh, zeroed
+/// frame, which is only done for not-present pages in a lazy region.
+///
+/// Returns true if the faulting access can be retried.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) || !in_lazy_region(addr) {
+        return false;   // the page is present but the access is not permitted, or the address is not lazy
+    }
+    let mut manager = match MEMORY_MANAGER.try_lock() {
+        Some(manager) => manager,
+        None => return false,   // faulted while mapping, so the page tables may be inconsistent
+    };
+    let MemoryManager { mapper, frame_allocator } = match manager.as_mut() {
+        Some(manager) => manager,
+        None => return false,
+    };
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(_) => return false,
+    }
+    unsafe {
+        // frames may hold stale data from a previous use
+        core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, page.size() as usize);
+    }
+    true
+}
+
+#[test_case]
+fn test_huge_frame_allocation_loses_no_frames() {
+    use alloc::{boxed::Box, collections::BTreeSet};
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+
+    const FRAME: u64 = Size4KiB::SIZE;
+    const HUGE: u64 = Size2MiB::SIZE;
+    let mut memory_map = MemoryMap::new();
+    // 600 frames, more than the free list holds, which end too soon after a 2MiB boundary
+    memory_map.add_region(MemoryRegion {
+        range: FrameRange::new(8 * HUGE + FRAME, 8 * HUGE + 601 * FRAME),
+        region_type: MemoryRegionType::Usable,
+    });
+    // 300 frames below a 2MiB boundary, a 2MiB frame, and one more frame
+    memory_map.add_region(MemoryRegion {
+        range: FrameRange::new(16 * HUGE - 300 * FRAME, 17 * HUGE + FRAME),
+        region_type: MemoryRegionType::Usable,
+    });
+    let memory_map: &'static MemoryMap = Box::leak(Box::new(memory_map));
+    let mut allocator = unsafe { BootInfoFrameAllocator::init(memory_map) };
+    let total = allocator.free_frame_count();
+    assert_eq!(total, 600 + 300 + 512 + 1);
+
+    // taking the 2MiB frame would skip more frames than the free list holds
+    assert_eq!(FrameAllocator::<Size2MiB>::allocate_frame(&mut allocator), None);
+    assert_eq!(allocator.free_frame_count(), total);
+
+    let mut frames: BTreeSet<PhysFrame> = BTreeSet::new();
+    for _ in 0..600 {
+        assert!(frames.insert(FrameAllocator::<Size4KiB>::allocate_frame(&mut allocator).unwrap()));
+    }
+    // the 300 frames skipped now fit in the free list
+    let huge: PhysFrame<Size2MiB> = FrameAllocator::<Size2MiB>::allocate_frame(&mut allocator).unwrap();
+    assert_eq!(huge.start_address().as_u64(), 16 * HUGE);
+    assert_eq!(allocator.free_frame_count(), total - 600 - 512);
+
+    // every other frame can still be allocated, exactly once
+    while let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(&mut allocator) {
+        assert!(!(16 * HUGE..17 * HUGE).contains(&frame.start_address().as_u64()));
+        assert!(frames.insert(frame));
+    }
+    assert_eq!(frames.len(), total - 512);
+}
@@ -1,7 +1,17 @@
-use crate::println;
 use alloc::vec::Vec;
+use core::{cmp::{min, max}, fmt};
+#[cfg(test)]
+use crate::random::Prng;
 use lazy_static::lazy_static;
 
+/// Logs a step of generating a thumbnail. Only enabled with the verbose-png
+/// feature, since these lines would otherwise appear for every thumbnail.
+macro_rules! verbose_log {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "verbose-png")]
+        crate::log!($($arg)*);
+    };
+}
 
 // All png files must begin with bytes: [0x89, 'P', 'N', 'G', '\r', '\n', 0x1a, '\n'];
 const SIGNATURE_LENGTH: usize = 8;
@@ -11,6 +21,7 @@ const TYPE_OFFSET: usize = 4;   // length bytes
 const TYPE_LENGTH: usize = 4;
 const DATA_OFFSET: usize = TYPE_OFFSET + TYPE_LENGTH; // length bytes + type bytes
 const CRC_LENGTH: usize = 4;
+pub const MAX_CHUNK_LENGTH: usize = 0x7fff_ffff;    // the PNG specification limits lengths to 2^31 - 1
 
 const IHDR_DATA_LENGTH: usize = 13;
 const IHDR_TOTAL_LENGTH: usize = DATA_OFFSET + IHDR_DATA_LENGTH + CRC_LENGTH;
@@ -19,26 +30,91 @@ const IEND_TOTAL_LENGTH: usize = DATA_OFFSET + CRC_LENGTH;
 
 const FIRST_CHUNK_AFTER_IHDR: usize = SIGNATURE_LENGTH + IHDR_TOTAL_LENGTH;
 
-const GREYSCALE: u8 = 0;
-const TRUECOLOR: u8 = 2;
-const INDEXED_COLOR: u8 = 3;
-const GREYSCALE_WITH_ALPHA: u8 = 4;
-const TRUECOLOR_WITH_ALPHA: u8 = 6;
+pub const GREYSCALE: u8 = 0;
+pub const TRUECOLOR: u8 = 2;
+pub const INDEXED_COLOR: u8 = 3;
+pub const GREYSCALE_WITH_ALPHA: u8 = 4;
+pub const TRUECOLOR_WITH_ALPHA: u8 = 6;
 
 const PLTE_CHANNELS: usize = 3;
 
-const DEFAULT_COMPRESSION_LEVEL: u8 = 3;
+// (h_offset, v_offset, h_interval, v_interval) for each of the Adam7 passes
+const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4),
+    (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2),
+];
 
-const FORCED_BIT_DEPTH: u8 = 8;
+pub const DEFAULT_COMPRESSION_LEVEL: u8 = 3;
+pub const MAX_COMPRESSION_LEVEL: u8 = 10;
 
+/// The most entries a PLTE chunk can hold, since indices are one byte
+pub const MAX_PALETTE_COLORS: usize = 256;
 
-#[derive(Debug)]
+
+#[derive(Debug, PartialEq)]
 pub enum ParseError {
     SIGNATURE,
     LENGTH,
     TYPE,
     ORDER,
     MISSING,
+    CRC,
+    DECOMPRESS,
+    HEADER,
+    OPTIONS,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            ParseError::SIGNATURE => "invalid PNG signature",
+            ParseError::LENGTH => "chunk or image data length out of bounds",
+            ParseError::TYPE => "unexpected chunk type",
+            ParseError::ORDER => "chunks out of order",
+            ParseError::MISSING => "required chunk missing",
+            ParseError::CRC => "chunk CRC mismatch",
+            ParseError::DECOMPRESS => "corrupt or truncated zlib stream",
+            ParseError::HEADER => "unsupported or invalid IHDR values",
+            ParseError::OPTIONS => "invalid thumbnail options",
+        };
+        f.write_str(message)
+    }
+}
+
+/// A clockwise rotation, applied to an image before it is resampled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+/// How each pixel of a thumbnail is computed from the original image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resampling {
+    /// Copies the original pixel under the center of the new pixel, whether
+    /// shrinking or enlarging, which keeps the hard edges of pixel art and is
+    /// the fastest, but drops detail when shrinking
+    Nearest,
+    /// Averages the original pixels under the new pixel when shrinking, and
+    /// copies the original pixel under its center when enlarging
+    Box,
+    /// Averages like Box when shrinking, and interpolates between the four
+    /// original pixels nearest the center of the new pixel when enlarging
+    Bilinear,
+}
+
+/// The dimensions and sample format of the pixels returned by decode_png.
+/// Palettes are resolved, so color_type is never INDEXED_COLOR, and samples
+/// narrower than a byte are expanded, so bit_depth is either 8 or 16.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageInfo {
+    pub width: usize,
+    pub height: usize,
+    pub bit_depth: u8,
+    pub color_type: u8,
+    pub channels: usize,
 }
 
 struct PNGInfo {
@@ -51,6 +127,18 @@ struct PNGInfo {
     interlace_method: u8,
 }
 
+/// The pixel density given by a pHYs chunk, in pixels per metre if unit is
+/// PHYS_UNIT_METRE, or else only giving the pixel aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PhysicalDimensions {
+    x_pixels_per_unit: u32,
+    y_pixels_per_unit: u32,
+    unit: u8,
+}
+
+const PHYS_DATA_LENGTH: usize = 9;
+const PHYS_UNIT_METRE: u8 = 1;
+
 struct ThumbnailGenerationInfo {
     width: usize,
     height: usize,
@@ -77,6 +165,58 @@ lazy_static! {
         }
         crc_table
     };
+
+    /// The linear light intensity in [0, 1] of each 8-bit sRGB-encoded value.
+    static ref SRGB_TO_LINEAR: [f64; 256] = {
+        let mut linear_table: [f64; 256] = [0f64; 256];
+        for n in 0..256 {
+            let encoded: f64 = n as f64 / 255.0;
+            linear_table[n] = if encoded <= 0.04045 {
+                encoded / 12.92
+            } else {
+                // ((encoded + 0.055) / 1.055) ^ 2.4, since there is no powf in core
+                let base: f64 = (encoded + 0.055) / 1.055;
+                base * base * fifth_root(base * base)
+            };
+        }
+        linear_table
+    };
+}
+
+
+/// Computes the fifth root of a value in (0, 1] using Newton's method, which
+/// converges monotonically from above when starting at 1.
+fn fifth_root(value: f64) -> f64 {
+    let mut root: f64 = 1.0;
+    loop {
+        let root_4: f64 = root * root * root * root;
+        let next: f64 = root - (root_4 * root - value) / (5.0 * root_4);
+        if next >= root {
+            return root;
+        }
+        root = next;
+    }
+}
+
+
+/// Finds the 8-bit sRGB-encoded value whose linear intensity is closest to the
+/// given one, searching the (increasing) SRGB_TO_LINEAR table.
+fn linear_to_srgb(linear: f64) -> u8 {
+    let mut low: usize = 0;
+    let mut high: usize = 255;
+    while low < high {
+        let mid: usize = (low + high) / 2;
+        if SRGB_TO_LINEAR[mid] < linear {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    // low is the first value at least as bright, so check the one below too
+    if low > 0 && linear - SRGB_TO_LINEAR[low - 1] < SRGB_TO_LINEAR[low] - linear {
+        low -= 1;
+    }
+    low as u8
 }
 
 
@@ -93,7 +233,7 @@ fn channel_count(color_type: u8) -> usize {
     match color_type {
         GREYSCALE => 1,
         TRUECOLOR => 3,
-        INDEXED_COLOR => 3,
+        INDEXED_COLOR => 1,     // one palette index per pixel
         GREYSCALE_WITH_ALPHA => 2,
         TRUECOLOR_WITH_ALPHA => 4,
         _ => panic!("Invalid color type: {:?}", color_type),
@@ -137,37 +277,61 @@ fn check_png_info_valid(info: &PNGInfo) -> bool {
     && (info.compression_method == 0)   // png only supports 0
     && (info.filter_method == 0)        // png only supports 0
     && (check_interlace_method_valid(info.interlace_method) == true)
+    && (info.width > 0 && info.height > 0)  // an empty image has no pixels to scale
 
-    && (info.color_type & 1 == 0)           // For now, do not allow indexed-color
-    && (info.bit_depth == FORCED_BIT_DEPTH) // For now, only accept bit depth of 8
+    && (info.bit_depth >= 8 || info.interlace_method == 0)  // For now, sub-byte samples are only unpacked when not interlaced
 }
 
 
 fn compute_bytes_per_pixel(info: &PNGInfo) -> usize {
     let channels = channel_count(info.color_type);
     let bits_per_pixel = info.bit_depth as usize * channels;
-    bits_per_pixel >> 3
+    max(bits_per_pixel >> 3, 1)     // filters treat sub-byte pixels as one byte
+}
+
+
+/// Computes the number of bytes in one scanline of unfiltered data, excluding
+/// the filter type byte. Sub-byte pixels are packed, and the last byte padded.
+fn compute_scanline_length(info: &PNGInfo) -> usize {
+    let bits_per_pixel = info.bit_depth as usize * channel_count(info.color_type);
+    (info.width * bits_per_pixel + 7) >> 3
+}
+
+
+/// Inflates the concatenated IDAT data.
+///
+/// Returns ParseError::DECOMPRESS if the zlib stream is corrupt or truncated.
+fn decompress_data(data: Vec<u8>) -> Result<Vec<u8>, ParseError> {
+    match miniz_oxide::inflate::decompress_to_vec_zlib(data.as_slice()) {
+        Ok(decompressed) => Ok(decompressed),
+        Err(_) => Err(ParseError::DECOMPRESS),
+    }
 }
 
 
-fn decompress_data(data: Vec<u8>) -> Vec<u8> {
-    return miniz_oxide::inflate::decompress_to_vec_zlib(data.as_slice()).expect("Failed to decompress!");
+/// Deflates the filtered image data. Higher levels search harder for repeated
+/// data, so they are slower but produce smaller output: level 1 is fastest,
+/// level 9 gives the smallest output in reasonable time, level 10 is slower
+/// still for little gain, and level 0 stores the data uncompressed.
+fn compress_data(data: Vec<u8>, level: u8) -> Vec<u8> {
+    assert!(level <= MAX_COMPRESSION_LEVEL);
+    return miniz_oxide::deflate::compress_to_vec_zlib(data.as_slice(), level);
 }
 
 
-fn compress_data(data: Vec<u8>) -> Vec<u8> {
-    return miniz_oxide::deflate::compress_to_vec_zlib(data.as_slice(), DEFAULT_COMPRESSION_LEVEL);
+/// Branchless absolute value: (x >> 31) is -1 for negative x and 0 otherwise,
+/// so x is multiplied by either -1 or 1. Valid for every i32 except i32::MIN,
+/// which is far outside the range of [-510, 510] used by paeth_predictor.
+fn branchless_abs(x: i32) -> i32 {
+    x * ((x >> 31) | 1)
 }
 
 
 fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
     let p: i32 = a as i32 + b as i32 - c as i32;
-    let mut pa: i32 = p - a as i32;
-    let mut pb: i32 = p - b as i32;
-    let mut pc: i32 = p - c as i32;
-    pa *= (pa >> 31) | 1;
-    pb *= (pb >> 31) | 1;
-    pc *= (pc >> 31) | 1;
+    let pa: i32 = branchless_abs(p - a as i32);
+    let pb: i32 = branchless_abs(p - b as i32);
+    let pc: i32 = branchless_abs(p - c as i32);
     if pa <= pb && pa <= pc {
         a
     } else if pb <= pc {
@@ -178,13 +342,18 @@ fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
 }
 
 
-fn unfilter_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
+fn unfilter_data(info: &PNGInfo, data: Vec<u8>) -> Result<Vec<u8>, ParseError> {
     // Unfilters and deserializes data, thus removing filter type byte from the
     // beginning of each scanline
     assert!(info.interlace_method == 0);
-    let mut unfiltered: Vec<u8> = Vec::with_capacity(data.len() - info.height);
     let bytes_per_pixel: usize = compute_bytes_per_pixel(&info);
-    let stride: usize = info.width * bytes_per_pixel;
+    let stride: usize = compute_scanline_length(&info);
+    // the dimensions come from the IHDR chunk, so the expected length may not fit in a usize
+    match stride.checked_add(1).and_then(|s| s.checked_mul(info.height)) {
+        Some(length) if length == data.len() => {},
+        _ => return Err(ParseError::LENGTH),
+    }
+    let mut unfiltered: Vec<u8> = Vec::with_capacity(data.len() - info.height);
     for row in 0..info.height {
         let orig_start: usize = row * (stride + 1) + 1; // first byte index into data for row
         let unf_start: usize = row * stride;            // first byte index into unfiltered for row
@@ -200,8 +369,8 @@ fn unfilter_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
                     unfiltered.push(data[orig_start + col]);
                 }
                 for col in bytes_per_pixel..stride {
-                    let orig: u32 = data[orig_start + col] as u32;
-                    unfiltered.push((orig + unfiltered[unf_start + col - bytes_per_pixel] as u32) as u8);
+                    let left: u8 = unfiltered[unf_start + col - bytes_per_pixel];
+                    unfiltered.push(data[orig_start + col].wrapping_add(left));
                 }
             },
             2 => {  // up
@@ -211,8 +380,8 @@ fn unfilter_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
                     }
                 } else {
                     for col in 0..stride {
-                        let orig: u32 = data[orig_start + col] as u32;
-                        unfiltered.push((orig + unfiltered[unf_start + col - stride] as u32) as u8);
+                        let up: u8 = unfiltered[unf_start + col - stride];
+                        unfiltered.push(data[orig_start + col].wrapping_add(up));
                     }
                 }
             },
@@ -222,21 +391,19 @@ fn unfilter_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
                         unfiltered.push(data[orig_start + col]);
                     }
                     for col in bytes_per_pixel..stride {
-                        let orig: u32 = data[orig_start + col] as u32;
-                        let left: u32 = unfiltered[unf_start + col - bytes_per_pixel] as u32;
-                        unfiltered.push((orig + (left >> 1)) as u8);
+                        let left: u8 = unfiltered[unf_start + col - bytes_per_pixel];
+                        unfiltered.push(data[orig_start + col].wrapping_add(left >> 1));
                     }
                 } else {
                     for col in 0..bytes_per_pixel {
-                        let orig: u32 = data[orig_start + col] as u32;
-                        let up: u32 = unfiltered[unf_start + col - stride] as u32;
-                        unfiltered.push((orig + (up >> 1)) as u8);
+                        let up: u8 = unfiltered[unf_start + col - stride];
+                        unfiltered.push(data[orig_start + col].wrapping_add(up >> 1));
                     }
                     for col in bytes_per_pixel..stride {
-                        let orig: u32 = data[orig_start + col] as u32;
-                        let left: u32 = unfiltered[unf_start + col - bytes_per_pixel] as u32;
-                        let up: u32 = unfiltered[unf_start + col - stride] as u32;
-                        unfiltered.push((orig + ((left + up) >> 1)) as u8);
+                        // the sum of left and up needs nine bits before it is halved
+                        let left: u16 = unfiltered[unf_start + col - bytes_per_pixel] as u16;
+                        let up: u16 = unfiltered[unf_start + col - stride] as u16;
+                        unfiltered.push(data[orig_start + col].wrapping_add(((left + up) >> 1) as u8));
                     }
                 }
             },
@@ -246,39 +413,89 @@ fn unfilter_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
                         unfiltered.push(data[orig_start + col]);
                     }
                     for col in bytes_per_pixel..stride {
-                        let orig: u32 = data[orig_start + col] as u32;
-                        let result: u32 = paeth_predictor(
+                        let result: u8 = paeth_predictor(
                             unfiltered[unf_start + col - bytes_per_pixel],
-                            0, 0) as u32;
-                        unfiltered.push((orig + result) as u8);
+                            0, 0);
+                        unfiltered.push(data[orig_start + col].wrapping_add(result));
                     }
                 } else {
                     for col in 0..bytes_per_pixel {
-                        let orig: u32 = data[orig_start + col] as u32;
-                        let result: u32 = paeth_predictor(
-                            0, unfiltered[unf_start + col - stride], 0) as u32;
-                        unfiltered.push((orig + result) as u8);
+                        let result: u8 = paeth_predictor(
+                            0, unfiltered[unf_start + col - stride], 0);
+                        unfiltered.push(data[orig_start + col].wrapping_add(result));
                     }
                     for col in bytes_per_pixel..stride {
-                        let orig: u32 = data[orig_start + col] as u32;
-                        let result: u32 = paeth_predictor(
+                        let result: u8 = paeth_predictor(
                             unfiltered[unf_start + col - bytes_per_pixel],
                             unfiltered[unf_start + col - stride],
                             unfiltered[unf_start + col - (stride + bytes_per_pixel)],
-                            ) as u32;
-                        unfiltered.push((orig + result) as u8);
+                            );
+                        unfiltered.push(data[orig_start + col].wrapping_add(result));
                     }
                 }
             },
             _ => panic!("Invalid filter type {:?} for row {:?}", filter_type, row),
         }
     }
-    return unfiltered;
+    Ok(unfiltered)
+}
+
+
+/// Expands unfiltered greyscale or indexed-color data with 1, 2, or 4 bits per
+/// sample to one byte per sample, dropping the padding at the end of each
+/// scanline. Greyscale samples are scaled to the full 8-bit range, while palette
+/// indices are kept as is.
+///
+/// Returns the expanded data, which should then be treated as bit depth 8.
+fn unpack_samples(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
+    assert!(info.bit_depth < 8 && info.interlace_method == 0);
+    let bit_depth: usize = info.bit_depth as usize;
+    let samples_per_row: usize = info.width * channel_count(info.color_type);
+    let stride: usize = compute_scanline_length(&info);
+    let max_sample: u8 = ((1u16 << bit_depth) - 1) as u8;
+    let scale: u8 = if info.color_type == INDEXED_COLOR { 1 } else { 255 / max_sample };
+    let mut unpacked: Vec<u8> = Vec::with_capacity(samples_per_row * info.height);
+    for row in data.chunks_exact(stride) {
+        for sample_index in 0..samples_per_row {
+            let bit_offset: usize = sample_index * bit_depth;
+            // samples are packed starting from the most significant bit
+            let shift: usize = 8 - bit_depth - (bit_offset & 7);
+            let sample: u8 = (row[bit_offset >> 3] >> shift) & max_sample;
+            unpacked.push(sample * scale);
+        }
+    }
+    unpacked
+}
+
+
+/// Computes the length of the filtered data of an interlaced image, which
+/// is the sum over the seven passes of one filter type byte plus one scanline
+/// for each row of the pass. Passes with no pixels contribute nothing.
+///
+/// Returns None if the length does not fit in a usize.
+fn compute_interlaced_data_length(info: &PNGInfo) -> Option<usize> {
+    let bytes_per_pixel: usize = compute_bytes_per_pixel(&info);
+    let mut length: usize = 0;
+    for &(h_offset, v_offset, h_interval, v_interval) in &ADAM7_PASSES {
+        if (v_offset >= info.height) || (h_offset >= info.width) {
+            continue;
+        }
+        let pass_height: usize = ((info.height - (v_offset + 1)) / v_interval) + 1;
+        let pass_width: usize = ((info.width - (h_offset + 1)) / h_interval) + 1;
+        let pass_length: usize = pass_width.checked_mul(bytes_per_pixel)
+            .and_then(|s| s.checked_add(1))
+            .and_then(|s| s.checked_mul(pass_height))?;
+        length = length.checked_add(pass_length)?;
+    }
+    Some(length)
 }
 
 
-fn unfilter_interlaced_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
+fn unfilter_interlaced_data(info: &PNGInfo, data: Vec<u8>) -> Result<Vec<u8>, ParseError> {
     assert!(info.interlace_method == 1);
+    if compute_interlaced_data_length(&info) != Some(data.len()) {
+        return Err(ParseError::LENGTH);
+    }
     let mut base_offset: usize = 8;
     let base_interval: usize = 8;
     let mut h_offset: usize;
@@ -340,10 +557,8 @@ fn unfilter_interlaced_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
                     start += col_interval;
                     for _ in 1..pass_width {
                         for byte_location in start..start+bytes_per_pixel {
-                            unfiltered[byte_location] = (
-                                data[index] as u32 +
-                                unfiltered[byte_location - col_interval] as u32
-                                ) as u8;
+                            unfiltered[byte_location] =
+                                data[index].wrapping_add(unfiltered[byte_location - col_interval]);
                             index += 1;
                         }
                         start += col_interval;
@@ -361,10 +576,8 @@ fn unfilter_interlaced_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
                     } else {
                         for _ in 0..pass_width {
                             for byte_location in start..start+bytes_per_pixel {
-                                unfiltered[byte_location] = (
-                                    data[index] as u32 +
-                                    unfiltered[byte_location - row_interval] as u32
-                                    ) as u8;
+                                unfiltered[byte_location] =
+                                    data[index].wrapping_add(unfiltered[byte_location - row_interval]);
                                 index += 1;
                             }
                             start += col_interval;
@@ -380,30 +593,25 @@ fn unfilter_interlaced_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
                         start += col_interval;
                         for _ in 1..pass_width {
                             for byte_location in start..start+bytes_per_pixel {
-                                unfiltered[byte_location] = (
-                                    data[index] as u32 +
-                                    (unfiltered[byte_location - col_interval] as u32 >> 1)
-                                    ) as u8;
+                                unfiltered[byte_location] =
+                                    data[index].wrapping_add(unfiltered[byte_location - col_interval] >> 1);
                                 index += 1;
                             }
                             start += col_interval;
                         }
                     } else {
                         for byte_location in start..start+bytes_per_pixel {
-                            unfiltered[byte_location] = (
-                                data[index] as u32 +
-                                (unfiltered[byte_location - row_interval] as u32 >> 1)
-                                ) as u8;
+                            unfiltered[byte_location] =
+                                data[index].wrapping_add(unfiltered[byte_location - row_interval] >> 1);
                             index += 1;
                         }
                         start += col_interval;
                         for _ in 1..pass_width {
                             for byte_location in start..start+bytes_per_pixel {
-                                unfiltered[byte_location] = (
-                                    data[index] as u32 +
-                                    (unfiltered[byte_location - col_interval] as u32 +
-                                    unfiltered[byte_location - row_interval] as u32
-                                    ) >> 1) as u8;
+                                // the sum of left and up needs nine bits before it is halved
+                                let average: u16 = (unfiltered[byte_location - col_interval] as u16
+                                    + unfiltered[byte_location - row_interval] as u16) >> 1;
+                                unfiltered[byte_location] = data[index].wrapping_add(average as u8);
                                 index += 1;
                             }
                             start += col_interval;
@@ -419,33 +627,27 @@ fn unfilter_interlaced_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
                         start += col_interval;
                         for _ in 1..pass_width {
                             for byte_location in start..start+bytes_per_pixel {
-                                unfiltered[byte_location] = (
-                                    data[index] as u32 +
-                                    paeth_predictor(unfiltered[byte_location - col_interval], 0, 0) as u32
-                                    ) as u8;
+                                unfiltered[byte_location] = data[index].wrapping_add(
+                                    paeth_predictor(unfiltered[byte_location - col_interval], 0, 0));
                                 index += 1;
                             }
                             start += col_interval;
                         }
                     } else {
                         for byte_location in start..start+bytes_per_pixel {
-                            unfiltered[byte_location] = (
-                                data[index] as u32 +
-                                paeth_predictor(0, unfiltered[byte_location - row_interval], 0) as u32
-                                ) as u8;
+                            unfiltered[byte_location] = data[index].wrapping_add(
+                                paeth_predictor(0, unfiltered[byte_location - row_interval], 0));
                             index += 1;
                         }
                         start += col_interval;
                         for _ in 1..pass_width {
                             for byte_location in start..start+bytes_per_pixel {
-                                unfiltered[byte_location] = (
-                                    data[index] as u32 +
+                                unfiltered[byte_location] = data[index].wrapping_add(
                                     paeth_predictor(
                                         unfiltered[byte_location - col_interval],
                                         unfiltered[byte_location - row_interval],
                                         unfiltered[byte_location - (col_interval + row_interval)],
-                                        ) as u32
-                                    ) as u8;
+                                        ));
                                 index += 1;
                             }
                             start += col_interval
@@ -460,7 +662,29 @@ fn unfilter_interlaced_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
         v_offset = h_offset;
         v_interval = h_interval;
     }
-    return unfiltered;
+    Ok(unfiltered)
+}
+
+
+/// Applies one filter type to a scanline, writing the filtered bytes to
+/// `filtered`. `prev_row` is empty for the first scanline of the image.
+fn filter_scanline(filter_type: u8, row: &[u8], prev_row: &[u8], bytes_per_pixel: usize,
+                   filtered: &mut Vec<u8>) {
+    for col in 0..row.len() {
+        let left: u8 = if col >= bytes_per_pixel { row[col - bytes_per_pixel] } else { 0 };
+        let up: u8 = if prev_row.is_empty() { 0 } else { prev_row[col] };
+        let up_left: u8 = if col >= bytes_per_pixel && !prev_row.is_empty() {
+            prev_row[col - bytes_per_pixel]
+        } else { 0 };
+        let predictor: u8 = match filter_type {
+            0 => 0,                                             // no change
+            1 => left,                                          // sub
+            2 => up,                                            // up
+            3 => ((left as u16 + up as u16) >> 1) as u8,        // average
+            _ => paeth_predictor(left, up, up_left),            // Paeth predictor
+        };
+        filtered.push(row[col].wrapping_sub(predictor));
+    }
 }
 
 
@@ -471,14 +695,31 @@ fn filter_data(info: &PNGInfo, data: Vec<u8>) -> Vec<u8> {
     // number
     let mut filtered: Vec<u8> = Vec::with_capacity(data.len() + info.height);
     let bytes_per_pixel: usize = compute_bytes_per_pixel(&info);
-    let stride: usize = info.width * bytes_per_pixel;
+    let stride: usize = compute_scanline_length(&info);
+    let mut candidate: Vec<u8> = Vec::with_capacity(stride);
+    let mut best: Vec<u8> = Vec::with_capacity(stride);
     for row in 0..info.height {
-        // For now, always use filter type 0 -- no-op
-        filtered.push(0);
         let row_start: usize = row * stride;
-        for col in 0..stride {
-            filtered.push(data[row_start + col]);
+        let row_data: &[u8] = &data[row_start..row_start + stride];
+        let prev_row: &[u8] = if row == 0 { &[] } else { &data[row_start - stride..row_start] };
+        // Choose the filter type which minimizes the sum of the absolute values
+        // of the filtered bytes, treated as signed
+        let mut best_type: u8 = 0;
+        let mut best_sum: u64 = u64::MAX;
+        for filter_type in 0..5 {
+            candidate.clear();
+            filter_scanline(filter_type, row_data, prev_row, bytes_per_pixel, &mut candidate);
+            let sum: u64 = candidate.iter()
+                .map(|&byte| branchless_abs(byte as i8 as i32) as u64)
+                .sum();
+            if sum < best_sum {
+                best_sum = sum;
+                best_type = filter_type;
+                core::mem::swap(&mut best, &mut candidate);
+            }
         }
+        filtered.push(best_type);
+        filtered.extend_from_slice(&best);
     }
     return filtered;
 }
@@ -491,6 +732,42 @@ fn get_size_from_bytes(number_vec: &[u8], start: usize) -> usize {
 }
 
 
+/// Verifies that the CRC at the end of a complete chunk (length, type, data,
+/// and CRC) matches the CRC computed over its type and data.
+///
+/// Returns ParseError::LENGTH if the chunk is shorter than its length field
+/// claims, or ParseError::CRC if the CRCs do not match.
+pub fn verify_chunk_crc(chunk: &[u8]) -> Result<(), ParseError> {
+    if chunk.len() < DATA_OFFSET + CRC_LENGTH {
+        return Err(ParseError::LENGTH);
+    }
+    let crc_start: usize = DATA_OFFSET + get_size_from_bytes(chunk, 0);
+    if chunk.len() != crc_start + CRC_LENGTH {
+        return Err(ParseError::LENGTH);
+    }
+    if compute_crc(&chunk[TYPE_OFFSET..crc_start]) as usize != get_size_from_bytes(chunk, crc_start) {
+        return Err(ParseError::CRC);
+    }
+    Ok(())
+}
+
+
+/// Reads the length field of the chunk starting at chunk_start, whose length
+/// and type must be present in raw_data. Lengths are untrusted, so they are
+/// checked before being used to index or allocate.
+///
+/// Returns ParseError::LENGTH if the length exceeds MAX_CHUNK_LENGTH or the
+/// data remaining for the chunk's data and CRC.
+fn parse_chunk_length(raw_data: &[u8], chunk_start: usize) -> Result<usize, ParseError> {
+    let length: usize = get_size_from_bytes(raw_data, chunk_start);
+    let remaining: usize = raw_data.len().saturating_sub(chunk_start + DATA_OFFSET + CRC_LENGTH);
+    if length > MAX_CHUNK_LENGTH || length > remaining {
+        return Err(ParseError::LENGTH);
+    }
+    Ok(length)
+}
+
+
 fn write_size_to_bytes(size: usize, data: &mut Vec<u8>) {
     data.push((size >> 24) as u8);
     data.push((size >> 16) as u8);
@@ -504,8 +781,8 @@ fn write_size_to_bytes(size: usize, data: &mut Vec<u8>) {
 /// struct wrapped in an Option.
 ///
 /// If the signature or IHDR is invalid, returns None.
-fn parse_ihdr(raw_data: &Vec<u8>) -> Result<PNGInfo, ParseError> {
-    if &raw_data[0..SIGNATURE_LENGTH] != PNG_SIGNATURE {
+fn parse_ihdr(raw_data: &[u8]) -> Result<PNGInfo, ParseError> {
+    if raw_data.len() < SIGNATURE_LENGTH || &raw_data[0..SIGNATURE_LENGTH] != PNG_SIGNATURE {
         return Err(ParseError::SIGNATURE);
     }
     if raw_data.len() < FIRST_CHUNK_AFTER_IHDR {
@@ -518,6 +795,7 @@ fn parse_ihdr(raw_data: &Vec<u8>) -> Result<PNGInfo, ParseError> {
     if &raw_data[SIGNATURE_LENGTH+TYPE_OFFSET..SIGNATURE_LENGTH+DATA_OFFSET] != "IHDR".as_bytes() {
         return Err(ParseError::TYPE);
     }
+    verify_chunk_crc(&raw_data[SIGNATURE_LENGTH..FIRST_CHUNK_AFTER_IHDR])?;
     let offset: usize = SIGNATURE_LENGTH + DATA_OFFSET;
     let width = get_size_from_bytes(&raw_data, offset);
     let height = get_size_from_bytes(&raw_data, offset + 4);
@@ -539,25 +817,105 @@ fn parse_ihdr(raw_data: &Vec<u8>) -> Result<PNGInfo, ParseError> {
 ///
 /// Returns the data from the PLTE chunk as a slice wrapped in an Option, if
 /// the PLTE chunk exists. If the chunk does not exist, returns None.
-fn parse_plte(raw_data: &Vec<u8>) -> Result<Vec<u8>, ParseError> {
-    let plte_data: Vec<u8>;
+fn parse_plte(raw_data: &[u8]) -> Result<Vec<u8>, ParseError> {
+    parse_chunk_before_idat(raw_data, "PLTE".as_bytes())
+}
+
+
+/// Searches for and parses the first chunk of the given type, stopping once
+/// it sees an IDAT chunk. Suitable for any chunk which the PNG specification
+/// requires to precede the first IDAT chunk.
+///
+/// Returns the data from the chunk, or ParseError::MISSING if there is no such
+/// chunk before the image data.
+fn parse_chunk_before_idat(raw_data: &[u8], chunk_type: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let chunk_data: Vec<u8>;
     let mut chunk_start: usize = FIRST_CHUNK_AFTER_IHDR;
     loop {
         if raw_data.len() < chunk_start + DATA_OFFSET + CRC_LENGTH {
             return Err(ParseError::LENGTH);
         }
-        let length: usize = get_size_from_bytes(&raw_data, chunk_start);
         if &raw_data[chunk_start+TYPE_OFFSET..chunk_start+DATA_OFFSET] == "IDAT".as_bytes()
             || &raw_data[chunk_start+TYPE_OFFSET..chunk_start+DATA_OFFSET] == "IEND".as_bytes() {
             return Err(ParseError::MISSING);
         }
-        if &raw_data[chunk_start+TYPE_OFFSET..chunk_start+DATA_OFFSET] == "PLTE".as_bytes() {
-            plte_data = (&raw_data[chunk_start+DATA_OFFSET..chunk_start+DATA_OFFSET+length]).to_vec();
+        let length: usize = parse_chunk_length(raw_data, chunk_start)?;
+        if &raw_data[chunk_start+TYPE_OFFSET..chunk_start+DATA_OFFSET] == chunk_type {
+            let chunk_end: usize = chunk_start + DATA_OFFSET + length + CRC_LENGTH;
+            verify_chunk_crc(&raw_data[chunk_start..chunk_end])?;
+            chunk_data = (&raw_data[chunk_start+DATA_OFFSET..chunk_start+DATA_OFFSET+length]).to_vec();
             break;
         }
         chunk_start += DATA_OFFSET + length + CRC_LENGTH;
     }
-    Ok(plte_data)
+    Ok(chunk_data)
+}
+
+
+/// Searches for and parses the sBIT chunk, if it exists, which gives the
+/// number of significant bits in each channel of the original image. The sBIT
+/// chunk must precede the PLTE and first IDAT chunks.
+///
+/// Returns one significant bit count per channel (for indexed-color images,
+/// per channel of the palette entries), or ParseError::MISSING if the chunk
+/// does not exist.
+fn parse_sbit(raw_data: &[u8], info: &PNGInfo) -> Result<Vec<u8>, ParseError> {
+    let sbit_data = parse_chunk_before_idat(raw_data, "sBIT".as_bytes())?;
+    // For indexed-color images, sBIT applies to the channels of the palette entries
+    let (channels, max_bits): (usize, u8) = if info.color_type == INDEXED_COLOR {
+        (PLTE_CHANNELS, 8)
+    } else {
+        (channel_count(info.color_type), info.bit_depth)
+    };
+    if sbit_data.len() != channels
+        || sbit_data.iter().any(|&bits| bits == 0 || bits > max_bits) {
+        return Err(ParseError::LENGTH);
+    }
+    Ok(sbit_data)
+}
+
+
+/// Searches for and parses the pHYs chunk, if it exists, which gives the
+/// physical size of the pixels of the original image. The pHYs chunk must
+/// precede the first IDAT chunk.
+///
+/// Returns ParseError::MISSING if the chunk does not exist.
+fn parse_phys(raw_data: &[u8]) -> Result<PhysicalDimensions, ParseError> {
+    let phys_data = parse_chunk_before_idat(raw_data, "pHYs".as_bytes())?;
+    if phys_data.len() != PHYS_DATA_LENGTH || phys_data[8] > PHYS_UNIT_METRE {
+        return Err(ParseError::LENGTH);
+    }
+    Ok(PhysicalDimensions {
+        x_pixels_per_unit: get_size_from_bytes(&phys_data, 0) as u32,
+        y_pixels_per_unit: get_size_from_bytes(&phys_data, 4) as u32,
+        unit: phys_data[8],
+    })
+}
+
+
+/// Scales the pixel density of the original by the ratio of thumbnail size
+/// to original size, so that the thumbnail has the same physical size. The
+/// densities are swapped if the image is rotated by a quarter turn.
+///
+/// With an unknown unit, the densities only give the pixel aspect ratio,
+/// which resampling keeps, so they are not scaled, since rounding small
+/// values would change the ratio.
+fn scale_phys(phys: PhysicalDimensions, ratio: f64, rotation: Rotation) -> PhysicalDimensions {
+    let (x, y): (u32, u32) = match rotation {
+        Rotation::Clockwise90 | Rotation::Clockwise270 => (phys.y_pixels_per_unit, phys.x_pixels_per_unit),
+        _ => (phys.x_pixels_per_unit, phys.y_pixels_per_unit),
+    };
+    if phys.unit != PHYS_UNIT_METRE {
+        return PhysicalDimensions { x_pixels_per_unit: x, y_pixels_per_unit: y, unit: phys.unit };
+    }
+    // a density of 0 is meaningless, so never round down to it, and PNG
+    // integers are limited to 2^31 - 1 like chunk lengths
+    let scale = |density: u32| max(1, min(MAX_CHUNK_LENGTH as u64, (density as f64 * ratio + 0.5) as u64)) as u32;
+    PhysicalDimensions {
+        x_pixels_per_unit: scale(x),
+        y_pixels_per_unit: scale(y),
+        unit: phys.unit,
+    }
 }
 
 
@@ -568,22 +926,35 @@ fn parse_plte(raw_data: &Vec<u8>) -> Result<Vec<u8>, ParseError> {
 /// Concatenates all the IDAT data into one Vec<u8>. Returns that data Vec in
 /// an Option wrapper, or returns None if the data is missing or there is some
 /// other error.
-fn parse_idat(raw_data: &Vec<u8>) -> Result<Vec<u8>, ParseError> {
+fn parse_idat(raw_data: &[u8]) -> Result<Vec<u8>, ParseError> {
     let mut idat_data: Vec<u8> = Vec::new();
     let mut chunk_start: usize = FIRST_CHUNK_AFTER_IHDR;
     let mut seen_idat: bool = false;
+    let mut finished_idat: bool = false;
     loop {
         if raw_data.len() < chunk_start + DATA_OFFSET + CRC_LENGTH {
-            return Err(ParseError::LENGTH);
+            // Ran out of data before IEND, so the image is likely truncated
+            return Err(ParseError::MISSING);
         }
-        let length: usize = get_size_from_bytes(&raw_data, chunk_start);
-        if &raw_data[chunk_start+TYPE_OFFSET..chunk_start+DATA_OFFSET] == "IDAT".as_bytes() {
+        let length: usize = parse_chunk_length(raw_data, chunk_start)?;
+        let chunk_type: &[u8] = &raw_data[chunk_start+TYPE_OFFSET..chunk_start+DATA_OFFSET];
+        if chunk_type == "IDAT".as_bytes() {
+            if finished_idat {
+                return Err(ParseError::ORDER);  // IDAT chunks must be consecutive
+            }
             seen_idat = true;
+            let chunk_end: usize = chunk_start + DATA_OFFSET + length + CRC_LENGTH;
+            verify_chunk_crc(&raw_data[chunk_start..chunk_end])?;
             for byte in &raw_data[chunk_start+DATA_OFFSET..chunk_start+DATA_OFFSET+length] {
                 idat_data.push(*byte);
             }
-        } else if seen_idat {
+        } else if chunk_type == "IEND".as_bytes() {
+            if !seen_idat {
+                return Err(ParseError::ORDER);
+            }
             break;
+        } else if seen_idat {
+            finished_idat = true;
         }
         chunk_start += DATA_OFFSET + length + CRC_LENGTH;
     }
@@ -594,6 +965,68 @@ fn parse_idat(raw_data: &Vec<u8>) -> Result<Vec<u8>, ParseError> {
 }
 
 
+/// Searches the raw data for ancillary chunks of the given types, anywhere
+/// before IEND, so that they can be copied into another PNG.
+///
+/// Returns the complete chunks (length, type, data, and CRC) concatenated in
+/// their original order, or ParseError::MISSING if the data ends before IEND.
+fn parse_ancillary_chunks(raw_data: &[u8], chunk_types: &[&[u8]]) -> Result<Vec<u8>, ParseError> {
+    let mut chunk_data: Vec<u8> = Vec::new();
+    let mut chunk_start: usize = FIRST_CHUNK_AFTER_IHDR;
+    loop {
+        if raw_data.len() < chunk_start + DATA_OFFSET + CRC_LENGTH {
+            return Err(ParseError::MISSING);
+        }
+        let length: usize = parse_chunk_length(raw_data, chunk_start)?;
+        let chunk_end: usize = chunk_start + DATA_OFFSET + length + CRC_LENGTH;
+        let chunk_type: &[u8] = &raw_data[chunk_start+TYPE_OFFSET..chunk_start+DATA_OFFSET];
+        if chunk_type == "IEND".as_bytes() {
+            break;
+        }
+        // Critical chunks have an uppercase first letter, and are never copied,
+        // nor is pHYs, which generate_thumbnail rescales instead
+        if chunk_type[0] & 0x20 != 0 && chunk_types.contains(&chunk_type) && chunk_type != "pHYs".as_bytes() {
+            verify_chunk_crc(&raw_data[chunk_start..chunk_end])?;
+            for byte in &raw_data[chunk_start..chunk_end] {
+                chunk_data.push(*byte);
+            }
+        }
+        chunk_start = chunk_end;
+    }
+    Ok(chunk_data)
+}
+
+
+/// Rotates unfiltered, non-interlaced data clockwise by whole pixels. For
+/// Clockwise90 and Clockwise270 the caller must swap the width and height of
+/// the image info afterward, since rows of the result are columns of the original.
+fn rotate_image(info: &PNGInfo, data: Vec<u8>, rotation: Rotation) -> Vec<u8> {
+    if rotation == Rotation::None {
+        return data;
+    }
+    let bytes_per_pixel: usize = compute_bytes_per_pixel(info);
+    let (width, height): (usize, usize) = (info.width, info.height);
+    let mut rotated: Vec<u8> = Vec::with_capacity(data.len());
+    let (new_width, new_height): (usize, usize) = match rotation {
+        Rotation::Clockwise180 => (width, height),
+        _ => (height, width),
+    };
+    for new_row in 0..new_height {
+        for new_col in 0..new_width {
+            // Find the original pixel which lands at this position
+            let (orig_col, orig_row): (usize, usize) = match rotation {
+                Rotation::Clockwise90 => (new_row, height - 1 - new_col),
+                Rotation::Clockwise180 => (width - 1 - new_col, height - 1 - new_row),
+                _ => (width - 1 - new_row, new_col),    // Clockwise270
+            };
+            let orig_start: usize = (orig_row * width + orig_col) * bytes_per_pixel;
+            rotated.extend_from_slice(&data[orig_start..orig_start + bytes_per_pixel]);
+        }
+    }
+    rotated
+}
+
+
 fn deindex_color(idat_data: Vec<u8>, plte_data: Vec<u8>) -> Vec<u8> {
     assert!(plte_data.len() % 3 == 0);
     let mut color_data: Vec<u8> = Vec::with_capacity(idat_data.len() * PLTE_CHANNELS);
@@ -607,30 +1040,289 @@ fn deindex_color(idat_data: Vec<u8>, plte_data: Vec<u8>) -> Vec<u8> {
 }
 
 
+/// A group of similar colors, as a range into the list of unique colors.
+struct ColorBox {
+    start: usize,
+    end: usize,
+}
+
+
+/// Returns the channel (0 = red, 1 = green, 2 = blue) with the largest spread
+/// of values among the given colors, along with that spread.
+fn widest_channel(colors: &[([u8; PLTE_CHANNELS], u32)]) -> (usize, u8) {
+    let mut widest: (usize, u8) = (0, 0);
+    for channel in 0..PLTE_CHANNELS {
+        let min = colors.iter().map(|(color, _)| color[channel]).min().unwrap_or(0);
+        let max = colors.iter().map(|(color, _)| color[channel]).max().unwrap_or(0);
+        if max - min > widest.1 {
+            widest = (channel, max - min);
+        }
+    }
+    widest
+}
+
+
+/// Reduces the colors of the given RGB or RGBA pixels to a palette of at most
+/// max_colors entries using the median cut algorithm. Alpha is ignored, since
+/// PLTE entries have no alpha channel.
+///
+/// Repeatedly splits the group of colors with the widest channel spread at the
+/// median of that channel (weighted by pixel count), then uses the average
+/// color of each group as a palette entry. If the image has no more than
+/// max_colors unique colors, the palette contains exactly those colors.
+///
+/// Returns the PLTE data and one palette index per pixel, ready to be written
+/// with construct_indexed_png.
+fn quantize_median_cut(color_data: &[u8], bytes_per_pixel: usize, max_colors: usize) -> (Vec<u8>, Vec<u8>) {
+    assert!(bytes_per_pixel == 3 || bytes_per_pixel == 4);
+    assert!(max_colors >= 1 && max_colors <= MAX_PALETTE_COLORS);
+    let mut pixels: Vec<[u8; PLTE_CHANNELS]> = color_data.chunks_exact(bytes_per_pixel)
+        .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect();
+    pixels.sort_unstable();
+    // unique colors with the number of pixels of each color
+    let mut colors: Vec<([u8; PLTE_CHANNELS], u32)> = Vec::new();
+    for pixel in pixels {
+        match colors.last_mut() {
+            Some((color, count)) if *color == pixel => *count += 1,
+            _ => colors.push((pixel, 1)),
+        }
+    }
+
+    let mut boxes: Vec<ColorBox> = Vec::with_capacity(max_colors);
+    boxes.push(ColorBox { start: 0, end: colors.len() });
+    while boxes.len() < max_colors {
+        let widest = boxes.iter().enumerate()
+            .filter(|(_, color_box)| color_box.end - color_box.start > 1)
+            .map(|(i, color_box)| (i, widest_channel(&colors[color_box.start..color_box.end])))
+            .max_by_key(|(_, (_, spread))| *spread);
+        let (box_index, channel) = match widest {
+            Some((i, (channel, spread))) if spread > 0 => (i, channel),
+            _ => break, // every group holds a single color
+        };
+        let ColorBox { start, end } = boxes[box_index];
+        let slice = &mut colors[start..end];
+        slice.sort_unstable_by_key(|(color, _)| color[channel]);
+        let total: u32 = slice.iter().map(|(_, count)| count).sum();
+        let mut seen: u32 = 0;
+        let mut split: usize = 1;
+        for (i, (_, count)) in slice.iter().enumerate() {
+            seen += count;
+            if seen * 2 >= total {
+                split = (i + 1).min(slice.len() - 1).max(1);   // both halves must be non-empty
+                break;
+            }
+        }
+        boxes[box_index] = ColorBox { start, end: start + split };
+        boxes.push(ColorBox { start: start + split, end });
+    }
+
+    let mut plte_data: Vec<u8> = Vec::with_capacity(boxes.len() * PLTE_CHANNELS);
+    // unique colors with the palette index of their group
+    let mut lookup: Vec<([u8; PLTE_CHANNELS], u8)> = Vec::with_capacity(colors.len());
+    for (palette_index, color_box) in boxes.iter().enumerate() {
+        let mut sums: [u64; PLTE_CHANNELS] = [0; PLTE_CHANNELS];
+        let mut total: u64 = 0;
+        for (color, count) in &colors[color_box.start..color_box.end] {
+            for channel in 0..PLTE_CHANNELS {
+                sums[channel] += color[channel] as u64 * *count as u64;
+            }
+            total += *count as u64;
+            lookup.push((*color, palette_index as u8));
+        }
+        for channel in 0..PLTE_CHANNELS {
+            plte_data.push(((sums[channel] + total / 2) / total) as u8);    // rounded average
+        }
+    }
+    lookup.sort_unstable();
+    let indices: Vec<u8> = color_data.chunks_exact(bytes_per_pixel)
+        .map(|pixel| {
+            let color = [pixel[0], pixel[1], pixel[2]];
+            let position = lookup.binary_search_by_key(&color, |(color, _)| *color).unwrap();
+            lookup[position].1
+        })
+        .collect();
+    (plte_data, indices)
+}
+
+
+/// Returns the index of the palette entry closest to the given color, using
+/// the squared Euclidean distance in RGB. Ties go to the lowest index.
+fn nearest_palette_index(color: &[u8], plte_data: &[u8]) -> u8 {
+    let mut nearest: (usize, u32) = (0, u32::MAX);
+    for (index, entry) in plte_data.chunks_exact(PLTE_CHANNELS).enumerate() {
+        let distance: u32 = (0..PLTE_CHANNELS)
+            .map(|channel| {
+                let difference = color[channel] as i32 - entry[channel] as i32;
+                (difference * difference) as u32
+            })
+            .sum();
+        if distance < nearest.1 {
+            nearest = (index, distance);
+        }
+    }
+    nearest.0 as u8
+}
+
+
+/// Maps each RGB or RGBA pixel to the nearest entry of an existing palette,
+/// so that an indexed image can be re-encoded with its original PLTE after
+/// its colors have been averaged. Alpha is ignored.
+///
+/// Returns one palette index per pixel, ready to be written with
+/// construct_indexed_png.
+fn map_to_palette(color_data: &[u8], bytes_per_pixel: usize, plte_data: &[u8]) -> Vec<u8> {
+    assert!(bytes_per_pixel == 3 || bytes_per_pixel == 4);
+    assert!(plte_data.len() % PLTE_CHANNELS == 0 && plte_data.len() > 0);
+    color_data.chunks_exact(bytes_per_pixel)
+        .map(|pixel| nearest_palette_index(pixel, plte_data))
+        .collect()
+}
+
+
+/// Maps each RGB or RGBA pixel to a palette entry like map_to_palette, but
+/// with Floyd-Steinberg error diffusion: the difference between each pixel and
+/// its chosen entry is spread over the unvisited neighbors (7/16 to the right,
+/// and 3/16, 5/16, and 1/16 below left, below, and below right), so that
+/// gradients become a mix of nearby entries rather than flat bands. Alpha is
+/// ignored.
+///
+/// Returns one palette index per pixel, ready to be written with
+/// construct_indexed_png.
+fn dither_to_palette(color_data: &[u8], bytes_per_pixel: usize, width: usize, plte_data: &[u8]) -> Vec<u8> {
+    assert!(bytes_per_pixel == 3 || bytes_per_pixel == 4);
+    assert!(plte_data.len() % PLTE_CHANNELS == 0 && plte_data.len() > 0);
+    assert!(color_data.len() % (width * bytes_per_pixel) == 0);
+    // Errors in sixteenths, padded by one pixel on each side so that edge
+    // pixels need no special cases
+    let row_errors: usize = (width + 2) * PLTE_CHANNELS;
+    let mut current_errors: Vec<i32> = Vec::with_capacity(row_errors);
+    let mut next_errors: Vec<i32> = Vec::with_capacity(row_errors);
+    for _ in 0..row_errors {
+        current_errors.push(0);
+        next_errors.push(0);
+    }
+    let mut indices: Vec<u8> = Vec::with_capacity(color_data.len() / bytes_per_pixel);
+    for row in color_data.chunks_exact(width * bytes_per_pixel) {
+        for (col, pixel) in row.chunks_exact(bytes_per_pixel).enumerate() {
+            let error_start: usize = (col + 1) * PLTE_CHANNELS;
+            let mut adjusted: [u8; PLTE_CHANNELS] = [0; PLTE_CHANNELS];
+            for channel in 0..PLTE_CHANNELS {
+                let value: i32 = pixel[channel] as i32 + current_errors[error_start + channel] / 16;
+                adjusted[channel] = min(max(value, 0), 255) as u8;
+            }
+            let index: u8 = nearest_palette_index(&adjusted, plte_data);
+            indices.push(index);
+            let entry: &[u8] = &plte_data[index as usize * PLTE_CHANNELS..(index as usize + 1) * PLTE_CHANNELS];
+            for channel in 0..PLTE_CHANNELS {
+                let error: i32 = adjusted[channel] as i32 - entry[channel] as i32;
+                current_errors[error_start + PLTE_CHANNELS + channel] += error * 7;
+                next_errors[error_start - PLTE_CHANNELS + channel] += error * 3;
+                next_errors[error_start + channel] += error * 5;
+                next_errors[error_start + PLTE_CHANNELS + channel] += error;
+            }
+        }
+        core::mem::swap(&mut current_errors, &mut next_errors);
+        for error in next_errors.iter_mut() {
+            *error = 0;
+        }
+    }
+    indices
+}
+
+
+/// Flattens 8-bit color data with a trailing alpha channel onto an opaque
+/// background, so that it can be shown on a medium without transparency.
+/// The background has one value per color channel, i.e. three for truecolor
+/// with alpha and one for greyscale with alpha.
+///
+/// Returns the composited color data without the alpha channel.
+fn composite_over_background(color_data: &[u8], bytes_per_pixel: usize, background: &[u8]) -> Vec<u8> {
+    assert!(bytes_per_pixel == 2 || bytes_per_pixel == 4);
+    assert!(background.len() == bytes_per_pixel - 1);
+    let mut new_data: Vec<u8> = Vec::with_capacity(color_data.len() / bytes_per_pixel * background.len());
+    for pixel in color_data.chunks_exact(bytes_per_pixel) {
+        let alpha = pixel[bytes_per_pixel - 1] as u32;
+        for (channel, backdrop) in pixel.iter().zip(background.iter()) {
+            // Round to nearest when dividing out the alpha scale
+            new_data.push(((*channel as u32 * alpha + *backdrop as u32 * (255 - alpha) + 127) / 255) as u8);
+        }
+    }
+    new_data
+}
+
+
+/// Converts 8-bit truecolor data, with or without alpha, to greyscale using
+/// the Rec. 709 luminance weights, keeping any alpha channel as is.
+///
+/// If linear_light is true then the sRGB-encoded channels are first decoded to
+/// linear light, weighted, and encoded again, which gives the perceptually
+/// correct luminance. Otherwise the weights are applied directly to the
+/// encoded values, which is cheaper but makes saturated colors too dark.
+fn convert_to_greyscale(color_data: &[u8], bytes_per_pixel: usize, linear_light: bool) -> Vec<u8> {
+    assert!(bytes_per_pixel == 3 || bytes_per_pixel == 4);
+    const WEIGHTS: [f64; 3] = [0.2126, 0.7152, 0.0722];
+    let mut new_data: Vec<u8> = Vec::with_capacity(color_data.len() / bytes_per_pixel * (bytes_per_pixel - 2));
+    for pixel in color_data.chunks_exact(bytes_per_pixel) {
+        if linear_light {
+            let luminance: f64 = (0..3).map(|i| WEIGHTS[i] * SRGB_TO_LINEAR[pixel[i] as usize]).sum();
+            new_data.push(linear_to_srgb(luminance));
+        } else {
+            let luma: f64 = (0..3).map(|i| WEIGHTS[i] * pixel[i] as f64).sum();
+            new_data.push((luma + 0.5) as u8);
+        }
+        if bytes_per_pixel == 4 {
+            new_data.push(pixel[3]);
+        }
+    }
+    new_data
+}
+
+
+/// Clears the bits of each channel which the sBIT data declares insignificant,
+/// since averaging can leave noise in bits the original image did not use.
+/// Expects one byte or two big-endian bytes per channel, according to the
+/// bit depth; sub-byte samples are left unchanged.
+fn mask_significant_bits(info: &PNGInfo, color_data: &mut Vec<u8>, sbit_data: &[u8]) {
+    let channels: usize = sbit_data.len();
+    if info.bit_depth == 16 {
+        for (i, sample) in color_data.chunks_exact_mut(2).enumerate() {
+            let mask = (0xffff_0000u32 >> sbit_data[i % channels]) as u16;
+            sample[0] &= (mask >> 8) as u8;
+            sample[1] &= mask as u8;
+        }
+    } else if info.bit_depth == 8 {
+        for (i, byte) in color_data.iter_mut().enumerate() {
+            *byte &= (0xff00u32 >> sbit_data[i % channels]) as u8;
+        }
+    }
+}
+
+
 fn compute_orig_pixel_offset(orig_size: usize, new_size: usize, ratio: f64) -> usize {
     // Use when shrinking an image
-    println!("Computing orig pixel offset when orig={:?}, new={:?}, ratio={:?}", orig_size, new_size, ratio);
+    verbose_log!("Computing orig pixel offset when orig={:?}, new={:?}, ratio={:?}", orig_size, new_size, ratio);
     let scaled_new_size: f64 = new_size as f64 / ratio;
-    println!("Scaled new size = {:?}", scaled_new_size);
+    verbose_log!("Scaled new size = {:?}", scaled_new_size);
     let leftover: f64 = orig_size as f64 - scaled_new_size;
-    println!("Leftover pixels = {:?}", leftover);
+    verbose_log!("Leftover pixels = {:?}", leftover);
     let offset: f64 = leftover / 2.0;
-    println!("Offset = {:?}", offset);
-    println!("Offset as usize = {:?}", offset as usize);
+    verbose_log!("Offset = {:?}", offset);
+    verbose_log!("Offset as usize = {:?}", offset as usize);
     return offset as usize;
 }
 
 
 fn compute_new_pixel_offset(orig_size: usize, new_size: usize, ratio: f64) -> usize {
     // Use when stretching an image
-    println!("Computing new pixel offset when orig={:?}, new={:?}, ratio={:?}", orig_size, new_size, ratio);
+    verbose_log!("Computing new pixel offset when orig={:?}, new={:?}, ratio={:?}", orig_size, new_size, ratio);
     let scaled_orig_size: f64 = orig_size as f64 * ratio;
-    println!("Scaled orig size = {:?}", scaled_orig_size);
+    verbose_log!("Scaled orig size = {:?}", scaled_orig_size);
     let leftover: f64 = scaled_orig_size - new_size as f64;
-    println!("Leftover pixels = {:?}", leftover);
+    verbose_log!("Leftover pixels = {:?}", leftover);
     let offset: f64 = leftover / 2.0;
-    println!("Offset = {:?}", offset);
-    println!("Offset as usize = {:?}", offset as usize);
+    verbose_log!("Offset = {:?}", offset);
+    verbose_log!("Offset as usize = {:?}", offset as usize);
     return offset as usize;
 }
 
@@ -638,7 +1330,8 @@ fn compute_new_pixel_offset(orig_size: usize, new_size: usize, ratio: f64) -> us
 fn compute_thumbnail_generation_info(orig_info: &PNGInfo,
                                      max_width: usize,
                                      max_height: usize,
-                                     zoom_to_fill: bool
+                                     zoom_to_fill: bool,
+                                     no_upscale: bool
                                      ) -> ThumbnailGenerationInfo {
     let mut generation_info: ThumbnailGenerationInfo = ThumbnailGenerationInfo {
         width: 0, height: 0, ratio: 0.0, x_pixel_offset: 0, y_pixel_offset: 0
@@ -671,64 +1364,146 @@ fn compute_thumbnail_generation_info(orig_info: &PNGInfo,
         if h_ratio < v_ratio {  // scale to fit max_width
             generation_info.ratio = h_ratio;
             generation_info.width = max_width;
-            generation_info.height = (orig_info.height as f64 * h_ratio) as usize;
+            // an extreme aspect ratio must still leave at least one row
+            generation_info.height = max((orig_info.height as f64 * h_ratio) as usize, 1);
         } else {    // scale to fit max_height
             generation_info.ratio = v_ratio;
-            generation_info.width = (orig_info.width as f64 * v_ratio) as usize;
+            generation_info.width = max((orig_info.width as f64 * v_ratio) as usize, 1);
             generation_info.height = max_height;
         }
     }
+    if no_upscale && generation_info.ratio > 1.0 {
+        // Keep the original scale, cropping any dimension which exceeds its maximum
+        generation_info.ratio = 1.0;
+        generation_info.width = min(orig_info.width, max_width);
+        generation_info.height = min(orig_info.height, max_height);
+        generation_info.x_pixel_offset =
+            compute_orig_pixel_offset(orig_info.width, generation_info.width, 1.0);
+        generation_info.y_pixel_offset =
+            compute_orig_pixel_offset(orig_info.height, generation_info.height, 1.0);
+    }
     return generation_info;
 }
 
 
+/// Computes the footprint of each new pixel along one dimension of the
+/// original image, when new pixel i covers original positions from
+/// offset + i / ratio to offset + (i + 1) / ratio.
+///
+/// Returns, for each new pixel, the first original pixel it overlaps and the
+/// fraction of each successive original pixel which it covers. Footprints are
+/// clipped to the original image, so may be empty past its end.
+fn compute_box_filter_weights(new_length: usize, ratio: f64, offset: usize,
+                              orig_length: usize) -> Vec<(usize, Vec<f64>)> {
+    let mut footprints: Vec<(usize, Vec<f64>)> = Vec::with_capacity(new_length);
+    for new_index in 0..new_length {
+        let low: f64 = offset as f64 + new_index as f64 / ratio;
+        let mut high: f64 = offset as f64 + (new_index + 1) as f64 / ratio;
+        if high > orig_length as f64 {
+            high = orig_length as f64;
+        }
+        let first: usize = low as usize;    // truncation is floor, since low >= 0
+        let mut weights: Vec<f64> = Vec::new();
+        let mut orig_index: usize = first;
+        while (orig_index as f64) < high {
+            let covered_low: f64 = if low > orig_index as f64 { low } else { orig_index as f64 };
+            let covered_high: f64 = if high < (orig_index + 1) as f64 { high } else { (orig_index + 1) as f64 };
+            weights.push(covered_high - covered_low);
+            orig_index += 1;
+        }
+        footprints.push((first, weights));
+    }
+    footprints
+}
+
+
+/// Shrinks the original image with a box filter: each new pixel is the
+/// average of the original pixels under its exact footprint, with original
+/// pixels on the edges of the footprint weighted by the fraction covered.
+/// Unlike assigning each original pixel wholly to one new pixel, this gives
+/// every new pixel the same share of the original even when the ratio does
+/// not divide the dimensions evenly, which would otherwise cause banding.
+///
+/// If linear_light is true then 8-bit color samples are decoded from sRGB to
+/// linear light before averaging, and encoded again afterwards, since
+/// averaging the encoded values darkens the mix of light and dark pixels.
+/// Alpha and 16-bit samples are always averaged as they are.
 fn shrink_image(orig_info: &PNGInfo, orig_data: Vec<u8>,
                 new_width: usize, new_height: usize, ratio: f64,
-                x_pixel_offset: usize, y_pixel_offset: usize) -> Vec<u8> {
+                x_pixel_offset: usize, y_pixel_offset: usize, linear_light: bool) -> Vec<u8> {
     let bytes_per_pixel = compute_bytes_per_pixel(&orig_info);
+    // 16-bit samples are big-endian byte pairs, which must be averaged as a whole
+    let bytes_per_sample: usize = if orig_info.bit_depth == 16 { 2 } else { 1 };
+    let samples_per_pixel: usize = bytes_per_pixel / bytes_per_sample;
+    // the samples before any alpha sample, which are converted to linear light
+    let linear_samples: usize = if !linear_light || bytes_per_sample == 2 {
+        0
+    } else if orig_info.color_type == GREYSCALE_WITH_ALPHA || orig_info.color_type == TRUECOLOR_WITH_ALPHA {
+        samples_per_pixel - 1
+    } else {
+        samples_per_pixel
+    };
     let new_pixels: usize = new_width * new_height;
     let new_bytes: usize = new_pixels * bytes_per_pixel;
-    println!("Shrinking image to {:?}x{:?} ({:?} bytes)", new_height, new_width, new_bytes);
+    verbose_log!("Shrinking image to {:?}x{:?} ({:?} bytes)", new_height, new_width, new_bytes);
     let mut new_data: Vec<u8> = Vec::with_capacity(new_bytes);
-    let mut sums: Vec<u32> = Vec::with_capacity(new_bytes);
-    let mut counts: Vec<u32> = Vec::with_capacity(new_bytes);
-    for _ in 0..new_bytes {
-        sums.push(0u32);
-    }
-    for _ in 0..new_pixels {
-        counts.push(0u32);
-    }
     let bytes_per_orig_row: usize = orig_info.width * bytes_per_pixel;
-    let x_byte_offset: usize = x_pixel_offset * bytes_per_pixel;
-    let y_byte_offset: usize = y_pixel_offset * bytes_per_orig_row;
-    let orig_row_limit: usize = (new_height as f64 / ratio) as usize;
-    let orig_col_limit: usize = (new_width as f64 / ratio) as usize;
-    for row in 0..orig_row_limit {
-        let orig_row_start_byte: usize = row * bytes_per_orig_row + y_byte_offset + x_byte_offset;
-        let new_row_start_index: usize = (row as f64 * ratio) as usize * new_width;
-        for col in 0..orig_col_limit {
-            let orig_col_start_byte: usize = col * bytes_per_pixel + orig_row_start_byte;
-            let new_col_index: usize = (col as f64 * ratio) as usize;
-            let new_index: usize = new_row_start_index + new_col_index;
-            let new_col_start_byte: usize = new_index * bytes_per_pixel;
-            for i in 0..bytes_per_pixel {
-                sums[new_col_start_byte + i] += orig_data[orig_col_start_byte + i] as u32;
+    let col_footprints = compute_box_filter_weights(new_width, ratio, x_pixel_offset, orig_info.width);
+    let row_footprints = compute_box_filter_weights(new_height, ratio, y_pixel_offset, orig_info.height);
+    let mut sums: Vec<f64> = Vec::with_capacity(samples_per_pixel);
+    for (first_row, row_weights) in &row_footprints {
+        for (first_col, col_weights) in &col_footprints {
+            sums.clear();
+            sums.resize(samples_per_pixel, 0.0);
+            let mut total_weight: f64 = 0.0;
+            for (row_index, row_weight) in row_weights.iter().enumerate() {
+                let orig_row_start_byte: usize = (first_row + row_index) * bytes_per_orig_row;
+                for (col_index, col_weight) in col_weights.iter().enumerate() {
+                    let weight: f64 = row_weight * col_weight;
+                    let orig_col_start_byte: usize = orig_row_start_byte + (first_col + col_index) * bytes_per_pixel;
+                    for i in 0..samples_per_pixel {
+                        let sample_byte: usize = orig_col_start_byte + i * bytes_per_sample;
+                        let sample: f64 = if bytes_per_sample == 2 {
+                            (((orig_data[sample_byte] as u32) << 8) | orig_data[sample_byte + 1] as u32) as f64
+                        } else if i < linear_samples {
+                            SRGB_TO_LINEAR[orig_data[sample_byte] as usize]
+                        } else {
+                            orig_data[sample_byte] as f64
+                        };
+                        sums[i] += sample * weight;
+                    }
+                    total_weight += weight;
+                }
+            }
+            for i in 0..samples_per_pixel {
+                // if no original pixels fell into this one, then leave it black
+                let average: u32 = if total_weight <= 0.0 {
+                    0
+                } else if i < linear_samples {
+                    linear_to_srgb(sums[i] / total_weight) as u32
+                } else {
+                    (sums[i] / total_weight + 0.5) as u32
+                };
+                if bytes_per_sample == 2 {
+                    new_data.push((average >> 8) as u8);
+                }
+                new_data.push(average as u8);
             }
-            counts[new_index] += 1;
         }
     }
-    for byte_index in 0..new_bytes {
-        new_data.push((sums[byte_index] / counts[byte_index / bytes_per_pixel]) as u8);
-        // might be faster to use nested loop through bytes_per_pixel per column, to avoid second
-        // division
-    }
     return new_data;
 }
 
 
+/// Enlarges (or crops at the same scale) the original image, or shrinks it
+/// without averaging. If bilinear is false, then each new pixel copies the
+/// original pixel under its center, which keeps hard edges but looks blocky
+/// when enlarging. If bilinear is true, then each new pixel interpolates
+/// between the four original pixels nearest its center, clamped to the edges
+/// of the original image.
 fn stretch_image(orig_info: &PNGInfo, orig_data: Vec<u8>,
                  new_width: usize, new_height: usize, ratio: f64,
-                 x_pixel_offset: usize, y_pixel_offset: usize) -> Vec<u8> {
+                 x_pixel_offset: usize, y_pixel_offset: usize, bilinear: bool) -> Vec<u8> {
     let bytes_per_pixel = compute_bytes_per_pixel(&orig_info);
     let new_pixels: usize = new_width * new_height;
     let new_bytes: usize = new_pixels * bytes_per_pixel;
@@ -736,15 +1511,53 @@ fn stretch_image(orig_info: &PNGInfo, orig_data: Vec<u8>,
     for _ in 0..new_bytes {
         new_data.push(0u8);
     }
-    println!("Stretching image to {:?}x{:?} ({:?} bytes)", new_height, new_width, new_bytes);
+    verbose_log!("Stretching image to {:?}x{:?} ({:?} bytes)", new_height, new_width, new_bytes);
     let bytes_per_orig_row: usize = orig_info.width * bytes_per_pixel;
     let bytes_per_new_row: usize = new_width * bytes_per_pixel;
+    if bilinear {
+        let bytes_per_sample: usize = if orig_info.bit_depth == 16 { 2 } else { 1 };
+        let samples_per_pixel: usize = bytes_per_pixel / bytes_per_sample;
+        let read_sample = |byte: usize| -> f64 {
+            if bytes_per_sample == 2 {
+                (((orig_data[byte] as u32) << 8) | orig_data[byte + 1] as u32) as f64
+            } else {
+                orig_data[byte] as f64
+            }
+        };
+        for row in 0..new_height {
+            let (orig_row, next_row, y_weight) =
+                bilinear_neighbors(row + y_pixel_offset, ratio, orig_info.height);
+            for col in 0..new_width {
+                let (orig_col, next_col, x_weight) =
+                    bilinear_neighbors(col + x_pixel_offset, ratio, orig_info.width);
+                let new_col_start_byte: usize = row * bytes_per_new_row + col * bytes_per_pixel;
+                for i in 0..samples_per_pixel {
+                    let sample_offset: usize = i * bytes_per_sample;
+                    let top_left = read_sample(orig_row * bytes_per_orig_row + orig_col * bytes_per_pixel + sample_offset);
+                    let top_right = read_sample(orig_row * bytes_per_orig_row + next_col * bytes_per_pixel + sample_offset);
+                    let bottom_left = read_sample(next_row * bytes_per_orig_row + orig_col * bytes_per_pixel + sample_offset);
+                    let bottom_right = read_sample(next_row * bytes_per_orig_row + next_col * bytes_per_pixel + sample_offset);
+                    let top: f64 = top_left + (top_right - top_left) * x_weight;
+                    let bottom: f64 = bottom_left + (bottom_right - bottom_left) * x_weight;
+                    let value: u32 = (top + (bottom - top) * y_weight + 0.5) as u32;
+                    let new_byte: usize = new_col_start_byte + sample_offset;
+                    if bytes_per_sample == 2 {
+                        new_data[new_byte] = (value >> 8) as u8;
+                        new_data[new_byte + 1] = value as u8;
+                    } else {
+                        new_data[new_byte] = value as u8;
+                    }
+                }
+            }
+        }
+        return new_data;
+    }
     for row in 0..new_height {
         let new_row_start_byte: usize = row * bytes_per_new_row;
-        let orig_row: usize = ((row + y_pixel_offset) as f64 / ratio) as usize;
+        let orig_row: usize = nearest_index(row + y_pixel_offset, ratio, orig_info.height);
         let orig_row_start_byte: usize = orig_row * bytes_per_orig_row; // excluding the x byte offset
         for col in 0..new_width {
-            let orig_col: usize = ((col + x_pixel_offset) as f64 / ratio) as usize;
+            let orig_col: usize = nearest_index(col + x_pixel_offset, ratio, orig_info.width);
             let orig_col_start_byte = orig_col * bytes_per_pixel + orig_row_start_byte;
             let new_col_start_byte: usize = col * bytes_per_pixel + new_row_start_byte;
             for i in 0..bytes_per_pixel {
@@ -756,6 +1569,73 @@ fn stretch_image(orig_info: &PNGInfo, orig_data: Vec<u8>,
 }
 
 
+/// Maps the center of a new pixel back into the original image, and returns
+/// the index of the original pixel it falls in, clamped to less than
+/// orig_length.
+fn nearest_index(new_index: usize, ratio: f64, orig_length: usize) -> usize {
+    let position: f64 = (new_index as f64 + 0.5) / ratio;
+    (position as usize).min(orig_length - 1)   // truncation is floor, since position > 0
+}
+
+
+/// Maps the center of a new pixel back into the original image, and returns
+/// the two original pixel indices on either side of it along one dimension,
+/// along with the weight of the second. Coordinates past the first or last
+/// pixel centers are clamped, so the indices are always less than orig_length.
+fn bilinear_neighbors(new_index: usize, ratio: f64, orig_length: usize) -> (usize, usize, f64) {
+    let position: f64 = (new_index as f64 + 0.5) / ratio - 0.5;
+    if position <= 0.0 {
+        return (0, 0, 0.0);
+    }
+    let lower: usize = position as usize;   // truncation is floor, since position > 0
+    if lower + 1 >= orig_length {
+        return (orig_length - 1, orig_length - 1, 0.0);
+    }
+    (lower, lower + 1, position - lower as f64)
+}
+
+
+/// Converts an 8-bit RGBA color to the bytes of one pixel with the color type
+/// and bit depth (8 or 16) of the given image, dropping alpha if it has none.
+fn background_pixel(info: &PNGInfo, rgba: [u8; 4]) -> Vec<u8> {
+    let color: Vec<u8> = match info.color_type {
+        GREYSCALE => convert_to_greyscale(&rgba, 4, false)[..1].to_vec(),
+        GREYSCALE_WITH_ALPHA => convert_to_greyscale(&rgba, 4, false),
+        TRUECOLOR => rgba[..3].to_vec(),
+        _ => rgba.to_vec(),
+    };
+    if info.bit_depth == 16 {
+        // repeating the byte scales it exactly, since 0xffff = 0xff * 257
+        color.iter().flat_map(|&sample| [sample, sample]).collect()
+    } else {
+        color
+    }
+}
+
+
+/// Centers the image in a new image of new_width x new_height, which must be
+/// at least as large, filling the border with copies of the background pixel.
+fn pad_image(orig_info: &PNGInfo, orig_data: Vec<u8>,
+             new_width: usize, new_height: usize, background: &[u8]) -> Vec<u8> {
+    let bytes_per_pixel = compute_bytes_per_pixel(&orig_info);
+    assert_eq!(background.len(), bytes_per_pixel);
+    let x_offset: usize = (new_width - orig_info.width) / 2;
+    let y_offset: usize = (new_height - orig_info.height) / 2;
+    verbose_log!("Padding image to {:?}x{:?} at offset ({:?}, {:?})", new_height, new_width, x_offset, y_offset);
+    let mut new_data: Vec<u8> = Vec::with_capacity(new_width * new_height * bytes_per_pixel);
+    for _ in 0..new_width * new_height {
+        new_data.extend_from_slice(background);
+    }
+    let bytes_per_orig_row: usize = orig_info.width * bytes_per_pixel;
+    let bytes_per_new_row: usize = new_width * bytes_per_pixel;
+    for (row, orig_row) in orig_data.chunks_exact(bytes_per_orig_row).enumerate() {
+        let new_row_start_byte: usize = (row + y_offset) * bytes_per_new_row + x_offset * bytes_per_pixel;
+        new_data[new_row_start_byte..new_row_start_byte + bytes_per_orig_row].copy_from_slice(orig_row);
+    }
+    new_data
+}
+
+
 fn write_png_signature(data: &mut Vec<u8>) {
     for byte in &PNG_SIGNATURE {
         data.push(*byte);
@@ -812,8 +1692,38 @@ fn write_palette_as_plte(plte_data: &Vec<u8>, png_data: &mut Vec<u8>) {
 }
 
 
-fn write_iend(data: &mut Vec<u8>) {
-    write_size_to_bytes(0, data);
+fn write_phys(phys: &PhysicalDimensions, png_data: &mut Vec<u8>) {
+    write_size_to_bytes(PHYS_DATA_LENGTH, png_data);
+    let slice_start: usize = png_data.len();
+    for byte in "pHYs".as_bytes() {
+        png_data.push(*byte);
+    }
+    write_size_to_bytes(phys.x_pixels_per_unit as usize, png_data);
+    write_size_to_bytes(phys.y_pixels_per_unit as usize, png_data);
+    png_data.push(phys.unit);
+    let slice_end: usize = png_data.len();
+    let slice: &[u8] = &png_data[slice_start..slice_end];
+    write_size_to_bytes(compute_crc(slice) as usize, png_data);
+}
+
+
+fn write_chunk(chunk_type: &[u8], chunk_data: &[u8], png_data: &mut Vec<u8>) {
+    write_size_to_bytes(chunk_data.len(), png_data);
+    let slice_start: usize = png_data.len();
+    for byte in chunk_type {
+        png_data.push(*byte);
+    }
+    for byte in chunk_data {
+        png_data.push(*byte);
+    }
+    let slice_end: usize = png_data.len();
+    let slice: &[u8] = &png_data[slice_start..slice_end];
+    write_size_to_bytes(compute_crc(slice) as usize, png_data);
+}
+
+
+fn write_iend(data: &mut Vec<u8>) {
+    write_size_to_bytes(0, data);
     let slice_start: usize = data.len();
     for byte in "IEND".as_bytes() {
         data.push(*byte);
@@ -824,25 +1734,33 @@ fn write_iend(data: &mut Vec<u8>) {
 }
 
 
-fn construct_png(thumbnail_info: PNGInfo, compressed_data: Vec<u8>) -> Vec<u8> {
-    let total_size: usize = SIGNATURE_LENGTH + IHDR_TOTAL_LENGTH + DATA_OFFSET
-        + compressed_data.len() + CRC_LENGTH + IEND_TOTAL_LENGTH;
+fn construct_png(thumbnail_info: PNGInfo, compressed_data: Vec<u8>, ancillary_data: Vec<u8>) -> Vec<u8> {
+    let total_size: usize = SIGNATURE_LENGTH + IHDR_TOTAL_LENGTH + ancillary_data.len()
+        + DATA_OFFSET + compressed_data.len() + CRC_LENGTH + IEND_TOTAL_LENGTH;
     let mut png_data: Vec<u8> = Vec::with_capacity(total_size);
     write_png_signature(&mut png_data);
     write_info_as_ihdr(&thumbnail_info, &mut png_data);
+    // Ancillary chunks directly after IHDR precede PLTE and IDAT, as some require
+    for byte in &ancillary_data {
+        png_data.push(*byte);
+    }
     write_data_as_idat(&compressed_data, &mut png_data);
     write_iend(&mut png_data);
     return png_data;
 }
 
 
-fn construct_indexed_png(thumbnail_info: PNGInfo, compressed_data: Vec<u8>, plte_data: Vec<u8>) -> Vec<u8> {
-    let total_size: usize = SIGNATURE_LENGTH + IHDR_TOTAL_LENGTH + DATA_OFFSET
-        + compressed_data.len() + CRC_LENGTH + DATA_OFFSET + plte_data.len()
+fn construct_indexed_png(thumbnail_info: PNGInfo, compressed_data: Vec<u8>, plte_data: Vec<u8>,
+                         ancillary_data: Vec<u8>) -> Vec<u8> {
+    let total_size: usize = SIGNATURE_LENGTH + IHDR_TOTAL_LENGTH + ancillary_data.len()
+        + DATA_OFFSET + compressed_data.len() + CRC_LENGTH + DATA_OFFSET + plte_data.len()
         + CRC_LENGTH + IEND_TOTAL_LENGTH;
     let mut png_data: Vec<u8> = Vec::with_capacity(total_size);
     write_png_signature(&mut png_data);
     write_info_as_ihdr(&thumbnail_info, &mut png_data);
+    for byte in &ancillary_data {
+        png_data.push(*byte);
+    }
     write_palette_as_plte(&plte_data, &mut png_data);
     write_data_as_idat(&compressed_data, &mut png_data);
     write_iend(&mut png_data);
@@ -850,103 +1768,1641 @@ fn construct_indexed_png(thumbnail_info: PNGInfo, compressed_data: Vec<u8>, plte
 }
 
 
-/// Generates a thumbnail for the image represented by the given raw bytes.
+/// Parses, decompresses, and unfilters the IDAT data of a PNG whose IHDR has
+/// already been parsed into png_info, deinterlacing it and expanding sub-byte
+/// samples to 8 bits. Updates png_info to describe the returned data, which
+/// has no filter type bytes, is not interlaced, and is still indexed if the
+/// original was.
+fn decode_image_data(raw_data: &[u8], png_info: &mut PNGInfo) -> Result<Vec<u8>, ParseError> {
+    let idat_data: Vec<u8>;
+    match parse_idat(raw_data) {
+        Ok(data) => idat_data = data,
+        Err(e) => return Err(e),    // Error or missing required IDAT chunk
+    }
+
+    let decompressed_data: Vec<u8>;
+    match decompress_data(idat_data) {
+        Ok(data) => decompressed_data = data,
+        Err(e) => return Err(e),    // Corrupt or truncated image data
+    }
+    verbose_log!("Decompressed data from IDAT blocks:");
+
+    let mut unfiltered_data: Vec<u8>;
+    if png_info.interlace_method == 1 {
+        match unfilter_interlaced_data(png_info, decompressed_data) {
+            Ok(data) => unfiltered_data = data,
+            Err(e) => return Err(e),    // Image data does not match the IHDR dimensions
+        }
+        png_info.interlace_method = 0;
+    } else {
+        match unfilter_data(png_info, decompressed_data) {
+            Ok(data) => unfiltered_data = data,
+            Err(e) => return Err(e),    // Image data does not match the IHDR dimensions
+        }
+    };
+    if png_info.bit_depth < 8 {
+        unfiltered_data = unpack_samples(png_info, unfiltered_data);
+        png_info.bit_depth = 8;
+    }
+    verbose_log!("Unfiltered the data:");
+    Ok(unfiltered_data)
+}
+
+
+/// Decodes a PNG into its pixels, without generating a thumbnail.
 ///
-/// raw_bytes:      the unaltered bytes of the png file
-/// max_width:      the maximum width allowed for the thumbnail
-/// max_height:     the maximum height allowed for the thumbnail
-/// zoom_to_fill:   if true then fits the less constrained dimension to the
-///                 corresponding maximum size, and crops the more constrained
-///                 dimension to fit the its corresponding maximum size;
-///                 otherwise, zooms to fit the original aspect ratio within
-///                 the given maximum dimensions
+/// Returns the color data as rows of pixels from top to bottom, with no
+/// padding between rows and samples in the order given by the color type
+/// (16-bit samples are big-endian), along with an ImageInfo describing them.
+/// Indexed-color images are converted to truecolor using their palette.
+pub fn decode_png(raw_bytes: &[u8]) -> Result<(ImageInfo, Vec<u8>), ParseError> {
+    let mut png_info: PNGInfo;
+    match parse_ihdr(raw_bytes) {
+        Ok(info) => png_info = info,
+        Err(e) => return Err(e),
+    }
+    if !check_png_info_valid(&png_info) {
+        return Err(ParseError::HEADER);
+    }
+    let plte_data: Vec<u8>;
+    if png_info.color_type == INDEXED_COLOR {
+        match parse_plte(raw_bytes) {
+            Ok(data) => plte_data = data,
+            Err(e) => return Err(e),    // Error or missing required PLTE chunk
+        }
+    } else { plte_data = Vec::with_capacity(0); }
+
+    let mut color_data: Vec<u8>;
+    match decode_image_data(raw_bytes, &mut png_info) {
+        Ok(data) => color_data = data,
+        Err(e) => return Err(e),
+    }
+    if png_info.color_type == INDEXED_COLOR {
+        color_data = deindex_color(color_data, plte_data);
+        png_info.color_type = TRUECOLOR;
+    }
+    let image_info = ImageInfo {
+        width: png_info.width,
+        height: png_info.height,
+        bit_depth: png_info.bit_depth,
+        color_type: png_info.color_type,
+        channels: channel_count(png_info.color_type),
+    };
+    Ok((image_info, color_data))
+}
+
+
+/// Encodes 8-bit pixels as a complete PNG, without reading one first. The
+/// pixels are rows from top to bottom with no padding between rows, in the
+/// sample order given by color_type, which must not be INDEXED_COLOR.
+///
+/// Returns ParseError::HEADER if the dimensions or color type cannot be
+/// written, or ParseError::LENGTH if pixels does not hold exactly
+/// width * height pixels.
+pub fn encode_png(width: usize, height: usize, color_type: u8, pixels: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let png_info = PNGInfo {
+        width,
+        height,
+        bit_depth: 8,
+        color_type,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    if color_type == INDEXED_COLOR || !check_png_info_valid(&png_info) {
+        return Err(ParseError::HEADER);
+    }
+    if pixels.len() != width * height * channel_count(color_type) {
+        return Err(ParseError::LENGTH);
+    }
+    let filtered_data: Vec<u8> = filter_data(&png_info, pixels.to_vec());
+    let compressed_data: Vec<u8> = compress_data(filtered_data, DEFAULT_COMPRESSION_LEVEL);
+    Ok(construct_png(png_info, compressed_data, Vec::new()))
+}
+
+
+/// Options controlling how generate_thumbnail resizes and encodes an image.
+///
+/// The default fits the unrotated image within 150x150 pixels, averaging
+/// colors when shrinking and repeating pixels when enlarging, converts
+/// indexed-color images to truecolor, discards every ancillary chunk, and
+/// compresses with DEFAULT_COMPRESSION_LEVEL. Set only the fields which differ, e.g.
+/// `ThumbnailOptions { zoom_to_fill: true, ..Default::default() }`.
+#[derive(Debug, Clone, Copy)]
+pub struct ThumbnailOptions<'a> {
+    /// The maximum width allowed for the thumbnail
+    pub max_width: usize,
+    /// The maximum height allowed for the thumbnail
+    pub max_height: usize,
+    /// If true then fits the less constrained dimension to the corresponding
+    /// maximum size, and crops the more constrained dimension to fit its
+    /// corresponding maximum size; otherwise, zooms to fit the original aspect
+    /// ratio within the given maximum dimensions
+    pub zoom_to_fill: bool,
+    /// If true then images smaller than the maximum dimensions are kept at
+    /// their original scale rather than enlarged
+    pub no_upscale: bool,
+    /// If true and zoom_to_fill is false, then the fitted image is centered
+    /// in a thumbnail of exactly max_width x max_height, with the space on
+    /// either side filled with the background color
+    pub letterbox: bool,
+    /// The color of the letterbox bars, as 8-bit red, green, blue, and alpha,
+    /// which is converted to the color type of the thumbnail; its color is
+    /// also what transparent pixels are flattened onto when alpha is dropped
+    pub background: [u8; 4],
+    /// If true then the thumbnail is masked to the significant bits given by
+    /// the sBIT chunk of the original, if it has one
+    pub keep_significant_bits: bool,
+    /// If true then thumbnails of indexed-color images keep the original palette
+    pub preserve_palette: bool,
+    /// If nonzero, then 8-bit truecolor thumbnails are reduced to at most this
+    /// many colors, up to MAX_PALETTE_COLORS, and written as indexed-color images;
+    /// any alpha channel is dropped by compositing over the background color
+    pub palette_colors: usize,
+    /// If true then thumbnails written as indexed-color images are dithered
+    /// to their palette, which turns gradients into a mix of nearby colors
+    /// rather than flat bands
+    pub dither: bool,
+    /// How new pixels are computed from the original pixels around them
+    pub resampling: Resampling,
+    /// If true then shrunken images are averaged in linear light rather than
+    /// in sRGB-encoded values, which keeps fine light and dark detail from
+    /// darkening, at the cost of two table lookups per 8-bit color sample
+    pub linear_light: bool,
+    /// From 0 to MAX_COMPRESSION_LEVEL, trading speed for a smaller thumbnail
+    /// (see compress_data)
+    pub compression_level: u8,
+    /// The types of ancillary chunks to copy from the original
+    pub keep_chunks: &'a [&'a [u8]],
+    /// Rotation applied to the original before resampling, e.g. to honor the
+    /// orientation a camera recorded; max_width and max_height apply to the
+    /// rotated image
+    pub rotation: Rotation,
+}
+
+impl Default for ThumbnailOptions<'_> {
+    fn default() -> Self {
+        ThumbnailOptions {
+            max_width: 150,
+            max_height: 150,
+            zoom_to_fill: false,
+            no_upscale: false,
+            letterbox: false,
+            background: [0, 0, 0, 255],
+            keep_significant_bits: false,
+            preserve_palette: false,
+            palette_colors: 0,
+            dither: false,
+            resampling: Resampling::Box,
+            linear_light: false,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            keep_chunks: &[],
+            rotation: Rotation::None,
+        }
+    }
+}
+
+
+/// Generates a thumbnail for the image represented by the given raw bytes, the
+/// unaltered bytes of the png file, as configured by the given options.
 ///
-/// Average colors are used to compute the thumbnail. If the image is interlaced,
-/// then the image is first deinterlaced as part of the unfiltering process.
-/// The variable zoom_to_fill determines whether the more or less constrained
+/// Unless options.resampling is Nearest, average colors are used to shrink the
+/// image. If the image is interlaced, then the image is first deinterlaced as
+/// part of the unfiltering process.
+/// The option zoom_to_fill determines whether the more or less constrained
 /// dimension is stretched to its corresponding maximum. If zoom_to_fill is true,
 /// then the less constrained dimension is used, resulting in a thumbnail with
-/// size maximum_width x maximum_height; if zoom_to_fill is false, then the more
+/// size max_width x max_height; if zoom_to_fill is false, then the more
 /// constrained dimension is used, resulting in a thumbnail that is zoomed to
-/// fit, rather than fill.
+/// fit, rather than fill. If letterbox is also true, then the fitted image is
+/// centered in a thumbnail of max_width x max_height, padded with background.
 ///
-/// Disregards all ancillary chunks (those besides IHDR, PLTE, IDAT, and IEND).
+/// If keep_significant_bits is true and the original image has an sBIT chunk,
+/// then each channel of the thumbnail is masked to the number of significant
+/// bits declared for the corresponding channel of the original, so that the
+/// thumbnail does not contain precision which the original never had.
+///
+/// If preserve_palette is true and the original image is indexed-color, then
+/// each pixel of the thumbnail is mapped to the nearest entry of the original
+/// palette, and the thumbnail is written as an indexed-color image with that
+/// palette, which is much smaller than the equivalent truecolor image.
+/// Otherwise indexed-color images are converted to truecolor.
+///
+/// If palette_colors is nonzero and the thumbnail is 8-bit truecolor, then its
+/// colors are reduced to a palette of at most palette_colors entries with the
+/// median cut algorithm, and it is written as an indexed-color image with that
+/// palette. This applies to indexed-color originals which do not preserve
+/// their palette, too. PLTE entries have no alpha, so a thumbnail with an alpha
+/// channel is first composited over the red, green, and blue of background.
+///
+/// If dither is true, then pixels of thumbnails written as indexed-color
+/// images are mapped to the palette with Floyd-Steinberg error diffusion,
+/// rather than each to its nearest palette entry.
+///
+/// If the original image has a valid pHYs chunk, then the thumbnail has one
+/// with the pixel densities scaled by the resampling ratio, so that the
+/// thumbnail keeps the physical size of the original. Densities without a
+/// unit give only the pixel aspect ratio, so they are kept as they are.
+///
+/// Other ancillary chunks (those besides IHDR, PLTE, IDAT, and IEND) are discarded,
+/// except for those whose types are listed in keep_chunks, which are copied
+/// unchanged into the thumbnail directly after its IHDR chunk. Only chunks
+/// which stay valid once the image is resized and deindexed should be kept,
+/// e.g. tEXt or gAMA, but not tRNS for an indexed-color original.
 ///
 /// Returns the thumbnail image as a byte vector ready to be written.
-/// If an error occurs, returns the original raw_bytes, since a thumbnail
-/// cannot be computed.
-pub fn generate_thumbnail(raw_bytes: Vec<u8>, max_width: usize,
-                          max_height: usize, zoom_to_fill: bool
+/// Malformed ancillary chunks do not prevent a thumbnail from being computed:
+/// an invalid sBIT chunk is ignored, and if any chunk listed in keep_chunks is
+/// invalid then no chunks are copied. If the signature or a critical chunk is
+/// invalid, returns the error, and the caller should fall back to the original
+/// image, since a thumbnail cannot be computed. Returns ParseError::OPTIONS if
+/// the compression level or the number of palette colors is out of range.
+pub fn generate_thumbnail(raw_bytes: Vec<u8>, options: &ThumbnailOptions
                           )-> Result<Vec<u8>, ParseError> {
+    if options.compression_level > MAX_COMPRESSION_LEVEL || options.palette_colors > MAX_PALETTE_COLORS {
+        return Err(ParseError::OPTIONS);
+    }
     let mut png_info: PNGInfo;
     match parse_ihdr(&raw_bytes) {
         Ok(info) => png_info = info,
-        Err(e) => return Err(e),   // Can't parse as PNG, so return original
+        Err(e) => return Err(e),   // Can't parse as PNG, so caller should use original
+    }
+    if !check_png_info_valid(&png_info) {
+        return Err(ParseError::HEADER);     // Unsupported or invalid IHDR values
     }
-    assert!(check_png_info_valid(&png_info) == true);
 
     let plte_data: Vec<u8>;
     if png_info.color_type == INDEXED_COLOR {
         match parse_plte(&raw_bytes) {
             Ok(data) => plte_data = data,
-            Err(e) => return Err(e),    // Error or missing required PLTE chunk, so return original
+            Err(e) => return Err(e),    // Error or missing required PLTE chunk
         }
     } else { plte_data = Vec::with_capacity(0); }
-    let idat_data: Vec<u8>;
-    match parse_idat(&raw_bytes) {
-        Ok(data) => idat_data = data,
-        Err(e) => return Err(e),    // Error or missing required IDAT chunk, so return original
+    // Sub-byte samples are scaled exactly when unpacked, so need no masking
+    let sbit_data: Option<Vec<u8>> = if options.keep_significant_bits && png_info.bit_depth >= 8 {
+        match parse_sbit(&raw_bytes, &png_info) {
+            Ok(data) => Some(data),
+            Err(_) => None,     // sBIT is optional, so ignore it if missing or malformed
+        }
+    } else { None };
+    let phys: Option<PhysicalDimensions> = parse_phys(&raw_bytes).ok();    // pHYs is optional
+    let mut ancillary_data: Vec<u8>;
+    match parse_ancillary_chunks(&raw_bytes, options.keep_chunks) {
+        Ok(data) => ancillary_data = data,
+        Err(_) => ancillary_data = Vec::new(),  // copying chunks is optional, so drop them
     }
-
-    let decompressed_data = decompress_data(idat_data);
-    println!("Decompressed data from IDAT blocks:");
-
     let unfiltered_data: Vec<u8>;
-    if png_info.interlace_method == 1 {
-        unfiltered_data = unfilter_interlaced_data(&png_info, decompressed_data);
-        png_info.interlace_method = 0;
-    } else {
-        unfiltered_data = unfilter_data(&png_info, decompressed_data);
-    };
-    println!("Unfiltered the data:");
+    match decode_image_data(&raw_bytes, &mut png_info) {
+        Ok(data) => unfiltered_data = data,
+        Err(e) => return Err(e),
+    }
 
-    let color_data: Vec<u8>;
+    let mut color_data: Vec<u8>;
+    let indexed_output: bool = options.preserve_palette && png_info.color_type == INDEXED_COLOR;
     if png_info.color_type == INDEXED_COLOR {
-        color_data = deindex_color(unfiltered_data, plte_data);
+        // Colors are averaged while resampling, so resample as truecolor
+        color_data = deindex_color(unfiltered_data, plte_data.clone());
         png_info.color_type = TRUECOLOR;
     } else {
         color_data = unfiltered_data;
     }
+    color_data = rotate_image(&png_info, color_data, options.rotation);
+    if options.rotation == Rotation::Clockwise90 || options.rotation == Rotation::Clockwise270 {
+        core::mem::swap(&mut png_info.width, &mut png_info.height);
+    }
 
     let generation_info: ThumbnailGenerationInfo =
-        compute_thumbnail_generation_info(&png_info, max_width, max_height,
-                                          zoom_to_fill);
-    let thumbnail_color_data: Vec<u8> = if generation_info.ratio < 1.0 {
+        compute_thumbnail_generation_info(&png_info, options.max_width, options.max_height,
+                                          options.zoom_to_fill, options.no_upscale);
+    let mut thumbnail_color_data: Vec<u8> = if generation_info.ratio < 1.0 && options.resampling != Resampling::Nearest {
         shrink_image(&png_info,
                      color_data,
                      generation_info.width,
                      generation_info.height,
                      generation_info.ratio,
                      generation_info.x_pixel_offset,
-                     generation_info.y_pixel_offset)
-    } else {    // if image scale is the same (need to handle crop) or larger
+                     generation_info.y_pixel_offset,
+                     options.linear_light)
+    } else {    // if image scale is the same (need to handle crop) or larger, or not averaging
         stretch_image(&png_info,
                      color_data,
                      generation_info.width,
                      generation_info.height,
                      generation_info.ratio,
                      generation_info.x_pixel_offset,
-                     generation_info.y_pixel_offset)
+                     generation_info.y_pixel_offset,
+                     options.resampling == Resampling::Bilinear)
     };
-    let thumbnail_info: PNGInfo = PNGInfo {
+    let mut thumbnail_info: PNGInfo = PNGInfo {
         width: (generation_info.width),
         height: (generation_info.height),
         ..png_info
     };
-    println!("Scaled original image by {:?}", generation_info.ratio);
+    verbose_log!("Scaled original image by {:?}", generation_info.ratio);
+    if options.letterbox && !options.zoom_to_fill {
+        let background: Vec<u8> = background_pixel(&thumbnail_info, options.background);
+        thumbnail_color_data = pad_image(&thumbnail_info, thumbnail_color_data,
+                                         options.max_width, options.max_height, &background);
+        thumbnail_info.width = options.max_width;
+        thumbnail_info.height = options.max_height;
+    }
+    if let Some(phys) = phys {
+        write_phys(&scale_phys(phys, generation_info.ratio, options.rotation), &mut ancillary_data);
+    }
+
+    if let Some(sbit_data) = sbit_data {
+        mask_significant_bits(&thumbnail_info, &mut thumbnail_color_data, &sbit_data);
+    }
+
+    if options.palette_colors > 0
+        && thumbnail_info.color_type == TRUECOLOR_WITH_ALPHA && thumbnail_info.bit_depth == 8 {
+        // PLTE entries have no alpha, so flatten the thumbnail before quantizing it
+        thumbnail_color_data = composite_over_background(&thumbnail_color_data, 4,
+                                                         &options.background[..PLTE_CHANNELS]);
+        thumbnail_info.color_type = TRUECOLOR;
+    }
+    // palette index data with the palette it indexes
+    let palette_output: Option<(Vec<u8>, Vec<u8>)> = if indexed_output {
+        let index_data: Vec<u8> = if options.dither {
+            dither_to_palette(&thumbnail_color_data, PLTE_CHANNELS, thumbnail_info.width, &plte_data)
+        } else {
+            map_to_palette(&thumbnail_color_data, PLTE_CHANNELS, &plte_data)
+        };
+        Some((index_data, plte_data))
+    } else if options.palette_colors > 0
+        && thumbnail_info.color_type == TRUECOLOR && thumbnail_info.bit_depth == 8 {
+        let (plte_data, mut index_data) = quantize_median_cut(&thumbnail_color_data, PLTE_CHANNELS,
+                                                              options.palette_colors);
+        verbose_log!("Quantized thumbnail to {:?} colors", plte_data.len() / PLTE_CHANNELS);
+        if options.dither {
+            index_data = dither_to_palette(&thumbnail_color_data, PLTE_CHANNELS, thumbnail_info.width, &plte_data);
+        }
+        Some((index_data, plte_data))
+    } else { None };
+    if let Some((index_data, plte_data)) = palette_output {
+        let indexed_info: PNGInfo = PNGInfo {
+            color_type: INDEXED_COLOR,
+            ..thumbnail_info
+        };
+        let filtered_data: Vec<u8> = filter_data(&indexed_info, index_data);
+        let compressed_data: Vec<u8> = compress_data(filtered_data, options.compression_level);
+        return Ok(construct_indexed_png(indexed_info, compressed_data, plte_data, ancillary_data));
+    }
 
     let filtered_data: Vec<u8> = filter_data(&thumbnail_info, thumbnail_color_data);
-    let compressed_data: Vec<u8> = compress_data(filtered_data);
-    let chunked_data: Vec<u8> = construct_png(thumbnail_info, compressed_data);
+    let compressed_data: Vec<u8> = compress_data(filtered_data, options.compression_level);
+    let chunked_data: Vec<u8> = construct_png(thumbnail_info, compressed_data, ancillary_data);
     return Ok(chunked_data);
 }
+
+
+#[test_case]
+fn test_quantize_exact_palette() {
+    let mut color_data: Vec<u8> = Vec::new();
+    for i in 0..16u8 {  // 16 distinct colors, each used twice
+        for _ in 0..2 {
+            color_data.extend_from_slice(&[i * 16, 255 - i * 16, i * 3, 255]);
+        }
+    }
+    let (plte_data, indices) = quantize_median_cut(&color_data, 4, 16);
+    assert_eq!(plte_data.len(), 16 * PLTE_CHANNELS);
+    assert_eq!(indices.len(), 32);
+    let deindexed = deindex_color(indices, plte_data);
+    for (pixel, rgb) in color_data.chunks_exact(4).zip(deindexed.chunks_exact(3)) {
+        assert_eq!(&pixel[..3], rgb);
+    }
+}
+
+#[test_case]
+fn test_quantize_reduces_colors() {
+    let mut color_data: Vec<u8> = Vec::new();
+    for i in 0..64u8 {
+        color_data.extend_from_slice(&[i * 4, i * 2, 0]);
+    }
+    let (plte_data, indices) = quantize_median_cut(&color_data, 3, 8);
+    assert_eq!(plte_data.len(), 8 * PLTE_CHANNELS);
+    assert!(indices.iter().all(|&index| index < 8));
+    let deindexed = deindex_color(indices, plte_data);
+    for (pixel, rgb) in color_data.chunks_exact(3).zip(deindexed.chunks_exact(3)) {
+        assert!((pixel[0] as i32 - rgb[0] as i32).abs() <= 16);
+    }
+}
+
+#[test_case]
+fn test_map_to_palette_nearest_entry() {
+    let plte_data: Vec<u8> = Vec::from([
+        0, 0, 0,        // black
+        255, 255, 255,  // white
+        255, 0, 0,      // red
+        0, 0, 255,      // blue
+    ]);
+    let color_data: Vec<u8> = Vec::from([
+        10, 20, 5,      // near black
+        240, 250, 230,  // near white
+        200, 40, 30,    // near red
+        30, 10, 180,    // near blue
+        255, 0, 0,      // exactly red
+        127, 127, 127,  // slightly closer to black than to white
+    ]);
+    assert_eq!(map_to_palette(&color_data, 3, &plte_data), Vec::from([0, 1, 2, 3, 2, 0]));
+}
+
+#[test_case]
+fn test_sbit_masks_insignificant_bits() {
+    let info = PNGInfo {
+        width: 8,
+        height: 8,
+        bit_depth: 8,
+        color_type: TRUECOLOR,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    let mut color_data: Vec<u8> = Vec::new();
+    for i in 0..(8 * 8 * 3) {
+        color_data.push((i * 37 % 256) as u8);
+    }
+    let mut png_data: Vec<u8> = Vec::new();
+    write_png_signature(&mut png_data);
+    write_info_as_ihdr(&info, &mut png_data);
+    write_chunk("sBIT".as_bytes(), &[5, 5, 5], &mut png_data);
+    write_data_as_idat(&compress_data(filter_data(&info, color_data), DEFAULT_COMPRESSION_LEVEL), &mut png_data);
+    write_iend(&mut png_data);
+
+    for &keep_significant_bits in &[true, false] {
+        let thumbnail = generate_thumbnail(png_data.clone(), &ThumbnailOptions {
+            max_width: 3, max_height: 3, keep_significant_bits, ..Default::default()
+        }).expect("thumbnail generation failed");
+        let thumbnail_info = parse_ihdr(&thumbnail).expect("thumbnail has no IHDR");
+        let thumbnail_data = unfilter_data(&thumbnail_info,
+            decompress_data(parse_idat(&thumbnail).expect("thumbnail has no IDAT")).expect("could not decompress thumbnail"))
+            .expect("could not unfilter thumbnail");
+        let low_bits_clear = thumbnail_data.iter().all(|&byte| byte & 0b111 == 0);
+        assert_eq!(low_bits_clear, keep_significant_bits);
+    }
+}
+
+#[test_case]
+fn test_shrink_image_matches_gradient() {
+    let orig_png = image_diff::gradient_png(8, 8);
+    let thumbnail = generate_thumbnail(orig_png, &ThumbnailOptions {
+        max_width: 4, max_height: 4, ..Default::default()
+    }).expect("thumbnail generation failed");
+    let diff = image_diff::diff_images(&thumbnail, &image_diff::gradient_png(4, 4));
+    assert!(diff.max_error.iter().all(|&error| error <= 1));
+}
+
+#[test_case]
+fn test_stretch_image_matches_gradient() {
+    let orig_png = image_diff::gradient_png(4, 4);
+    let thumbnail = generate_thumbnail(orig_png, &ThumbnailOptions {
+        max_width: 8, max_height: 8, ..Default::default()
+    }).expect("thumbnail generation failed");
+    let diff = image_diff::diff_images(&thumbnail, &image_diff::gradient_png(8, 8));
+    assert!(diff.mean_absolute_error.iter().all(|&error| error <= 16.0));
+    assert!(diff.max_error.iter().all(|&error| error <= 32));
+}
+
+#[test_case]
+fn test_no_upscale_keeps_small_image_size() {
+    for &zoom_to_fill in &[false, true] {
+        let thumbnail = generate_thumbnail(image_diff::gradient_png(10, 10), &ThumbnailOptions {
+            max_width: 100, max_height: 100, zoom_to_fill, no_upscale: true, ..Default::default()
+        }).expect("thumbnail generation failed");
+        let diff = image_diff::diff_images(&thumbnail, &image_diff::gradient_png(10, 10));
+        assert!(diff.max_error.iter().all(|&error| error == 0));
+    }
+    let thumbnail = generate_thumbnail(image_diff::gradient_png(10, 10), &ThumbnailOptions {
+        max_width: 100, max_height: 100, ..Default::default()
+    }).expect("thumbnail generation failed");
+    let thumbnail_info = parse_ihdr(&thumbnail).expect("thumbnail has no IHDR");
+    assert_eq!((thumbnail_info.width, thumbnail_info.height), (100, 100));
+}
+
+#[test_case]
+fn test_no_upscale_icon() {
+    // an icon smaller than the default 150x150 maximum is re-encoded as is
+    let icon: Vec<u8> = image_diff::gradient_png(32, 32);
+    let thumbnail = generate_thumbnail(icon.clone(), &ThumbnailOptions {
+        no_upscale: true, ..Default::default()
+    }).expect("thumbnail generation failed");
+    let thumbnail_info = parse_ihdr(&thumbnail).expect("thumbnail has no IHDR");
+    assert_eq!((thumbnail_info.width, thumbnail_info.height), (32, 32));
+    assert_eq!(image_diff::decode(&thumbnail).1, image_diff::decode(&icon).1);
+}
+
+#[test_case]
+fn test_parse_idat_iend_before_idat() {
+    let mut png_data: Vec<u8> = Vec::new();
+    write_png_signature(&mut png_data);
+    write_info_as_ihdr(&parse_ihdr(&image_diff::gradient_png(2, 2)).unwrap(), &mut png_data);
+    write_iend(&mut png_data);
+    write_data_as_idat(&compress_data(Vec::from([0u8; 14]), DEFAULT_COMPRESSION_LEVEL), &mut png_data);
+    assert_eq!(parse_idat(&png_data), Err(ParseError::ORDER));
+}
+
+#[test_case]
+fn test_parse_idat_missing_iend() {
+    let mut png_data: Vec<u8> = image_diff::gradient_png(2, 2);
+    png_data.truncate(png_data.len() - IEND_TOTAL_LENGTH);
+    assert_eq!(parse_idat(&png_data), Err(ParseError::MISSING));
+}
+
+#[test_case]
+fn test_composite_half_alpha_red_over_white() {
+    let color_data: Vec<u8> = Vec::from([
+        255, 0, 0, 128,     // half transparent red
+        0, 0, 255, 255,     // opaque blue
+        0, 255, 0, 0,       // fully transparent green
+    ]);
+    let composited = composite_over_background(&color_data, 4, &[255, 255, 255]);
+    assert_eq!(composited, Vec::from([255, 127, 127, 0, 0, 255, 255, 255, 255]));
+}
+
+#[test_case]
+fn test_branchless_abs_matches_abs() {
+    // p - a, p - b, and p - c all lie within [-510, 510] for u8 inputs
+    for x in -510..=510 {
+        assert_eq!(branchless_abs(x), x.abs());
+    }
+    assert_eq!(branchless_abs(i32::MAX), i32::MAX);
+    assert_eq!(branchless_abs(i32::MIN + 1), i32::MAX);
+}
+
+#[test_case]
+fn test_paeth_predictor_reference_cases() {
+    // (a, b, c) -> predictor, where a is left, b is above, and c is upper left
+    assert_eq!(paeth_predictor(0, 0, 0), 0);
+    assert_eq!(paeth_predictor(20, 10, 10), 20);       // p = 20: pa = 0
+    assert_eq!(paeth_predictor(10, 20, 10), 20);       // p = 20: pa = 10, pb = 0
+    assert_eq!(paeth_predictor(50, 60, 100), 50);      // p = 10: pa = 40, pb = 50, pc = 90
+    assert_eq!(paeth_predictor(60, 100, 50), 100);     // p = 110: pa = 50, pb = 10, pc = 60
+    assert_eq!(paeth_predictor(255, 0, 128), 128);     // p = 127: pa = 128, pb = 127, pc = 1
+    assert_eq!(paeth_predictor(255, 255, 0), 255);     // p = 510, above the byte range
+    assert_eq!(paeth_predictor(0, 0, 255), 0);         // p = -255, below the byte range
+}
+
+#[test_case]
+fn test_paeth_predictor_tie_breaking() {
+    // Ties are broken in the order a, b, c
+    assert_eq!(paeth_predictor(10, 10, 20), 10);       // p = 0: pa = pb = 10, pc = 20
+    assert_eq!(paeth_predictor(40, 10, 20), 40);       // p = 30: pa = pc = 10, pb = 20
+    assert_eq!(paeth_predictor(10, 40, 20), 40);       // p = 30: pb = pc = 10, pa = 20
+    assert_eq!(paeth_predictor(7, 7, 7), 7);
+}
+
+#[test_case]
+fn test_paeth_predictor_matches_specification() {
+    // The reference implementation from the PNG specification, using abs()
+    fn reference(a: u8, b: u8, c: u8) -> u8 {
+        let p: i32 = a as i32 + b as i32 - c as i32;
+        let pa = (p - a as i32).abs();
+        let pb = (p - b as i32).abs();
+        let pc = (p - c as i32).abs();
+        if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+    }
+    for a in (0..=255u8).step_by(15) {
+        for b in (0..=255u8).step_by(15) {
+            for c in (0..=255u8).step_by(15) {
+                assert_eq!(paeth_predictor(a, b, c), reference(a, b, c));
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_random_round_trip() {
+    const ITERATIONS: usize = 200;
+    let mut prng = Prng::new(0x2545_f491);
+    let color_types: [u8; 4] = [GREYSCALE, TRUECOLOR, GREYSCALE_WITH_ALPHA, TRUECOLOR_WITH_ALPHA];
+    for _ in 0..ITERATIONS {
+        let info = PNGInfo {
+            width: prng.gen_range(1, 13) as usize,
+            height: prng.gen_range(1, 13) as usize,
+            bit_depth: [8, 16][prng.gen_range(0, 2) as usize],
+            color_type: color_types[prng.gen_range(0, 4) as usize],
+            compression_method: 0,
+            filter_method: 0,
+            interlace_method: prng.gen_range(0, 2) as u8,
+        };
+        let total_bytes: usize = info.width * info.height * compute_bytes_per_pixel(&info);
+        let mut color_data: Vec<u8> = Vec::with_capacity(total_bytes);
+        for _ in 0..total_bytes {
+            color_data.push(prng.next_u32() as u8);
+        }
+        let filtered_data = image_diff::filter_scanlines(&info, &color_data, &mut prng);
+        let mut png_data: Vec<u8> = Vec::new();
+        write_png_signature(&mut png_data);
+        write_info_as_ihdr(&info, &mut png_data);
+        write_data_as_idat(&compress_data(filtered_data, DEFAULT_COMPRESSION_LEVEL), &mut png_data);
+        write_iend(&mut png_data);
+
+        // Decoding is lossless, and so is a thumbnail at the original size
+        let (_, decoded_data) = image_diff::decode(&png_data);
+        assert_eq!(decoded_data, color_data);
+        let thumbnail = generate_thumbnail(png_data.clone(), &ThumbnailOptions {
+            max_width: info.width, max_height: info.height, ..Default::default()
+        }).expect("thumbnail generation failed");
+        assert_eq!(image_diff::decode(&thumbnail).1, color_data);
+
+        // Resampling to any other size produces an image of the requested size
+        let max_width: usize = prng.gen_range(1, 17) as usize;
+        let max_height: usize = prng.gen_range(1, 17) as usize;
+        let thumbnail = generate_thumbnail(png_data, &ThumbnailOptions {
+            max_width, max_height, zoom_to_fill: true, ..Default::default()
+        }).expect("thumbnail generation failed");
+        let (thumbnail_info, thumbnail_data) = image_diff::decode(&thumbnail);
+        assert_eq!((thumbnail_info.width, thumbnail_info.height), (max_width, max_height));
+        assert_eq!(thumbnail_data.len(), max_width * max_height * compute_bytes_per_pixel(&info));
+    }
+    crate::serial_println!("round-tripped {} random images", ITERATIONS);
+}
+
+#[test_case]
+fn test_unfilter_interlaced_data_checks_length() {
+    let info = PNGInfo {
+        width: 9,
+        height: 5,
+        bit_depth: 8,
+        color_type: TRUECOLOR,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 1,
+    };
+    let color_data: Vec<u8> = (0..(9 * 5 * 3)).map(|i| i as u8).collect();
+    let mut prng = Prng::new(1);
+    let filtered_data = image_diff::filter_scanlines(&info, &color_data, &mut prng);
+    assert_eq!(compute_interlaced_data_length(&info), Some(filtered_data.len()));
+    assert_eq!(unfilter_interlaced_data(&info, filtered_data.clone()), Ok(color_data));
+
+    let mut truncated_data = filtered_data.clone();
+    truncated_data.truncate(filtered_data.len() - 4);
+    assert_eq!(unfilter_interlaced_data(&info, truncated_data), Err(ParseError::LENGTH));
+    let mut extended_data = filtered_data;
+    extended_data.push(0);
+    assert_eq!(unfilter_interlaced_data(&info, extended_data), Err(ParseError::LENGTH));
+}
+
+#[test_case]
+fn test_unfilter_interlaced_average_filter() {
+    let info = PNGInfo {
+        width: 9,
+        height: 5,
+        bit_depth: 8,
+        color_type: TRUECOLOR,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 1,
+    };
+    // Bright samples, so that the sum of left and up overflows a byte
+    let color_data: Vec<u8> = (0..(9 * 5 * 3)).map(|i| (200 + i * 37 % 56) as u8).collect();
+    let filtered_data = image_diff::filter_scanlines_as(&info, &color_data, 3);
+    assert_eq!(unfilter_interlaced_data(&info, filtered_data), Ok(color_data));
+}
+
+#[test_case]
+fn test_unfilter_data_checks_length() {
+    let info = parse_ihdr(&image_diff::gradient_png(4, 3)).expect("could not parse IHDR");
+    let filtered_data: Vec<u8> = filter_data(&info, Vec::from([7u8; 4 * 3 * 3]));
+    assert_eq!(unfilter_data(&info, filtered_data.clone()), Ok(Vec::from([7u8; 4 * 3 * 3])));
+    // Shorter than the number of rows, which used to underflow the capacity
+    assert_eq!(unfilter_data(&info, Vec::from([0u8; 2])), Err(ParseError::LENGTH));
+    let mut truncated_data = filtered_data;
+    truncated_data.pop();
+    assert_eq!(unfilter_data(&info, truncated_data), Err(ParseError::LENGTH));
+}
+
+#[test_case]
+fn test_unfilter_huge_dimensions_checks_length() {
+    // The largest dimensions an IHDR chunk allows, with 8 bytes per pixel, which
+    // need more filtered bytes than a usize can count
+    let info = PNGInfo {
+        width: 0x7fff_ffff,
+        height: 0x7fff_ffff,
+        bit_depth: 16,
+        color_type: TRUECOLOR_WITH_ALPHA,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    assert_eq!(unfilter_data(&info, Vec::from([0u8; 16])), Err(ParseError::LENGTH));
+    let interlaced_info = PNGInfo { interlace_method: 1, ..info };
+    assert_eq!(compute_interlaced_data_length(&interlaced_info), None);
+    assert_eq!(unfilter_interlaced_data(&interlaced_info, Vec::from([0u8; 16])), Err(ParseError::LENGTH));
+}
+
+#[test_case]
+fn test_srgb_table_round_trips() {
+    assert_eq!(SRGB_TO_LINEAR[0], 0.0);
+    // f64::abs is not available without std
+    assert!(SRGB_TO_LINEAR[255] > 1.0 - 1e-9 && SRGB_TO_LINEAR[255] < 1.0 + 1e-9);
+    assert!(SRGB_TO_LINEAR[128] > 0.2158595 && SRGB_TO_LINEAR[128] < 0.2158615);
+    for value in 0..=255u8 {
+        assert_eq!(linear_to_srgb(SRGB_TO_LINEAR[value as usize]), value);
+    }
+}
+
+#[test_case]
+fn test_greyscale_modes_on_saturated_primaries() {
+    let color_data: Vec<u8> = Vec::from([
+        255, 0, 0,
+        0, 255, 0,
+        0, 0, 255,
+        255, 255, 255,
+    ]);
+    let encoded = convert_to_greyscale(&color_data, 3, false);
+    let linear = convert_to_greyscale(&color_data, 3, true);
+    assert_eq!(encoded, Vec::from([54, 182, 18, 255]));
+    // Luminance of 0.2126, 0.7152, and 0.0722 in linear light, encoded as sRGB
+    assert_eq!(linear, Vec::from([127, 220, 76, 255]));
+    for (e, l) in encoded.iter().zip(linear.iter()).take(3) {
+        assert!(l > e);     // weighting encoded values darkens saturated colors
+    }
+}
+
+#[test_case]
+fn test_keep_whitelisted_ancillary_chunks() {
+    let orig_png = image_diff::gradient_png(8, 8);
+    let mut text_chunk: Vec<u8> = Vec::new();
+    write_chunk("tEXt".as_bytes(), "Copyright\0Oliver Calder".as_bytes(), &mut text_chunk);
+    let mut time_chunk: Vec<u8> = Vec::new();
+    write_chunk("tIME".as_bytes(), &[0x07, 0xe5, 1, 2, 3, 4, 5], &mut time_chunk);
+    let mut png_data: Vec<u8> = Vec::from(&orig_png[..FIRST_CHUNK_AFTER_IHDR]);
+    png_data.extend_from_slice(&time_chunk);
+    png_data.extend_from_slice(&orig_png[FIRST_CHUNK_AFTER_IHDR..orig_png.len() - IEND_TOTAL_LENGTH]);
+    png_data.extend_from_slice(&text_chunk);     // tEXt may also follow the IDAT chunks
+    write_iend(&mut png_data);
+
+    let thumbnail = generate_thumbnail(png_data.clone(), &ThumbnailOptions {
+        max_width: 4, max_height: 4, keep_chunks: &["tEXt".as_bytes()], ..Default::default()
+    }).expect("thumbnail generation failed");
+    assert_eq!(&thumbnail[FIRST_CHUNK_AFTER_IHDR..FIRST_CHUNK_AFTER_IHDR + text_chunk.len()],
+               &text_chunk[..]);
+    assert!(!thumbnail.windows(4).any(|window| window == "tIME".as_bytes()));
+    let diff = image_diff::diff_images(&thumbnail, &image_diff::gradient_png(4, 4));
+    assert!(diff.max_error.iter().all(|&error| error <= 1));
+
+    let thumbnail = generate_thumbnail(png_data, &ThumbnailOptions {
+        max_width: 4, max_height: 4, ..Default::default()
+    }).expect("thumbnail generation failed");
+    assert!(!thumbnail.windows(4).any(|window| window == "tEXt".as_bytes()));
+}
+
+#[test_case]
+fn test_dither_uses_more_palette_entries() {
+    // The eight corners of the RGB cube
+    let mut plte_data: Vec<u8> = Vec::new();
+    for corner in 0..8 {
+        for channel in 0..3 {
+            plte_data.push(if corner & (1 << channel) != 0 { 255 } else { 0 });
+        }
+    }
+    // A red and green gradient through the middle of the cube, with constant blue
+    let (width, height): (usize, usize) = (32, 32);
+    let mut color_data: Vec<u8> = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            color_data.extend_from_slice(&[(96 + 2 * x) as u8, (96 + 2 * y) as u8, 100]);
+        }
+    }
+    let count_distinct = |indices: &Vec<u8>| (0..8u8).filter(|index| indices.contains(index)).count();
+    let naive = map_to_palette(&color_data, 3, &plte_data);
+    let dithered = dither_to_palette(&color_data, 3, width, &plte_data);
+    assert_eq!(dithered.len(), width * height);
+    assert!(count_distinct(&dithered) > count_distinct(&naive));
+
+    // The average color is preserved much better by dithering
+    let deindexed = deindex_color(dithered, plte_data);
+    for channel in 0..3 {
+        let orig_sum: i64 = color_data.iter().skip(channel).step_by(3).map(|&v| v as i64).sum();
+        let new_sum: i64 = deindexed.iter().skip(channel).step_by(3).map(|&v| v as i64).sum();
+        let mean_difference: i64 = (orig_sum - new_sum) / (width * height) as i64;
+        assert!(mean_difference >= -8 && mean_difference <= 8);
+    }
+}
+
+#[test_case]
+fn test_preserve_palette_round_trip() {
+    let plte_data: Vec<u8> = Vec::from([
+        0, 0, 0,
+        255, 255, 255,
+        200, 30, 30,
+        30, 30, 200,
+    ]);
+    let info = PNGInfo {
+        width: 8,
+        height: 8,
+        bit_depth: 8,
+        color_type: INDEXED_COLOR,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    // 2x2 blocks of a single index, so that halving the size averages no colors together
+    let mut index_data: Vec<u8> = Vec::new();
+    for y in 0..8 {
+        for x in 0..8 {
+            index_data.push(((x / 2 + y / 2) % 4) as u8);
+        }
+    }
+    let compressed_data = compress_data(filter_data(&info, index_data), DEFAULT_COMPRESSION_LEVEL);
+    let png_data = construct_indexed_png(info, compressed_data, plte_data.clone(), Vec::new());
+
+    let thumbnail = generate_thumbnail(png_data.clone(), &ThumbnailOptions {
+        max_width: 4, max_height: 4, preserve_palette: true, ..Default::default()
+    }).expect("thumbnail generation failed");
+    let thumbnail_info = parse_ihdr(&thumbnail).expect("thumbnail has no IHDR");
+    assert_eq!(thumbnail_info.color_type, INDEXED_COLOR);
+    assert_eq!(parse_plte(&thumbnail), Ok(plte_data));
+    let thumbnail_indices = unfilter_data(&thumbnail_info,
+        decompress_data(parse_idat(&thumbnail).expect("thumbnail has no IDAT")).expect("could not decompress thumbnail"))
+        .expect("could not unfilter thumbnail");
+    for y in 0..4 {
+        for x in 0..4 {
+            assert_eq!(thumbnail_indices[y * 4 + x], ((x + y) % 4) as u8);
+        }
+    }
+
+    // Without preserve_palette, the same colors are written as truecolor
+    let truecolor = generate_thumbnail(png_data, &ThumbnailOptions {
+        max_width: 4, max_height: 4, ..Default::default()
+    }).expect("thumbnail generation failed");
+    assert_eq!(parse_ihdr(&truecolor).expect("thumbnail has no IHDR").color_type, TRUECOLOR);
+    assert_eq!(image_diff::decode(&truecolor).1, image_diff::decode(&thumbnail).1);
+}
+
+#[test_case]
+fn test_palette_colors_quantizes_truecolor() {
+    let png_data = image_diff::gradient_png(32, 32);
+    let truecolor = generate_thumbnail(png_data.clone(), &ThumbnailOptions {
+        max_width: 16, max_height: 16, ..Default::default()
+    }).expect("thumbnail generation failed");
+    let thumbnail = generate_thumbnail(png_data.clone(), &ThumbnailOptions {
+        max_width: 16, max_height: 16, palette_colors: 16, ..Default::default()
+    }).expect("thumbnail generation failed");
+    assert_eq!(parse_ihdr(&thumbnail).expect("thumbnail has no IHDR").color_type, INDEXED_COLOR);
+    let plte_data = parse_plte(&thumbnail).expect("thumbnail has no PLTE");
+    assert!(plte_data.len() <= 16 * PLTE_CHANNELS);
+    // 16 red by 16 green steps of 16 are split into 4 x 4 groups of 4 x 4 colors,
+    // and each color is replaced by the average of its group
+    let diff = image_diff::diff_images(&truecolor, &thumbnail);
+    assert_eq!(diff.max_error, Vec::from([24, 24, 0]));
+
+    assert_eq!(generate_thumbnail(png_data, &ThumbnailOptions {
+        palette_colors: MAX_PALETTE_COLORS + 1, ..Default::default()
+    }), Err(ParseError::OPTIONS));
+}
+
+#[test_case]
+fn test_palette_colors_composites_alpha_over_background() {
+    let pixels: Vec<u8> = [255, 0, 0, 128].repeat(4 * 4);    // half transparent red
+    let png_data = encode_png(4, 4, TRUECOLOR_WITH_ALPHA, &pixels).expect("could not encode pixels");
+    let thumbnail = generate_thumbnail(png_data, &ThumbnailOptions {
+        max_width: 2, max_height: 2, palette_colors: 4, background: [0, 0, 255, 255], ..Default::default()
+    }).expect("thumbnail generation failed");
+    assert_eq!(parse_ihdr(&thumbnail).expect("thumbnail has no IHDR").color_type, INDEXED_COLOR);
+    assert_eq!(parse_plte(&thumbnail), Ok(Vec::from([128, 0, 127])));
+    assert_eq!(image_diff::decode(&thumbnail).1, [128, 0, 127].repeat(2 * 2));
+}
+
+#[test_case]
+fn test_dither_mixes_palette_entries() {
+    let plte_data: Vec<u8> = Vec::from([
+        0, 0, 0,
+        255, 255, 255,
+    ]);
+    let info = PNGInfo {
+        width: 8,
+        height: 8,
+        bit_depth: 8,
+        color_type: INDEXED_COLOR,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    // A black and white checkerboard, which halving the size averages to mid grey
+    let index_data: Vec<u8> = (0..8 * 8).map(|i| ((i % 8 + i / 8) % 2) as u8).collect();
+    let compressed_data = compress_data(filter_data(&info, index_data), DEFAULT_COMPRESSION_LEVEL);
+    let png_data = construct_indexed_png(info, compressed_data, plte_data.clone(), Vec::new());
+    let white_pixels = |dither: bool| {
+        let thumbnail = generate_thumbnail(png_data.clone(), &ThumbnailOptions {
+            max_width: 4, max_height: 4, preserve_palette: true, dither, ..Default::default()
+        }).expect("thumbnail generation failed");
+        assert_eq!(parse_plte(&thumbnail), Ok(plte_data.clone()));
+        image_diff::decode(&thumbnail).1.chunks_exact(PLTE_CHANNELS)
+            .filter(|pixel| pixel[0] == 255)
+            .count()
+    };
+    // Every grey pixel maps to the same nearest entry
+    let undithered_white: usize = white_pixels(false);
+    assert!(undithered_white == 0 || undithered_white == 16);
+    // Dithered, about half of the pixels are white
+    let dithered_white: usize = white_pixels(true);
+    assert!((6..=10).contains(&dithered_white), "{} of 16 pixels white", dithered_white);
+}
+
+#[test_case]
+fn test_16_bit_greyscale_thumbnail() {
+    let encode = |width: usize, height: usize, sample: fn(usize, usize) -> u16| {
+        let info = PNGInfo {
+            width,
+            height,
+            bit_depth: 16,
+            color_type: GREYSCALE,
+            compression_method: 0,
+            filter_method: 0,
+            interlace_method: 0,
+        };
+        let mut color_data: Vec<u8> = Vec::with_capacity(width * height * 2);
+        for y in 0..height {
+            for x in 0..width {
+                color_data.push((sample(x, y) >> 8) as u8);
+                color_data.push(sample(x, y) as u8);
+            }
+        }
+        let compressed_data = compress_data(filter_data(&info, color_data), DEFAULT_COMPRESSION_LEVEL);
+        construct_png(info, compressed_data, Vec::new())
+    };
+
+    // Averaging 0x00ff and 0x0100 must carry between the bytes of each sample,
+    // and the mean of 0x00ff.8 rounds up to 0x0100
+    let png_data = encode(4, 4, |x, _| if x % 2 == 0 { 0x00ff } else { 0x0100 });
+    let thumbnail = generate_thumbnail(png_data, &ThumbnailOptions {
+        max_width: 2, max_height: 2, ..Default::default()
+    }).expect("thumbnail generation failed");
+    let (thumbnail_info, thumbnail_data) = image_diff::decode(&thumbnail);
+    assert_eq!((thumbnail_info.bit_depth, thumbnail_info.width, thumbnail_info.height), (16, 2, 2));
+    assert_eq!(thumbnail_data, Vec::from([0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00]));
+
+    // 90000 samples of 0xffff in one pixel would overflow a u32 sum
+    let png_data = encode(300, 300, |_, _| 0xffff);
+    let thumbnail = generate_thumbnail(png_data, &ThumbnailOptions {
+        max_width: 1, max_height: 1, ..Default::default()
+    }).expect("thumbnail generation failed");
+    assert_eq!(image_diff::decode(&thumbnail).1, Vec::from([0xff, 0xff]));
+}
+
+#[test_case]
+fn test_unpack_4_bit_greyscale() {
+    let info = PNGInfo {
+        width: 5,
+        height: 2,
+        bit_depth: 4,
+        color_type: GREYSCALE,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    // Five 4-bit samples take three bytes per scanline, the last one padded
+    assert_eq!(compute_scanline_length(&info), 3);
+    let packed_data: Vec<u8> = Vec::from([
+        0x01, 0x23, 0x40,
+        0xfe, 0xdc, 0xb0,
+    ]);
+    let unfiltered_data = unfilter_data(&info, filter_data(&info, packed_data))
+        .expect("could not unfilter data");
+    let unpacked_data = unpack_samples(&info, unfiltered_data);
+    assert_eq!(unpacked_data.len(), info.width * info.height);
+    assert_eq!(unpacked_data, Vec::from([
+        0x00, 0x11, 0x22, 0x33, 0x44,
+        0xff, 0xee, 0xdd, 0xcc, 0xbb,
+    ]));
+
+    let mut png_data: Vec<u8> = Vec::new();
+    write_png_signature(&mut png_data);
+    write_info_as_ihdr(&info, &mut png_data);
+    write_data_as_idat(&compress_data(filter_data(&info, Vec::from([
+        0x01, 0x23, 0x40,
+        0xfe, 0xdc, 0xb0,
+    ])), DEFAULT_COMPRESSION_LEVEL), &mut png_data);
+    write_iend(&mut png_data);
+    let thumbnail = generate_thumbnail(png_data, &ThumbnailOptions {
+        max_width: 5, max_height: 2, ..Default::default()
+    }).expect("thumbnail generation failed");
+    let (thumbnail_info, thumbnail_data) = image_diff::decode(&thumbnail);
+    assert_eq!(thumbnail_info.bit_depth, 8);
+    assert_eq!(thumbnail_data, unpacked_data);
+}
+
+#[test_case]
+fn test_zero_width_ihdr_is_rejected() {
+    let info = PNGInfo {
+        width: 0,
+        height: 2,
+        bit_depth: 1,
+        color_type: GREYSCALE,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    // Each scanline is only its filter type byte
+    let mut png_data: Vec<u8> = Vec::new();
+    write_png_signature(&mut png_data);
+    write_info_as_ihdr(&info, &mut png_data);
+    write_data_as_idat(&compress_data(Vec::from([0, 0]), DEFAULT_COMPRESSION_LEVEL), &mut png_data);
+    write_iend(&mut png_data);
+    assert_eq!(decode_png(&png_data).err(), Some(ParseError::HEADER));
+    assert_eq!(generate_thumbnail(png_data, &ThumbnailOptions {
+        max_width: 4, max_height: 4, ..Default::default()
+    }).err(), Some(ParseError::HEADER));
+    assert!(!check_png_info_valid(&PNGInfo { width: 2, height: 0, ..info }));
+}
+
+#[test_case]
+fn test_adaptive_filter_round_trip() {
+    let info = PNGInfo {
+        width: 13,
+        height: 9,
+        bit_depth: 8,
+        color_type: TRUECOLOR,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    let mut prng = Prng::new(254);
+    let mut data: Vec<u8> = Vec::with_capacity(info.width * info.height * 3);
+    for y in 0..info.height {
+        for x in 0..info.width {
+            // Mix gradients, which favor the predicting filters, with noise
+            let noise = if y % 3 == 0 { prng.next_u32() as u8 } else { 0 };
+            data.push((x * 19) as u8 ^ noise);
+            data.push((y * 23) as u8);
+            data.push((x * y) as u8);
+        }
+    }
+    let filtered = filter_data(&info, data.clone());
+    assert_eq!(filtered.len(), info.height * (info.width * 3 + 1));
+    let stride: usize = info.width * 3 + 1;
+    let filter_types: Vec<u8> = (0..info.height).map(|row| filtered[row * stride]).collect();
+    assert!(filter_types.iter().all(|&t| t <= 4));
+    assert!(filter_types.iter().any(|&t| t != 0), "no scanline was filtered");
+    assert_eq!(unfilter_data(&info, filtered), Ok(data));
+}
+
+#[test_case]
+fn test_corrupt_chunk_fails_crc() {
+    let png_data = image_diff::gradient_png(4, 4);
+    assert!(parse_ihdr(&png_data).is_ok());
+    assert!(parse_idat(&png_data).is_ok());
+
+    let mut corrupt_ihdr = png_data.clone();
+    corrupt_ihdr[SIGNATURE_LENGTH + DATA_OFFSET] ^= 0x01;     // first byte of the width
+    assert_eq!(parse_ihdr(&corrupt_ihdr).err(), Some(ParseError::CRC));
+
+    let mut corrupt_idat = png_data.clone();
+    corrupt_idat[FIRST_CHUNK_AFTER_IHDR + DATA_OFFSET + 2] ^= 0x80;
+    assert_eq!(parse_idat(&corrupt_idat).err(), Some(ParseError::CRC));
+    assert_eq!(verify_chunk_crc(&corrupt_idat[FIRST_CHUNK_AFTER_IHDR..corrupt_idat.len() - IEND_TOTAL_LENGTH]),
+               Err(ParseError::CRC));
+}
+
+#[test_case]
+fn test_malformed_ancillary_chunks_are_ignored() {
+    let info = PNGInfo {
+        width: 4,
+        height: 4,
+        bit_depth: 8,
+        color_type: GREYSCALE,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    let color_data: Vec<u8> = (0..16).map(|i| (i * 16) as u8).collect();
+    let mut ancillary_data: Vec<u8> = Vec::new();
+    write_chunk("sBIT".as_bytes(), &[3, 3], &mut ancillary_data);     // one channel, not two
+    let text_start: usize = ancillary_data.len();
+    write_chunk("tEXt".as_bytes(), "Title\0Test".as_bytes(), &mut ancillary_data);
+    ancillary_data[text_start + DATA_OFFSET] ^= 0x20;                 // breaks the CRC
+    let png_data = construct_png(PNGInfo { ..info }, compress_data(filter_data(&info, color_data.clone()), DEFAULT_COMPRESSION_LEVEL),
+                                 ancillary_data);
+
+    let thumbnail = generate_thumbnail(png_data, &ThumbnailOptions {
+        max_width: 4, max_height: 4, keep_significant_bits: true,
+        keep_chunks: &["tEXt".as_bytes()], ..Default::default()
+    }).expect("malformed ancillary chunks should not prevent a thumbnail");
+    assert_eq!(parse_ancillary_chunks(&thumbnail, &["tEXt".as_bytes()]), Ok(Vec::new()));
+    let (_, thumbnail_data) = image_diff::decode(&thumbnail);
+    assert_eq!(thumbnail_data, color_data);     // not masked by the invalid sBIT
+}
+
+#[test_case]
+fn test_phys_is_scaled_with_thumbnail() {
+    let gradient = image_diff::gradient_png(64, 64);
+    let with_phys = |phys: PhysicalDimensions| {
+        let mut png_data: Vec<u8> = gradient[..FIRST_CHUNK_AFTER_IHDR].to_vec();
+        write_phys(&phys, &mut png_data);
+        png_data.extend_from_slice(&gradient[FIRST_CHUNK_AFTER_IHDR..]);
+        png_data
+    };
+    // 300 DPI is 11811 pixels per metre, and 150 DPI is 5905.5
+    let dpi_300 = PhysicalDimensions { x_pixels_per_unit: 11811, y_pixels_per_unit: 11811, unit: PHYS_UNIT_METRE };
+    let thumbnail = generate_thumbnail(with_phys(dpi_300), &ThumbnailOptions {
+        max_width: 32, max_height: 32, keep_chunks: &["pHYs".as_bytes()], ..Default::default()
+    }).expect("thumbnail generation failed");
+    let dpi_150 = PhysicalDimensions { x_pixels_per_unit: 5906, y_pixels_per_unit: 5906, unit: PHYS_UNIT_METRE };
+    assert_eq!(parse_phys(&thumbnail), Ok(dpi_150));
+    // kept in keep_chunks, but only the scaled chunk is written
+    assert_eq!(parse_ancillary_chunks(&thumbnail, &["pHYs".as_bytes()]), Ok(Vec::new()));
+
+    let wide_pixels = PhysicalDimensions { x_pixels_per_unit: 100, y_pixels_per_unit: 200, unit: 0 };
+    let thumbnail = generate_thumbnail(with_phys(wide_pixels), &ThumbnailOptions {
+        max_width: 16, max_height: 16, rotation: Rotation::Clockwise90, ..Default::default()
+    }).expect("thumbnail generation failed");
+    // only the aspect ratio is known, so it is swapped but not scaled
+    assert_eq!(parse_phys(&thumbnail), Ok(PhysicalDimensions { x_pixels_per_unit: 200, y_pixels_per_unit: 100, unit: 0 }));
+    // rounding small values would turn 2:3 into 1:1
+    let aspect_ratio = PhysicalDimensions { x_pixels_per_unit: 2, y_pixels_per_unit: 3, unit: 0 };
+    assert_eq!(scale_phys(aspect_ratio, 0.1, Rotation::None), aspect_ratio);
+    let metres = PhysicalDimensions { unit: PHYS_UNIT_METRE, ..aspect_ratio };
+    assert_eq!(scale_phys(metres, 0.1, Rotation::None),
+               PhysicalDimensions { x_pixels_per_unit: 1, y_pixels_per_unit: 1, unit: PHYS_UNIT_METRE });
+
+    assert_eq!(parse_phys(&gradient), Err(ParseError::MISSING));
+    let thumbnail = generate_thumbnail(gradient, &ThumbnailOptions::default()).expect("thumbnail generation failed");
+    assert_eq!(parse_phys(&thumbnail), Err(ParseError::MISSING));
+}
+
+#[test_case]
+fn test_corrupt_deflate_stream() {
+    let compressed_data = compress_data(Vec::from([7u8; 64]), DEFAULT_COMPRESSION_LEVEL);
+    assert_eq!(decompress_data(compressed_data.clone()), Ok(Vec::from([7u8; 64])));
+
+    let truncated_data: Vec<u8> = compressed_data[..compressed_data.len() / 2].to_vec();
+    assert_eq!(decompress_data(truncated_data), Err(ParseError::DECOMPRESS));
+    // A zlib header announcing a preset dictionary, which PNG never uses
+    let corrupt_data: Vec<u8> = Vec::from([0x78, 0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(decompress_data(corrupt_data.clone()), Err(ParseError::DECOMPRESS));
+
+    let info = PNGInfo {
+        width: 8,
+        height: 8,
+        bit_depth: 8,
+        color_type: GREYSCALE,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    let png_data = construct_png(info, corrupt_data, Vec::new());
+    assert_eq!(generate_thumbnail(png_data, &ThumbnailOptions { max_width: 4, max_height: 4, ..Default::default() }),
+               Err(ParseError::DECOMPRESS));
+}
+
+#[test_case]
+fn test_bilinear_stretch() {
+    let info = PNGInfo {
+        width: 2,
+        height: 2,
+        bit_depth: 8,
+        color_type: GREYSCALE,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    let orig_data: Vec<u8> = Vec::from([
+          0, 100,
+        100, 200,
+    ]);
+    // New pixel centers map to original positions -0.25, 0.25, 0.75, and 1.25,
+    // so the outer ones are clamped and the inner ones weighted 1/4 and 3/4
+    let stretched = stretch_image(&info, orig_data.clone(), 4, 4, 2.0, 0, 0, true);
+    assert_eq!(stretched, Vec::from([
+          0,  25,  75, 100,
+         25,  50, 100, 125,
+         75, 100, 150, 175,
+        100, 125, 175, 200,
+    ]));
+    let blocky = stretch_image(&info, orig_data, 4, 4, 2.0, 0, 0, false);
+    assert_eq!(blocky, Vec::from([
+          0,   0, 100, 100,
+          0,   0, 100, 100,
+        100, 100, 200, 200,
+        100, 100, 200, 200,
+    ]));
+
+    // Sampling the last pixel of a larger image must not index past its end
+    let wide_info = PNGInfo { width: 3, height: 1, ..info };
+    let stretched = stretch_image(&wide_info, Vec::from([0, 60, 120]), 9, 3, 3.0, 0, 0, true);
+    assert_eq!(&stretched[0..9], &[0, 0, 20, 40, 60, 80, 100, 120, 120]);
+}
+
+#[test_case]
+fn test_shrink_extreme_aspect_ratio() {
+    // Fitting 1000x3 into 10x10 rounds the height to zero pixels, and thumbnail
+    // pixels which receive no original pixels must not divide by a zero count
+    for &zoom_to_fill in &[false, true] {
+        let thumbnail = generate_thumbnail(image_diff::gradient_png(1000, 3), &ThumbnailOptions {
+            max_width: 10, max_height: 10, zoom_to_fill, ..Default::default()
+        }).expect("thumbnail generation failed");
+        let thumbnail_info = parse_ihdr(&thumbnail).expect("thumbnail has no IHDR");
+        assert_eq!(thumbnail_info.width, 10);
+        assert!(thumbnail_info.height >= 1 && thumbnail_info.height <= 10);
+    }
+    let info = PNGInfo {
+        width: 1000,
+        height: 3,
+        bit_depth: 8,
+        color_type: GREYSCALE,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    let shrunk = shrink_image(&info, Vec::from([200u8; 3000]), 10, 10, 0.01, 0, 0, false);
+    assert_eq!(shrunk.len(), 100);
+    assert!(shrunk[..10].iter().all(|&value| value == 200));
+    assert!(shrunk[10..].iter().all(|&value| value == 0));  // no original pixels, so black
+}
+
+#[test_case]
+fn test_decode_png() {
+    let (image_info, color_data) = decode_png(&image_diff::gradient_png(6, 4))
+        .expect("could not decode gradient");
+    assert_eq!(image_info, ImageInfo { width: 6, height: 4, bit_depth: 8, color_type: TRUECOLOR, channels: 3 });
+    assert_eq!(color_data.len(), 6 * 4 * 3);
+
+    let info = PNGInfo {
+        width: 3,
+        height: 2,
+        bit_depth: 8,
+        color_type: INDEXED_COLOR,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    let plte_data: Vec<u8> = Vec::from([255, 0, 0, 0, 255, 0, 0, 0, 255]);
+    let index_data: Vec<u8> = Vec::from([0, 1, 2, 2, 1, 0]);
+    let png_data = construct_indexed_png(PNGInfo { ..info }, compress_data(filter_data(&info, index_data.clone()), DEFAULT_COMPRESSION_LEVEL),
+                                         plte_data.clone(), Vec::new());
+    let (image_info, color_data) = decode_png(&png_data).expect("could not decode indexed image");
+    assert_eq!(image_info.color_type, TRUECOLOR);
+    assert_eq!(image_info.channels, 3);
+    assert_eq!(color_data, deindex_color(index_data, plte_data));
+
+    assert_eq!(decode_png(&PNG_SIGNATURE[..4]).err(), Some(ParseError::SIGNATURE));
+    let unsupported_png = construct_png(PNGInfo { bit_depth: 7, color_type: GREYSCALE, ..info },
+                                        compress_data(Vec::from([0u8; 14]), DEFAULT_COMPRESSION_LEVEL), Vec::new());
+    assert_eq!(decode_png(&unsupported_png).err(), Some(ParseError::HEADER));
+}
+
+#[test_case]
+fn test_encode_png() {
+    let pixels: Vec<u8> = (0..5 * 3 * 4).map(|i| (i * 5) as u8).collect();
+    let png_data = encode_png(5, 3, TRUECOLOR_WITH_ALPHA, &pixels).expect("could not encode pixels");
+    let (image_info, color_data) = decode_png(&png_data).expect("could not decode encoded image");
+    assert_eq!(image_info, ImageInfo { width: 5, height: 3, bit_depth: 8, color_type: TRUECOLOR_WITH_ALPHA, channels: 4 });
+    assert_eq!(color_data, pixels);
+
+    assert_eq!(encode_png(5, 3, TRUECOLOR, &pixels).err(), Some(ParseError::LENGTH));
+    assert_eq!(encode_png(5, 3, INDEXED_COLOR, &pixels[..15]).err(), Some(ParseError::HEADER));
+    assert_eq!(encode_png(0, 3, GREYSCALE, &[]).err(), Some(ParseError::HEADER));
+}
+
+#[test_case]
+fn test_compression_levels() {
+    let thumbnail_at = |level: u8| generate_thumbnail(image_diff::gradient_png(64, 64), &ThumbnailOptions {
+        max_width: 32, max_height: 32, compression_level: level, ..Default::default()
+    });
+    let stored = thumbnail_at(0).expect("thumbnail generation failed");
+    let smallest = thumbnail_at(9).expect("thumbnail generation failed");
+    assert!(smallest.len() < stored.len());
+    assert_eq!(image_diff::decode(&stored).1, image_diff::decode(&smallest).1);
+    assert_eq!(thumbnail_at(MAX_COMPRESSION_LEVEL + 1), Err(ParseError::OPTIONS));
+}
+
+#[test_case]
+fn test_oversized_chunk_length() {
+    let png_data: Vec<u8> = image_diff::gradient_png(2, 2);
+    let remaining: usize = png_data.len() - FIRST_CHUNK_AFTER_IHDR - DATA_OFFSET - CRC_LENGTH;
+    // Lengths past the end of the data or the specification's limit are rejected
+    for &length in &[remaining + 1, MAX_CHUNK_LENGTH + 1, 0xffff_ffff] {
+        let mut corrupt_data = png_data.clone();
+        corrupt_data.truncate(FIRST_CHUNK_AFTER_IHDR);
+        write_size_to_bytes(length, &mut corrupt_data);
+        corrupt_data.extend_from_slice(&png_data[FIRST_CHUNK_AFTER_IHDR + TYPE_OFFSET..]);
+        assert_eq!(parse_idat(&corrupt_data), Err(ParseError::LENGTH));
+        assert_eq!(parse_plte(&corrupt_data), Err(ParseError::MISSING));     // stops at IDAT
+        assert_eq!(parse_ancillary_chunks(&corrupt_data, &[]), Err(ParseError::LENGTH));
+    }
+}
+
+#[test_case]
+fn test_parse_error_display() {
+    use alloc::format;
+    assert_eq!(format!("{}", ParseError::SIGNATURE), "invalid PNG signature");
+    let corrupt_png = &PNG_SIGNATURE[..4];
+    assert_eq!(format!("Error: {}", decode_png(corrupt_png).unwrap_err()), "Error: invalid PNG signature");
+    assert_eq!(format!("{:?}", ParseError::CRC), "CRC");    // Debug still gives the variant name
+}
+
+#[test_case]
+fn test_sub_filter_wraps_past_255() {
+    let info = PNGInfo {
+        width: 6,
+        height: 1,
+        bit_depth: 8,
+        color_type: GREYSCALE,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    let filtered_data: Vec<u8> = Vec::from([1, 200, 100, 0, 255, 57, 2]);   // sub filter
+    // Reference reconstruction: each byte adds its left neighbor modulo 256
+    let mut expected_data: Vec<u8> = Vec::new();
+    let mut left: u32 = 0;
+    for &byte in &filtered_data[1..] {
+        left = (byte as u32 + left) % 256;
+        expected_data.push(left as u8);
+    }
+    assert_eq!(expected_data, Vec::from([200, 44, 44, 43, 100, 102]));
+    assert_eq!(unfilter_data(&info, filtered_data), Ok(expected_data));
+}
+
+#[test_case]
+fn test_rotate_image() {
+    let info = PNGInfo {
+        width: 2,
+        height: 3,
+        bit_depth: 8,
+        color_type: GREYSCALE,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    let data: Vec<u8> = Vec::from([
+        1, 2,
+        3, 4,
+        5, 6,
+    ]);
+    assert_eq!(rotate_image(&info, data.clone(), Rotation::None), data);
+    assert_eq!(rotate_image(&info, data.clone(), Rotation::Clockwise90), Vec::from([
+        5, 3, 1,
+        6, 4, 2,
+    ]));
+    assert_eq!(rotate_image(&info, data.clone(), Rotation::Clockwise180), Vec::from([
+        6, 5,
+        4, 3,
+        2, 1,
+    ]));
+    assert_eq!(rotate_image(&info, data.clone(), Rotation::Clockwise270), Vec::from([
+        2, 4, 6,
+        1, 3, 5,
+    ]));
+
+    let png_data = encode_png(2, 3, GREYSCALE, &data).expect("could not encode pixels");
+    let thumbnail = generate_thumbnail(png_data, &ThumbnailOptions {
+        max_width: 3, max_height: 2, rotation: Rotation::Clockwise90, ..Default::default()
+    }).expect("thumbnail generation failed");
+    let (thumbnail_info, thumbnail_data) = image_diff::decode(&thumbnail);
+    assert_eq!((thumbnail_info.width, thumbnail_info.height), (3, 2));
+    assert_eq!(thumbnail_data, Vec::from([5, 3, 1, 6, 4, 2]));
+}
+
+#[test_case]
+fn test_box_filter_checkerboard() {
+    let info = PNGInfo {
+        width: 10,
+        height: 10,
+        bit_depth: 8,
+        color_type: GREYSCALE,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    let mut checkerboard: Vec<u8> = Vec::with_capacity(100);
+    for y in 0..10 {
+        for x in 0..10 {
+            checkerboard.push(if (x + y) % 2 == 0 { 0 } else { 255 });
+        }
+    }
+    // Each new pixel covers 3 1/3 original pixels in each direction, so
+    // bucketing whole pixels would alternate between 4x4 and 3x3 blocks, and
+    // the 3x3 blocks would be 5/9 or 4/9 white
+    let shrunk = shrink_image(&info, checkerboard, 3, 3, 0.3, 0, 0, false);
+    assert_eq!(shrunk.len(), 9);
+    for &value in &shrunk {
+        assert!((122..=134).contains(&value), "value {} is not near-uniform gray", value);
+    }
+}
+
+#[test_case]
+fn test_nearest_keeps_pixel_art_crisp() {
+    // 15x15 pixel art of 3x3 blocks in a checkerboard of 40 and 200
+    let mut pixel_art: Vec<u8> = Vec::with_capacity(15 * 15);
+    for y in 0..15 {
+        for x in 0..15 {
+            pixel_art.push(if (x / 3 + y / 3) % 2 == 0 { 40 } else { 200 });
+        }
+    }
+    let png_data = encode_png(15, 15, GREYSCALE, &pixel_art).expect("could not encode pixels");
+    let thumbnail_of = |max_size: usize, resampling: Resampling| {
+        let thumbnail = generate_thumbnail(png_data.clone(), &ThumbnailOptions {
+            max_width: max_size, max_height: max_size, resampling, ..Default::default()
+        }).expect("thumbnail generation failed");
+        image_diff::decode(&thumbnail).1
+    };
+
+    // Shrinking by 4/15 puts new pixel edges inside the blocks, so averaging
+    // blurs the edges, while sampling the pixel under each center, at 1.875,
+    // 5.625, 9.375, and 13.125, does not
+    let nearest = thumbnail_of(4, Resampling::Nearest);
+    assert_eq!(nearest, Vec::from([
+         40, 200, 200,  40,
+        200,  40,  40, 200,
+        200,  40,  40, 200,
+         40, 200, 200,  40,
+    ]));
+    let averaged = thumbnail_of(4, Resampling::Box);
+    assert!(averaged.iter().any(|&value| value != 40 && value != 200));
+
+    // Enlarging by 4/3 keeps every pixel at one of the two original values
+    let enlarged = thumbnail_of(20, Resampling::Nearest);
+    assert_eq!(enlarged.len(), 20 * 20);
+    assert!(enlarged.iter().all(|&value| value == 40 || value == 200));
+    for x in 0..20 {
+        let orig_x = (2 * x + 1) * 3 / 8;   // the original pixel under the center, (x + 0.5) * 3/4
+        assert_eq!(enlarged[x], pixel_art[orig_x]);
+    }
+}
+
+#[test_case]
+fn test_letterbox_wide_image() {
+    // a 40x10 truecolor image of a single color, fitted into a 20x20 square
+    let pixels: Vec<u8> = [10u8, 200, 30].repeat(40 * 10);
+    let png_data = encode_png(40, 10, TRUECOLOR, &pixels).expect("could not encode pixels");
+    let thumbnail = generate_thumbnail(png_data, &ThumbnailOptions {
+        max_width: 20, max_height: 20, letterbox: true, background: [255, 0, 255, 255], ..Default::default()
+    }).expect("thumbnail generation failed");
+    let (thumbnail_info, thumbnail_data) = image_diff::decode(&thumbnail);
+    assert_eq!((thumbnail_info.width, thumbnail_info.height), (20, 20));
+
+    // the image is 20x5, centered with 7 rows of padding above and 8 below
+    for (row, pixels) in thumbnail_data.chunks_exact(20 * 3).enumerate() {
+        let expected: &[u8] = if (7..12).contains(&row) { &[10, 200, 30] } else { &[255, 0, 255] };
+        for pixel in pixels.chunks_exact(3) {
+            assert_eq!(pixel, expected, "wrong color in row {}", row);
+        }
+    }
+
+    // the background is converted to the color type of the thumbnail
+    let grey_info = PNGInfo {
+        width: 1,
+        height: 1,
+        bit_depth: 16,
+        color_type: GREYSCALE_WITH_ALPHA,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    };
+    assert_eq!(background_pixel(&grey_info, [255, 255, 255, 128]), Vec::from([255, 255, 128, 128]));
+}
+
+#[test_case]
+fn test_shrink_in_linear_light() {
+    let mut checkerboard: Vec<u8> = Vec::with_capacity(8 * 8 * 2);
+    for y in 0..8 {
+        for x in 0..8 {
+            checkerboard.push(if (x + y) % 2 == 0 { 0 } else { 255 });
+            checkerboard.push(255);     // opaque, and alpha is never converted
+        }
+    }
+    let png_data = encode_png(8, 8, GREYSCALE_WITH_ALPHA, &checkerboard).expect("could not encode checkerboard");
+    let thumbnail_at = |linear_light: bool| {
+        let thumbnail = generate_thumbnail(png_data.clone(), &ThumbnailOptions {
+            max_width: 4, max_height: 4, linear_light, ..Default::default()
+        }).expect("thumbnail generation failed");
+        decode_png(&thumbnail).expect("could not decode thumbnail").1
+    };
+    // Half black and half white is half as bright in linear light, which is
+    // 188 once encoded, whereas averaging the encoded values gives 128
+    assert!(thumbnail_at(true).chunks_exact(2).all(|pixel| pixel == [188, 255]));
+    assert!(thumbnail_at(false).chunks_exact(2).all(|pixel| pixel == [128, 255]));
+}
+
+
+/// Test helpers for comparing images which are expected to be similar but
+/// not byte-identical, such as the output of lossy resampling.
+#[cfg(test)]
+mod image_diff {
+    use super::*;
+    use alloc::vec;
+
+    /// Per-channel differences between two images of the same size and
+    /// color type.
+    pub struct ImageDiff {
+        pub mean_absolute_error: Vec<f64>,
+        pub max_error: Vec<u8>,
+    }
+
+    /// Decodes the given PNG into its header info and unfiltered color data,
+    /// resolving the palette of indexed-color images.
+    pub fn decode(png_data: &Vec<u8>) -> (PNGInfo, Vec<u8>) {
+        let mut info = parse_ihdr(png_data).expect("could not parse IHDR");
+        let idat_data = parse_idat(png_data).expect("could not parse IDAT");
+        let decompressed_data = decompress_data(idat_data).expect("could not decompress IDAT");
+        let mut color_data = if info.interlace_method == 1 {
+            let color_data = unfilter_interlaced_data(&info, decompressed_data)
+                .expect("could not unfilter interlaced data");
+            info.interlace_method = 0;
+            color_data
+        } else {
+            unfilter_data(&info, decompressed_data).expect("could not unfilter data")
+        };
+        if info.color_type == INDEXED_COLOR {
+            let plte_data = parse_plte(png_data).expect("could not parse PLTE");
+            color_data = deindex_color(color_data, plte_data);
+            info.color_type = TRUECOLOR;
+        }
+        (info, color_data)
+    }
+
+    /// Decodes two 8-bit PNG images and computes the mean absolute error and
+    /// maximum error of each channel. Panics if the images differ in size or
+    /// color type.
+    pub fn diff_images(png_a: &Vec<u8>, png_b: &Vec<u8>) -> ImageDiff {
+        let (info_a, data_a) = decode(png_a);
+        let (info_b, data_b) = decode(png_b);
+        assert_eq!((info_a.width, info_a.height), (info_b.width, info_b.height));
+        assert_eq!(info_a.color_type, info_b.color_type);
+        assert_eq!(info_a.bit_depth, 8);
+        assert_eq!(info_b.bit_depth, 8);
+        let channels: usize = channel_count(info_a.color_type);
+        let mut sums: Vec<u64> = vec![0; channels];
+        let mut max_error: Vec<u8> = vec![0; channels];
+        for (i, (a, b)) in data_a.iter().zip(data_b.iter()).enumerate() {
+            let error = (*a as i16 - *b as i16).abs() as u8;
+            sums[i % channels] += error as u64;
+            max_error[i % channels] = max_error[i % channels].max(error);
+        }
+        let samples_per_channel = (data_a.len() / channels) as f64;
+        ImageDiff {
+            mean_absolute_error: sums.iter().map(|&sum| sum as f64 / samples_per_channel).collect(),
+            max_error,
+        }
+    }
+
+    /// Filters the scanlines of the given color data as an encoder would,
+    /// choosing a pseudorandom filter type for each scanline. Interlaced
+    /// images are split into their seven Adam7 passes first.
+    pub fn filter_scanlines(info: &PNGInfo, color_data: &[u8], prng: &mut Prng) -> Vec<u8> {
+        filter_scanlines_with(info, color_data, || prng.gen_range(0, 5) as u8)
+    }
+
+    /// Filters the scanlines of the given color data with a single filter
+    /// type, as filter_scanlines does.
+    pub fn filter_scanlines_as(info: &PNGInfo, color_data: &[u8], filter_type: u8) -> Vec<u8> {
+        filter_scanlines_with(info, color_data, || filter_type)
+    }
+
+    fn filter_scanlines_with(info: &PNGInfo, color_data: &[u8], mut next_filter_type: impl FnMut() -> u8) -> Vec<u8> {
+        let passes: &[(usize, usize, usize, usize)] = if info.interlace_method == 1 {
+            &ADAM7_PASSES
+        } else {
+            &[(0, 0, 1, 1)]
+        };
+        let bytes_per_pixel: usize = compute_bytes_per_pixel(info);
+        let mut filtered: Vec<u8> = Vec::new();
+        for &(h_offset, v_offset, h_interval, v_interval) in passes {
+            let mut prev_row: Vec<u8> = Vec::new();
+            for y in (v_offset..info.height).step_by(v_interval) {
+                let mut row: Vec<u8> = Vec::new();
+                for x in (h_offset..info.width).step_by(h_interval) {
+                    let start: usize = (y * info.width + x) * bytes_per_pixel;
+                    row.extend_from_slice(&color_data[start..start+bytes_per_pixel]);
+                }
+                if row.is_empty() {
+                    continue;
+                }
+                let filter_type = next_filter_type();
+                filtered.push(filter_type);
+                filter_scanline(filter_type, &row, &prev_row, bytes_per_pixel, &mut filtered);
+                prev_row = row;
+            }
+        }
+        filtered
+    }
+
+    /// Encodes an 8-bit truecolor PNG of the given size with a horizontal red
+    /// gradient and a vertical green gradient. Each channel is sampled at the
+    /// pixel center, so the gradient of a smaller image is the exact average
+    /// of the same gradient at twice the size.
+    pub fn gradient_png(width: usize, height: usize) -> Vec<u8> {
+        let info = PNGInfo {
+            width,
+            height,
+            bit_depth: 8,
+            color_type: TRUECOLOR,
+            compression_method: 0,
+            filter_method: 0,
+            interlace_method: 0,
+        };
+        let mut color_data: Vec<u8> = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                color_data.push(((2 * x + 1) * 128 / width) as u8);
+                color_data.push(((2 * y + 1) * 128 / height) as u8);
+                color_data.push(128);
+            }
+        }
+        let compressed_data = compress_data(filter_data(&info, color_data), DEFAULT_COMPRESSION_LEVEL);
+        construct_png(info, compressed_data, Vec::new())
+    }
+}
@@ -0,0 +1,66 @@
+/* Seeded pseudorandom number generation
+ *
+ * There is no source of entropy in the kernel, so this is a small xorshift*
+ * generator whose output is fully determined by its seed. That makes it
+ * suitable for reproducible tests and for image processing such as dithering,
+ * but not for anything which must be unpredictable.
+ */
+
+/// A xorshift64* pseudorandom number generator.
+pub struct Prng {
+    state: u64,
+}
+
+impl Prng {
+    /// Creates a generator from the given seed. Xorshift cannot leave the
+    /// all-zero state, so a seed of zero is replaced by a fixed nonzero one.
+    pub fn new(seed: u64) -> Self {
+        Prng {
+            state: if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns the high half of the next u64, since the low bits of
+    /// xorshift* are the weakest.
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Returns a number in the half-open range [low, high). Uses modulo
+    /// reduction, so the result is very slightly biased for large ranges.
+    pub fn gen_range(&mut self, low: u64, high: u64) -> u64 {
+        assert!(low < high);
+        low + self.next_u64() % (high - low)
+    }
+}
+
+
+#[test_case]
+fn test_same_seed_same_sequence() {
+    let mut first = Prng::new(42);
+    let mut second = Prng::new(42);
+    for _ in 0..1000 {
+        assert_eq!(first.next_u64(), second.next_u64());
+    }
+    let mut other = Prng::new(43);
+    assert!((0..10).any(|_| first.next_u64() != other.next_u64()));
+}
+
+#[test_case]
+fn test_gen_range_bounds() {
+    let mut prng = Prng::new(0);
+    let mut seen = [false; 10];
+    for _ in 0..1000 {
+        let value = prng.gen_range(5, 15);
+        assert!(value >= 5 && value < 15);
+        seen[(value - 5) as usize] = true;
+    }
+    assert!(seen.iter().all(|&seen| seen));
+}
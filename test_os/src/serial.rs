@@ -8,6 +8,35 @@ lazy_static! {
         serial_port.init();
         Mutex::new(serial_port)
     };
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(0x2F8) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+/// A destination for raw bytes, such as a serial port.
+///
+/// Unlike `core::fmt::Write`, bytes are sent unaltered, which is required for
+/// binary responses like PNG data.
+pub trait RawSender {
+    fn send_raw(&mut self, byte: u8);
+
+    fn send_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.send_raw(*byte);
+        }
+    }
+
+    fn send_str(&mut self, s: &str) {
+        self.send_bytes(s.as_bytes());
+    }
+}
+
+impl RawSender for SerialPort {
+    fn send_raw(&mut self, byte: u8) {
+        SerialPort::send_raw(self, byte);
+    }
 }
 
 #[doc(hidden)]
@@ -36,3 +65,20 @@ macro_rules! serial_println {
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(concat!($fmt, "\n"), $($arg)*));
 }
+
+#[test_case]
+fn test_send_str_sends_each_byte() {
+    use alloc::vec::Vec;
+
+    struct Recorder(Vec<u8>);  // records bytes instead of writing them to a port
+    impl RawSender for Recorder {
+        fn send_raw(&mut self, byte: u8) {
+            self.0.push(byte);
+        }
+    }
+
+    let mut recorder = Recorder(Vec::new());
+    recorder.send_str("OK 64x64\n");
+    recorder.send_bytes(&[0x89, 0x50]);
+    assert_eq!(recorder.0.as_slice(), b"OK 64x64\n\x89\x50");
+}
@@ -0,0 +1,146 @@
+/* System calls
+ *
+ * Kernel services are invoked with `int 0x80`, using the following registers:
+ *
+ *   rax: the syscall number, one of the SYS_* constants below
+ *   rdi, rsi, rdx: the first, second, and third arguments
+ *
+ * The result is returned in rax, where SYSCALL_ERROR indicates failure. All
+ * other registers are preserved. The entry stub saves the registers which the
+ * C calling convention lets the dispatcher clobber, and moves the arguments
+ * into the registers the dispatcher expects, so handlers are plain Rust
+ * functions. Handlers run with interrupts disabled, since the IDT entry is an
+ * interrupt gate.
+ *
+ * There is no userland yet, so buffers passed to syscalls are trusted rather
+ * than checked against the caller's address space.
+ */
+
+use crate::{vga_buffer, serial::{SERIAL1, RawSender}, exit_qemu, hlt_loop, QemuExitCode};
+use core::arch::{asm, global_asm};
+
+/// Exits qemu, with success only if the first argument is 0. Never returns.
+pub const SYS_EXIT: u64 = 0;
+/// Writes the bytes of the buffer at the second argument, whose length is the
+/// third argument, to the file descriptor in the first argument. Returns the
+/// number of bytes written.
+pub const SYS_WRITE: u64 = 1;
+
+/// The file descriptor of the VGA text buffer.
+pub const FD_VGA: u64 = 1;
+/// The file descriptor of the first serial port.
+pub const FD_SERIAL: u64 = 2;
+
+/// Returned in rax for an unknown syscall number or invalid arguments.
+pub const SYSCALL_ERROR: u64 = u64::MAX;
+
+type SyscallHandler = fn(u64, u64, u64) -> u64;
+
+/// Handlers indexed by syscall number.
+static SYSCALL_TABLE: [SyscallHandler; 2] = [
+    sys_exit,   // SYS_EXIT
+    sys_write,  // SYS_WRITE
+];
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    // rax holds the syscall number, and is overwritten with the result
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "sub rsp, 8",       // the CPU pushed 5 words, so this aligns the stack to 16 bytes for the call
+    "mov rcx, rdx",     // shift (number, arg0, arg1, arg2) into rdi, rsi, rdx, rcx
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "call {dispatch}",
+    "add rsp, 8",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "iretq",
+    dispatch = sym dispatch,
+);
+
+extern "C" {
+    /// The handler for `int 0x80`, which does not follow the x86-interrupt
+    /// calling convention, since it must read and write general registers.
+    pub(crate) fn syscall_entry();
+}
+
+extern "C" fn dispatch(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    match SYSCALL_TABLE.get(number as usize) {
+        Some(handler) => handler(arg0, arg1, arg2),
+        None => SYSCALL_ERROR,
+    }
+}
+
+fn sys_exit(code: u64, _: u64, _: u64) -> u64 {
+    exit_qemu(if code == 0 { QemuExitCode::Success } else { QemuExitCode::Failed });
+    hlt_loop();     // exiting has no effect without the isa-debug-exit device
+}
+
+fn sys_write(fd: u64, buf: u64, len: u64) -> u64 {
+    if buf == 0 && len > 0 {
+        return SYSCALL_ERROR;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) };
+    match fd {
+        FD_VGA => {
+            for &byte in bytes {
+                vga_buffer::print_byte(byte);
+            }
+        }
+        FD_SERIAL => SERIAL1.lock().send_bytes(bytes),
+        _ => return SYSCALL_ERROR,
+    }
+    len
+}
+
+/// Invokes the syscall with the given number and arguments, returning its
+/// result.
+///
+/// Unsafe since the arguments are trusted, e.g. as pointers to buffers.
+pub unsafe fn syscall(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    let result: u64;
+    asm!(
+        "int 0x80",
+        inlateout("rax") number => result,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+    );
+    result
+}
+
+#[test_case]
+fn test_write_syscall_prints_to_vga() {
+    use vga_buffer::{WRITER, BUFFER_HEIGHT};
+
+    let s = "Written through a system call.";
+    let line = alloc::format!("\n{}\n", s);
+    let written = unsafe { syscall(SYS_WRITE, FD_VGA, line.as_ptr() as u64, line.len() as u64) };
+    assert_eq!(written, line.len() as u64);
+
+    let writer = WRITER.lock();
+    for (i, c) in s.bytes().enumerate() {
+        assert_eq!(writer.char_at(BUFFER_HEIGHT - 2, i), c);
+    }
+}
+
+#[test_case]
+fn test_unknown_syscall_fails() {
+    assert_eq!(unsafe { syscall(SYSCALL_TABLE.len() as u64, 0, 0, 0) }, SYSCALL_ERROR);
+    assert_eq!(unsafe { syscall(SYS_WRITE, 7, 0, 0) }, SYSCALL_ERROR);  // no such file descriptor
+}
@@ -0,0 +1,140 @@
+/* Channels
+ *
+ * A bounded multi-producer, single-consumer channel for passing values between
+ * tasks. Like the ScancodeStream, it pairs an ArrayQueue with an AtomicWaker
+ * for the consumer, so try_send never blocks or allocates and may also be
+ * called from interrupt handlers. The Receiver is a Stream which ends once
+ * every Sender has been dropped and the queue is drained.
+ */
+
+use alloc::sync::Arc;
+use core::{pin::Pin, task::{Poll, Context}, sync::atomic::{AtomicUsize, Ordering}};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+struct Shared<T> {
+    queue: ArrayQueue<T>,
+    waker: AtomicWaker,     // the receiver's waker
+    senders: AtomicUsize,   // live Senders, so the receiver knows when the stream ends
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a channel which holds at most `capacity` unreceived values.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: ArrayQueue::new(capacity),
+        waker: AtomicWaker::new(),
+        senders: AtomicUsize::new(1),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    /// Sends the value if the channel has room, else returns it.
+    ///
+    /// Must not block or allocate.
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        match self.shared.queue.push(value) {
+            Ok(()) => {
+                self.shared.waker.wake();   // after the push, so the receiver never wakes to an empty queue
+                Ok(())
+            }
+            Err(crossbeam_queue::PushError(value)) => Err(value),
+        }
+    }
+
+    /// Sends the value, yielding to other tasks while the channel is full.
+    pub async fn send(&self, mut value: T) {
+        loop {
+            match self.try_send(value) {
+                Ok(()) => return,
+                Err(returned) => value = returned,
+            }
+            super::yield_now().await;   // the receiver has no way to wake senders
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.waker.wake();   // the last sender is gone, so the stream ends
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let shared = &self.shared;
+        if let Ok(value) = shared.queue.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        shared.waker.register(&cx.waker());
+        match shared.queue.pop() {
+            Ok(value) => {
+                shared.waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(crossbeam_queue::PopError) => {
+                if shared.senders.load(Ordering::Acquire) == 0 {
+                    // a last value may have been sent just before its sender was dropped
+                    Poll::Ready(shared.queue.pop().ok())
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_channel_collects_from_two_senders() {
+    use super::{Task, simple_executor::SimpleExecutor};
+    use alloc::vec::Vec;
+    use futures_util::stream::StreamExt;
+    use spin::Mutex;
+
+    static RECEIVED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+    let (sender, mut receiver) = channel(2);    // smaller than what is sent, so senders must wait
+    let other_sender = sender.clone();
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        for value in 0..5 {
+            sender.send(value).await;
+        }
+    }));
+    executor.spawn(Task::new(async move {
+        for value in 100..105 {
+            other_sender.send(value).await;
+        }
+    }));
+    executor.spawn(Task::new(async move {
+        while let Some(value) = receiver.next().await {
+            RECEIVED.lock().push(value);
+        }
+    }));
+    executor.run();     // returns only once the receiver sees the end of the stream
+
+    let mut received = RECEIVED.lock().clone();
+    received.sort();
+    assert_eq!(received, [0, 1, 2, 3, 4, 100, 101, 102, 103, 104]);
+}
@@ -1,14 +1,76 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Waker, Context, Poll};
+use super::{Task, TaskId, watchdog, join::{JoinHandle, task_with_handle}};
+use crate::println;
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::{fmt, future::Future, task::{Waker, Poll}, sync::atomic::{AtomicBool, Ordering}};
 use crossbeam_queue::ArrayQueue;
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
     // Arc allows sharing ownership (between the executor and wakers) via reference counting
-    waker_cache: BTreeMap<TaskId, Waker>,
+    waker_cache: BTreeMap<TaskId, CachedWaker>,
     // Wakers are reused for the same task, and the cache ensures they are not deallocated inside interrupt handlers
+    spawn_queue: Arc<ArrayQueue<Task>>,
+    // tasks spawned through a Spawner while the executor is running, not yet moved into tasks
+    shutdown_requested: Arc<AtomicBool>,
+}
+
+/// A handle for spawning tasks onto an executor, or shutting it down, from
+/// within its running tasks, which cannot borrow the Executor itself.
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Arc<ArrayQueue<Task>>,
+    shutdown_requested: Arc<AtomicBool>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, task: Task) {
+        if self.spawn_queue.push(task).is_err() {
+            panic!("spawn_queue full");
+        }
+    }
+
+    /// Spawns the future as a task, returning a handle which can be awaited
+    /// for its output.
+    pub fn spawn_with_handle<T, F>(&self, future: F) -> JoinHandle<T>
+    where
+        T: 'static,
+        F: Future<Output = T> + 'static,
+    {
+        let (task, handle) = task_with_handle(future);
+        self.spawn(task);
+        handle
+    }
+
+    /// Makes the executor's run() drop all of its tasks and return, once the
+    /// currently running task yields.
+    pub fn shutdown(&self) {
+        self.shutdown_requested.store(true, Ordering::Relaxed);
+    }
+}
+
+struct CachedWaker {
+    waker: Waker,
+    task_waker: Arc<TaskWaker>,     // kept to count how many wakers for the task exist
+    last_poll: Poll<()>,
+}
+
+/// A snapshot of the state of a live task, for diagnosing tasks which never
+/// complete.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TaskDebugInfo {
+    pub id: TaskId,
+    /// Whether some future or interrupt handler holds a clone of the task's
+    /// waker, and so may wake it. A pending task without one is stuck.
+    pub waker_registered: bool,
+    /// The result of the most recent poll, or None if not yet polled.
+    pub last_poll: Option<Poll<()>>,
+}
+
+impl fmt::Display for TaskDebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: waker registered: {}, last poll: {:?}", self.id, self.waker_registered, self.last_poll)
+    }
 }
 
 impl Executor {
@@ -17,6 +79,17 @@ impl Executor {
             tasks: BTreeMap::new(),
             task_queue: Arc::new(ArrayQueue::new(128)),
             waker_cache: BTreeMap::new(),
+            spawn_queue: Arc::new(ArrayQueue::new(128)),
+            shutdown_requested: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns a handle which tasks can use to spawn further tasks onto this
+    /// executor while it runs.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: self.spawn_queue.clone(),
+            shutdown_requested: self.shutdown_requested.clone(),
         }
     }
 
@@ -28,25 +101,67 @@ impl Executor {
         self.task_queue.push(task_id).expect("queue full");
     }
 
+    /// Spawns the future as a task, returning a handle which can be awaited
+    /// for its output.
+    pub fn spawn_with_handle<T, F>(&mut self, future: F) -> JoinHandle<T>
+    where
+        T: 'static,
+        F: Future<Output = T> + 'static,
+    {
+        let (task, handle) = task_with_handle(future);
+        self.spawn(task);
+        handle
+    }
+
+    /// Drops the task with the given ID without polling it again, whether it
+    /// is queued or pending. Returns false if no such task is live, e.g.
+    /// because it already completed. A JoinHandle for a cancelled task never
+    /// completes.
+    pub fn cancel(&mut self, task_id: TaskId) -> bool {
+        // the task may not have been moved out of the spawn_queue yet
+        while let Ok(task) = self.spawn_queue.pop() {
+            self.spawn(task);
+        }
+        // any queued wakeups for the ID are skipped, since it is no longer in tasks
+        self.waker_cache.remove(&task_id);
+        self.tasks.remove(&task_id).is_some()
+    }
+
     fn run_ready_tasks(&mut self) {
         // destructure `self` to avoid borrow checker errors
         let Self {
             tasks,
             task_queue,
             waker_cache,
+            spawn_queue,
+            shutdown_requested,
         } = self;
 
-        while let Ok(task_id) = task_queue.pop() {
+        while !shutdown_requested.load(Ordering::Relaxed) {
+            // move in any tasks spawned through a Spawner, e.g. by the previously polled task
+            while let Ok(task) = spawn_queue.pop() {
+                let task_id = task.id;
+                if tasks.insert(task.id, task).is_some() {
+                    panic!("task with same ID already in tasks");
+                }
+                task_queue.push(task_id).expect("queue full");
+            }
+            let task_id = match task_queue.pop() {
+                Ok(task_id) => task_id,
+                Err(_) => break,
+            };
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue,   // task no longer exists
             };
-            let waker = waker_cache
+            let cached_waker = waker_cache
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+                .or_insert_with(|| CachedWaker::new(task_id, task_queue.clone()));
                 // task_queue is wrapped into Arc, so clone only increases the reference count
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            watchdog::task_started(task_id);
+            cached_waker.last_poll = task.poll_with_waker(&cached_waker.waker);
+            watchdog::task_yielded();
+            match cached_waker.last_poll {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
@@ -61,18 +176,82 @@ impl Executor {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.task_queue.is_empty() && self.spawn_queue.is_empty() {
             enable_and_hlt();
         } else {
             interrupts::enable();
         }
     }
 
-    pub fn run(&mut self) -> ! {
+    /// Runs tasks as they become ready, sleeping while none are, until a
+    /// shutdown is requested through a Spawner. Then drops all remaining tasks
+    /// and returns.
+    pub fn run(&mut self) {
         loop {
+            if watchdog::is_enabled() {
+                // a task which hangs during this pass stops us, so the watchdog reports this instead
+                watchdog::record_snapshot(self.tasks.len(), self.task_queue.len(), &self.task_debug_info());
+            }
             self.run_ready_tasks();
+            if self.shutdown_requested.load(Ordering::Relaxed) {
+                break;
+            }
             self.sleep_if_idle();
         }
+        self.drop_all_tasks();
+    }
+
+    fn drop_all_tasks(&mut self) {
+        while self.task_queue.pop().is_ok() {}
+        while self.spawn_queue.pop().is_ok() {}
+        self.tasks.clear();
+        self.waker_cache.clear();
+    }
+
+    /// Returns the state of each live task, in order of TaskId.
+    pub fn task_debug_info(&self) -> Vec<TaskDebugInfo> {
+        self.tasks.keys().map(|&task_id| {
+            match self.waker_cache.get(&task_id) {
+                Some(cached_waker) => TaskDebugInfo {
+                    id: task_id,
+                    waker_registered: cached_waker.is_registered(),
+                    last_poll: Some(cached_waker.last_poll),
+                },
+                None => TaskDebugInfo {
+                    id: task_id,
+                    waker_registered: false,
+                    last_poll: None,
+                },
+            }
+        }).collect()
+    }
+
+    /// Prints the state of each live task. Must not be called from an
+    /// interrupt handler, since it prints directly.
+    pub fn debug_dump(&self) {
+        println!("executor: {} live tasks, {} queued", self.tasks.len(), self.task_queue.len());
+        for info in self.task_debug_info() {
+            println!("  {}", info);
+        }
+    }
+}
+
+impl CachedWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Self {
+        let task_waker = Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+        });
+        CachedWaker {
+            waker: Waker::from(task_waker.clone()),
+            task_waker,
+            last_poll: Poll::Pending,
+        }
+    }
+
+    fn is_registered(&self) -> bool {
+        // One reference is held here, and one by the cached waker itself
+        Arc::strong_count(&self.task_waker) > 2
     }
 }
 
@@ -82,13 +261,6 @@ struct TaskWaker {
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
-    }
-
     fn wake_task(&self) {
         self.task_queue.push(self.task_id).expect("task_queue full");
     }
@@ -103,3 +275,161 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+
+#[test_case]
+fn test_debug_info_shows_stuck_tasks() {
+    use futures_util::{future::poll_fn, task::AtomicWaker};
+
+    static WAKER: AtomicWaker = AtomicWaker::new();
+
+    let mut executor = Executor::new();
+    // Registers its waker but is never woken
+    executor.spawn(Task::new(poll_fn(|cx| {
+        WAKER.register(cx.waker());
+        Poll::<()>::Pending
+    })));
+    // Never registers its waker, so can never be woken
+    executor.spawn(Task::new(poll_fn(|_| Poll::<()>::Pending)));
+    executor.spawn(Task::new(async {}));
+    let before = executor.task_debug_info();
+    assert_eq!(before.len(), 3);
+    assert!(before.iter().all(|info| info.last_poll.is_none() && !info.waker_registered));
+
+    executor.run_ready_tasks();
+    let after = executor.task_debug_info();
+    assert_eq!(after.len(), 2);     // the completed task is gone
+    assert_eq!((after[0].waker_registered, after[0].last_poll), (true, Some(Poll::Pending)));
+    assert_eq!((after[1].waker_registered, after[1].last_poll), (false, Some(Poll::Pending)));
+    executor.debug_dump();
+
+    WAKER.take();   // dropping the registered waker leaves the task stuck
+    assert!(!executor.task_debug_info()[0].waker_registered);
+}
+
+#[test_case]
+fn test_spawner_spawns_from_running_task() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(async move {
+        for _ in 0..2 {
+            spawner.spawn(Task::new(async {
+                COMPLETED.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+        COMPLETED.fetch_add(1, Ordering::Relaxed);
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(COMPLETED.load(Ordering::Relaxed), 3);
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_shutdown_returns_from_run() {
+    use futures_util::future::pending;
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(pending()));   // would keep run() going forever
+    executor.spawn(Task::new(async move {
+        spawner.spawn(Task::new(pending()));
+        spawner.shutdown();
+    }));
+    executor.run();
+    assert!(executor.tasks.is_empty());
+    assert!(executor.waker_cache.is_empty());
+    assert!(executor.task_queue.is_empty() && executor.spawn_queue.is_empty());
+}
+
+#[test_case]
+fn test_executor_runs_trivial_task() {
+    use core::sync::atomic::AtomicUsize;
+
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        COMPLETED.fetch_add(1, Ordering::Relaxed);
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(COMPLETED.load(Ordering::Relaxed), 1);
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_join_handle_returns_output() {
+    use core::sync::atomic::AtomicU32;
+
+    static JOINED: AtomicU32 = AtomicU32::new(0);
+
+    let mut executor = Executor::new();
+    let (task, handle) = task_with_handle(async { 42u32 });
+    // spawned first, so it is already waiting on the handle when the task completes
+    executor.spawn(Task::new(async move {
+        JOINED.store(handle.await, Ordering::Relaxed);
+    }));
+    executor.spawn(task);
+    executor.run_ready_tasks();
+    assert_eq!(JOINED.load(Ordering::Relaxed), 42);
+    assert!(executor.tasks.is_empty());
+
+    let mut executor = Executor::new();
+    let handle = executor.spawn_with_handle(async { 7u32 });
+    executor.run_ready_tasks();     // completes before anything awaits the handle
+    executor.spawn(Task::new(async move {
+        JOINED.store(handle.await, Ordering::Relaxed);
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(JOINED.load(Ordering::Relaxed), 7);
+}
+
+#[test_case]
+fn test_cancel_drops_pending_task() {
+    use futures_util::future::pending;
+
+    let mut executor = Executor::new();
+    let task = Task::new(pending());
+    let task_id = task.id();
+    executor.spawn(task);
+    executor.run_ready_tasks();
+    assert_eq!(executor.tasks.len(), 1);    // never completes on its own
+    assert!(executor.cancel(task_id));
+    assert!(executor.tasks.is_empty());
+    assert!(executor.waker_cache.is_empty());
+    assert!(!executor.cancel(task_id));     // already gone
+
+    // a task spawned through a Spawner is cancelled before it is ever polled
+    let task = Task::new(async { panic!("cancelled task was polled") });
+    let task_id = task.id();
+    executor.spawner().spawn(task);
+    assert!(executor.cancel(task_id));
+    executor.run_ready_tasks();
+    assert!(executor.tasks.is_empty());
+}
+
+#[test_case]
+fn test_yield_now_interleaves_tasks() {
+    use super::yield_now;
+    use alloc::vec;
+    use spin::Mutex;
+
+    static ORDER: Mutex<Vec<(char, usize)>> = Mutex::new(Vec::new());
+
+    async fn count(name: char) {
+        for i in 0..3 {
+            ORDER.lock().push((name, i));
+            yield_now().await;
+        }
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(count('a')));
+    executor.spawn(Task::new(count('b')));
+    executor.run_ready_tasks();
+    assert!(executor.tasks.is_empty());
+    assert_eq!(*ORDER.lock(), vec![('a', 0), ('b', 0), ('a', 1), ('b', 1), ('a', 2), ('b', 2)]);
+}
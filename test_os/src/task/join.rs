@@ -0,0 +1,56 @@
+use super::Task;
+use alloc::sync::Arc;
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
+use spin::Mutex;
+
+/// The slot shared between a spawned task and its JoinHandle. Only locked
+/// from tasks, never from interrupt handlers, so a spin lock cannot deadlock.
+struct JoinState<T> {
+    output: Option<T>,
+    waker: Option<Waker>,   // the waker of the task awaiting the handle, if any
+}
+
+/// Awaits the output of a task spawned with spawn_with_handle.
+///
+/// Dropping the handle detaches the task, which still runs to completion.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+/// Wraps the future in a Task which stores its output for the returned
+/// JoinHandle, and wakes the task awaiting the handle once it does.
+pub(crate) fn task_with_handle<T, F>(future: F) -> (Task, JoinHandle<T>)
+where
+    T: 'static,
+    F: Future<Output = T> + 'static,
+{
+    let state = Arc::new(Mutex::new(JoinState { output: None, waker: None }));
+    let task_state = state.clone();
+    let task = Task::new(async move {
+        let output = future.await;
+        let waker = {
+            let mut state = task_state.lock();
+            state.output = Some(output);
+            state.waker.take()
+        };  // release the lock before waking
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+    (task, JoinHandle { state })
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut state = self.state.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use crate::{print, println};
-use core::{pin::Pin, task::{Poll, Context}};
+use super::timer::sleep;
+use core::{pin::Pin, task::{Poll, Context}, sync::atomic::{AtomicU64, Ordering}};
 use futures_util::{stream::{Stream, StreamExt}, task::AtomicWaker};
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use spin::Mutex;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 // using OnceCell instead of lazy_static ensures that initialization does not
@@ -12,19 +14,90 @@ static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
 static WAKER: AtomicWaker = AtomicWaker::new();
 
+static DROPPED_SCANCODES: AtomicU64 = AtomicU64::new(0);
+// counted rather than printed, since printing from the interrupt handler would
+// lock the VGA writer, which deadlocks if the interrupted code holds that lock
+
 /// Called by the keyboard interrupt handler
 ///
 /// Must not block or allocate.
 pub(crate) fn add_scancode(scancode: u8) {  // pub(crate) makes available to lib.rs
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {   // gets reference to queue
         if let Err(_) = queue.push(scancode) {  // performs synchronization and pushes
-            println!("WARNING: scancode queue full; dropping keyboard input");
+            DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);  // queue full
         } else {
             WAKER.wake();   // if a waker is registered, notify the executor; else, no-op
             // this occurs after the scancode has been pushed, so we don't wake with an empty queue
         }
     } else {
-        println!("WARNING: scancode queue uninitialized");
+        DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);  // queue uninitialized
+    }
+}
+
+/// Returns the number of scancodes dropped since the last call, and resets
+/// the count.
+pub fn take_dropped_scancodes() -> u64 {
+    DROPPED_SCANCODES.swap(0, Ordering::Relaxed)
+}
+
+/// The keyboard layouts which scancodes can be decoded with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104Key,
+    Dvorak104Key,
+    Uk105Key,
+}
+
+static LAYOUT: Mutex<Layout> = Mutex::new(Layout::Us104Key);
+// only locked by tasks, never by the interrupt handler
+
+/// Selects the layout which KeyStreams decode keys with from their next key.
+pub fn set_layout(layout: Layout) {
+    *LAYOUT.lock() = layout;
+}
+
+/// Returns the currently selected layout.
+pub fn layout() -> Layout {
+    *LAYOUT.lock()
+}
+
+/// A scancode decoder for one layout, since pc_keyboard fixes the layout of
+/// a Keyboard in its type.
+pub(crate) enum Decoder {
+    Us104Key(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Dvorak104Key(Keyboard<layouts::Dvorak104Key, ScancodeSet1>),
+    Uk105Key(Keyboard<layouts::Uk105Key, ScancodeSet1>),
+}
+
+impl Decoder {
+    pub(crate) fn new(layout: Layout) -> Self {
+        match layout {
+            Layout::Us104Key => Decoder::Us104Key(
+                Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)),
+            Layout::Dvorak104Key => Decoder::Dvorak104Key(
+                Keyboard::new(layouts::Dvorak104Key, ScancodeSet1, HandleControl::Ignore)),
+            Layout::Uk105Key => Decoder::Uk105Key(
+                Keyboard::new(layouts::Uk105Key, ScancodeSet1, HandleControl::Ignore)),
+        }
+    }
+
+    /// Returns the key completed by the scancode, if any. Some keys take
+    /// several scancodes, and some scancodes (e.g. releases) decode to no key.
+    pub(crate) fn decode(&mut self, scancode: u8) -> Option<DecodedKey> {
+        match self {
+            Decoder::Us104Key(keyboard) => match keyboard.add_byte(scancode) {
+                Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+                _ => None,
+            },
+            Decoder::Dvorak104Key(keyboard) => match keyboard.add_byte(scancode) {
+                Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+                _ => None,
+            },
+            Decoder::Uk105Key(keyboard) => match keyboard.add_byte(scancode) {
+                Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+                _ => None,
+            },
+        }
     }
 }
 
@@ -62,19 +135,101 @@ impl Stream for ScancodeStream {
     }
 }
 
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
-
-    while let Some(scancode) = scancodes.next().await { // next() method from StreamExt trait
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+/// A stream of the keys decoded from the scancodes received by the keyboard
+/// interrupt handler.
+pub struct KeyStream {
+    scancodes: ScancodeStream,
+    layout: Layout,
+    decoder: Decoder,
+}
+
+impl KeyStream {
+    /// Takes over the scancode queue, so may only be called once, and not
+    /// together with ScancodeStream::new.
+    pub fn new() -> Self {
+        let layout = layout();
+        KeyStream {
+            scancodes: ScancodeStream::new(),
+            layout,
+            decoder: Decoder::new(layout),
+        }
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        let Self { scancodes, layout: current_layout, decoder } = &mut *self;
+        let selected_layout = layout();
+        if *current_layout != selected_layout {
+            *current_layout = selected_layout;
+            *decoder = Decoder::new(selected_layout);
+        }
+        while let Poll::Ready(Some(scancode)) = Pin::new(&mut *scancodes).poll_next(cx) {
+            if let Some(key) = decoder.decode(scancode) {
+                return Poll::Ready(Some(key));
             }
         }
-        // since poll_next (called by scancodes.next()) never returns None, this is an endless loop
+        Poll::Pending   // the scancode stream never ends, and registered the waker
+    }
+}
+
+/// How often report_dropped_scancodes checks for drops, about once a second.
+const DROP_REPORT_TICKS: u64 = 18;
+
+/// Reports scancodes dropped by the interrupt handler, whether or not any
+/// task is reading keys.
+pub async fn report_dropped_scancodes() {
+    loop {
+        sleep(DROP_REPORT_TICKS).await;
+        let dropped = take_dropped_scancodes();
+        if dropped > 0 {
+            println!("WARNING: dropped {} scancodes", dropped);  // safe here, outside of interrupt context
+        }
     }
 }
+
+pub async fn print_keypresses() {
+    let mut keys = KeyStream::new();
+
+    while let Some(key) = keys.next().await {   // next() method from StreamExt trait
+        match key {
+            DecodedKey::Unicode(character) => print!("{}", character),
+            DecodedKey::RawKey(key) => print!("{:?}", key),
+        }
+        // since poll_next (called by keys.next()) never returns None, this is an endless loop
+    }
+}
+
+#[test_case]
+fn test_full_scancode_queue_does_not_lock_writer() {
+    use crate::vga_buffer::WRITER;
+    use x86_64::instructions::interrupts;
+
+    let _ = SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(128)); // may already be initialized
+    let queue = SCANCODE_QUEUE.try_get().unwrap();
+    while queue.pop().is_ok() {}
+    take_dropped_scancodes();
+
+    interrupts::without_interrupts(|| {
+        let _writer = WRITER.lock();    // add_scancode would spin forever if it tried to print
+        for scancode in 0..(queue.capacity() + 5) {
+            add_scancode(scancode as u8);
+        }
+    });
+    assert_eq!(take_dropped_scancodes(), 5);
+    assert_eq!(take_dropped_scancodes(), 0);
+    while queue.pop().is_ok() {}
+}
+
+#[test_case]
+fn test_dvorak_layout_decodes_scancode() {
+    set_layout(Layout::Dvorak104Key);
+    let mut decoder = Decoder::new(layout());
+    set_layout(Layout::Us104Key);
+    // 0x1f is the key labelled S on a US keyboard, which is O in Dvorak
+    assert_eq!(decoder.decode(0x1f), Some(DecodedKey::Unicode('o')));
+    assert_eq!(decoder.decode(0x9f), None);    // its release
+    assert_eq!(Decoder::new(layout()).decode(0x1f), Some(DecodedKey::Unicode('s')));
+}
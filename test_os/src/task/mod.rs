@@ -1,9 +1,17 @@
-use core::{future::Future, pin::Pin, task::{Context, Poll}, sync::atomic::{AtomicU64, Ordering}};
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}, sync::atomic::{AtomicU64, Ordering}};
 use alloc::boxed::Box;
 
 pub mod simple_executor;
 pub mod keyboard;
+pub mod serial;
+pub mod readline;
 pub mod executor;
+pub mod watchdog;
+pub mod timer;
+pub mod join;
+pub mod channel;
+pub mod thumbnail;
+pub mod shell;
 
 pub struct Task {   // newtype wrapper around a pinned, heap allocated, dynamically dispatched future
     id: TaskId,
@@ -21,15 +29,28 @@ impl Task {
         }
     }
 
+    /// The ID by which the executor knows this task, e.g. to cancel it.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
         // use Pin::as_mut to convert self.future from type Pin<Box<T>> to type Pin<&mut T>
         // then call poll on the converted self.future field and return the result
     }
+
+    /// Polls the task once with a context built from the given waker. Both
+    /// executors poll tasks only through this, so changes to how tasks are
+    /// polled apply to both.
+    fn poll_with_waker(&mut self, waker: &Waker) -> Poll<()> {
+        let mut context = Context::from_waker(waker);
+        self.poll(&mut context)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
@@ -38,3 +59,28 @@ impl TaskId {
         // relaxed ordering since it only matters that each ID is unique
     }
 }
+
+/// A future which is pending exactly once, waking itself first.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+/// Returns a future which gives the executor a chance to poll other ready
+/// tasks before the awaiting task continues, so a long running task can avoid
+/// starving the others.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();   // requeues the task behind those already ready
+        Poll::Pending
+    }
+}
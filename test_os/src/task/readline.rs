@@ -0,0 +1,65 @@
+use alloc::{string::String, vec::Vec};
+
+/// Accumulates input bytes until a full line has been received.
+///
+/// Shared by the line-oriented readers (serial commands, keyboard input) so
+/// that they all treat newlines and erasure the same way.
+pub struct LineBuffer {
+    bytes: Vec<u8>,
+}
+
+impl LineBuffer {
+    pub fn new() -> Self {
+        LineBuffer {
+            bytes: Vec::new(),
+        }
+    }
+
+    /// Returns true if no bytes of the current line remain, e.g. so that an
+    /// echoed backspace does not erase the prompt.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Adds a byte to the line being accumulated.
+    ///
+    /// Returns the completed line (without its terminator) once a newline or
+    /// carriage return is received. Backspace and delete erase the previous
+    /// byte. Non-UTF-8 input is replaced rather than rejected.
+    pub fn push(&mut self, byte: u8) -> Option<String> {
+        match byte {
+            b'\n' | b'\r' => {
+                let line = String::from_utf8_lossy(&self.bytes).into_owned();
+                self.bytes.clear();
+                Some(line)
+            }
+            0x08 | 0x7f => {
+                self.bytes.pop();
+                None
+            }
+            byte => {
+                self.bytes.push(byte);
+                None
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_line_buffer_completes_on_newline() {
+    let mut line = LineBuffer::new();
+    for byte in b"shutdown" {
+        assert_eq!(line.push(*byte), None);
+    }
+    assert_eq!(line.push(b'\n').as_deref(), Some("shutdown"));
+    assert_eq!(line.push(b'\r').as_deref(), Some(""));   // buffer is reset after each line
+}
+
+#[test_case]
+fn test_line_buffer_backspace() {
+    let mut line = LineBuffer::new();
+    for byte in b"echo\x08\x08\x08\x08\x08ok" {
+        line.push(*byte);
+    }
+    assert_eq!(line.push(b'\n').as_deref(), Some("ok"));
+}
@@ -0,0 +1,338 @@
+use conquer_once::{spin::OnceCell, TryInitError};
+use crossbeam_queue::ArrayQueue;
+use crate::{serial_println, hlt_loop, exit_qemu, interrupts, QemuExitCode};
+use super::{readline::LineBuffer, timer::sleep};
+use alloc::string::String;
+use core::{future::Future, pin::Pin, task::{Poll, Context}, sync::atomic::{AtomicU64, Ordering}};
+use futures_util::{future::poll_fn, stream::Stream, task::AtomicWaker};
+
+/// The bytes received on one serial port, which its interrupt handler queues
+/// until a task reads them.
+pub struct ReceiveQueue {
+    queue: OnceCell<ArrayQueue<u8>>,
+    // as with the scancode queue, OnceCell ensures that the queue is allocated
+    // before the interrupt handler runs, never inside of it
+    waker: AtomicWaker,
+    dropped_bytes: AtomicU64,
+    // as with dropped scancodes, counted rather than printed from interrupt context
+}
+
+/// Bytes received on the first serial port, which carry PNGs to thumbnail.
+pub static COM1_QUEUE: ReceiveQueue = ReceiveQueue::new();
+
+/// Bytes received on the second serial port, which carry host commands.
+pub static COM2_QUEUE: ReceiveQueue = ReceiveQueue::new();
+
+/// Room for a few lines of commands.
+const COMMAND_QUEUE_CAPACITY: usize = 256;
+
+impl ReceiveQueue {
+    pub const fn new() -> Self {
+        ReceiveQueue {
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            dropped_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Allocates room for capacity bytes. Must be called outside of interrupt
+    /// context, before bytes arrive. Fails if the queue is already allocated.
+    pub fn try_init(&self, capacity: usize) -> Result<(), TryInitError> {
+        self.queue.try_init_once(|| ArrayQueue::new(capacity))
+    }
+
+    /// Called by the serial interrupt handler
+    ///
+    /// Must not block or allocate. Public so that integration tests can
+    /// simulate bytes arriving from the host.
+    pub fn add_byte(&self, byte: u8) {
+        if let Ok(queue) = self.queue.try_get() {
+            if queue.push(byte).is_err() {
+                self.dropped_bytes.fetch_add(1, Ordering::Relaxed);     // queue full
+            } else {
+                self.waker.wake();  // notify the executor only after the byte is queued
+            }
+        } else {
+            self.dropped_bytes.fetch_add(1, Ordering::Relaxed);     // queue uninitialized
+        }
+    }
+
+    /// Returns the number of received bytes dropped since the last call, and
+    /// resets the count.
+    pub fn take_dropped_bytes(&self) -> u64 {
+        self.dropped_bytes.swap(0, Ordering::Relaxed)
+    }
+
+    /// Removes the oldest byte received, if any.
+    pub fn pop_byte(&self) -> Option<u8> {
+        let queue = self.queue.try_get().expect("serial queue not initialized");
+        queue.pop().ok()
+    }
+
+    /// Removes the oldest byte received, or registers the task to be woken
+    /// once one arrives. Only one task should read from the queue at a time,
+    /// since there is a single waker.
+    pub fn poll_byte(&self, cx: &mut Context) -> Poll<u8> {
+        let queue = self.queue.try_get().expect("serial queue not initialized");
+
+        if let Ok(byte) = queue.pop() {
+            return Poll::Ready(byte);   // avoid registering a waker when a byte is already waiting
+        }
+
+        self.waker.register(&cx.waker());
+        match queue.pop() {
+            Ok(byte) => {
+                self.waker.take();
+                Poll::Ready(byte)
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+/// Initializes the queue which buffers bytes received on the second serial
+/// port. Must be called outside of interrupt context, before bytes arrive.
+pub fn init_queue() {
+    COM2_QUEUE.try_init(COMMAND_QUEUE_CAPACITY)
+        .expect("serial::init_queue should only be called once");
+}
+
+/// Called by the COM2 serial interrupt handler
+///
+/// Must not block or allocate. Public so that integration tests can simulate
+/// bytes arriving from the host.
+pub fn add_byte(byte: u8) {
+    COM2_QUEUE.add_byte(byte);
+}
+
+/// Returns the number of bytes received on the second serial port which were
+/// dropped since the last call, and resets the count.
+pub fn take_dropped_bytes() -> u64 {
+    COM2_QUEUE.take_dropped_bytes()
+}
+
+/// Removes the oldest byte received on the second serial port, if any.
+pub fn pop_byte() -> Option<u8> {
+    COM2_QUEUE.pop_byte()
+}
+
+/// A stream of the bytes received on a serial port, which never ends.
+///
+/// Streams share the queue and its single waker with any other reader, such
+/// as read_line, so only one task should read from a port at a time.
+pub struct SerialStream {
+    queue: &'static ReceiveQueue,
+}
+
+impl SerialStream {
+    /// Requires the queue to be initialized.
+    pub fn new(queue: &'static ReceiveQueue) -> Self {
+        SerialStream { queue }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        self.queue.poll_byte(cx).map(Some)
+    }
+}
+
+/// Waits for the host to send a full line over the second serial port.
+///
+/// Returns the line without its terminating newline.
+pub async fn read_line() -> String {
+    let mut line = LineBuffer::new();
+    loop {
+        let byte = poll_fn(|cx| COM2_QUEUE.poll_byte(cx)).await;
+        if let Some(text) = line.push(byte) {
+            return text;
+        }
+    }
+}
+
+/// Waits for the host to send a full line over the second serial port, or
+/// returns None if the deadline tick passes before the line has begun.
+///
+/// A line which has already begun is always read to its end.
+async fn read_line_before(deadline: u64) -> Option<String> {
+    let mut line = LineBuffer::new();
+    let mut timeout = sleep(deadline.saturating_sub(interrupts::ticks()));
+    loop {
+        let byte = poll_fn(|cx| {
+            if let Poll::Ready(byte) = COM2_QUEUE.poll_byte(cx) {
+                return Poll::Ready(Some(byte));     // a queued byte wins even once the deadline has passed
+            }
+            if line.is_empty() {
+                Pin::new(&mut timeout).poll(cx).map(|()| None)
+            } else {
+                Poll::Pending
+            }
+        }).await?;
+        if let Some(text) = line.push(byte) {
+            return Some(text);
+        }
+    }
+}
+
+/// Input passed to the application when the host's run command omits one,
+/// or when the host sends no run command at all.
+pub const DEFAULT_APPLICATION_INPUT: u32 = 42;
+
+static RUN_TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(interrupts::ms_to_ticks(10_000));
+
+/// Sets how many timer ticks handle_commands waits for a run command before
+/// running the application with DEFAULT_APPLICATION_INPUT.
+pub fn set_run_timeout(timeout_ticks: u64) {
+    RUN_TIMEOUT_TICKS.store(timeout_ticks, Ordering::Relaxed);
+}
+
+/// A text command sent by the host.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Thumbnail(usize, usize),    // maximum width and height of subsequent thumbnails
+    Run(u32),                   // input to the application
+    Shutdown,
+    Unknown(String),
+}
+
+pub fn parse_command(line: &str) -> Command {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("thumbnail") => {
+            let width = words.next().and_then(|w| w.parse().ok());
+            let height = words.next().and_then(|h| h.parse().ok());
+            match (width, height, words.next()) {
+                (Some(width), Some(height), None) => Command::Thumbnail(width, height),
+                _ => Command::Unknown(String::from(line)),
+            }
+        }
+        Some("run") => {
+            match (words.next(), words.next()) {
+                (None, _) => Command::Run(DEFAULT_APPLICATION_INPUT),
+                (Some(input), None) => match input.parse() {
+                    Ok(input) => Command::Run(input),
+                    Err(_) => Command::Unknown(String::from(line)),
+                },
+                _ => Command::Unknown(String::from(line)),
+            }
+        }
+        Some("shutdown") if words.next().is_none() => Command::Shutdown,
+        _ => Command::Unknown(String::from(line)),
+    }
+}
+
+/// Reads commands from the second serial port and carries them out, passing
+/// the input of each run command to `run_application`.
+///
+/// If no run command arrives within the run timeout, the application is run
+/// once with DEFAULT_APPLICATION_INPUT instead.
+pub async fn handle_commands<F, Fut>(run_application: F)
+where
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut run_deadline = Some(interrupts::ticks() + RUN_TIMEOUT_TICKS.load(Ordering::Relaxed));
+    loop {
+        let line = match run_deadline {
+            Some(deadline) => match read_line_before(deadline).await {
+                Some(line) => line,
+                None => {
+                    serial_println!("No run command received, using input {}", DEFAULT_APPLICATION_INPUT);
+                    run_deadline = None;
+                    run_application(DEFAULT_APPLICATION_INPUT).await;
+                    continue;
+                }
+            },
+            None => read_line().await,
+        };
+        let dropped = take_dropped_bytes();
+        if dropped > 0 {
+            serial_println!("WARNING: dropped {} bytes of serial input", dropped);
+        }
+        match parse_command(&line) {
+            Command::Thumbnail(width, height) => {
+                super::thumbnail::set_thumbnail_size(width, height);
+                serial_println!("Thumbnails will be at most {}x{}", width, height);
+            }
+            Command::Run(input) => {
+                run_deadline = None;
+                run_application(input).await;
+            }
+            Command::Shutdown => {
+                serial_println!("Shutting down");
+                exit_qemu(QemuExitCode::Success);
+                hlt_loop();
+            }
+            Command::Unknown(text) => {
+                serial_println!("Unknown command: {:?}", text);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_parse_command() {
+    assert_eq!(parse_command("thumbnail 64 64"), Command::Thumbnail(64, 64));
+    assert_eq!(parse_command(" shutdown "), Command::Shutdown);
+    assert_eq!(parse_command("thumbnail 64"), Command::Unknown(String::from("thumbnail 64")));
+    assert_eq!(parse_command("reboot"), Command::Unknown(String::from("reboot")));
+    assert_eq!(parse_command("run 7"), Command::Run(7));
+    assert_eq!(parse_command("run"), Command::Run(DEFAULT_APPLICATION_INPUT));
+    assert_eq!(parse_command("run -1"), Command::Unknown(String::from("run -1")));
+    assert_eq!(parse_command("run 7 8"), Command::Unknown(String::from("run 7 8")));
+}
+
+#[test_case]
+fn test_read_line_before_passed_deadline() {
+    use futures_util::task::noop_waker;
+
+    let _ = COM2_QUEUE.try_init(COMMAND_QUEUE_CAPACITY);    // may already be initialized
+    while pop_byte().is_some() {}
+
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut read = core::pin::pin!(read_line_before(0));
+    assert_eq!(read.as_mut().poll(&mut context), Poll::Ready(None));
+
+    for byte in b"run 7\n" {
+        add_byte(*byte);
+    }
+    let mut read = core::pin::pin!(read_line_before(0));
+    assert_eq!(read.as_mut().poll(&mut context), Poll::Ready(Some(String::from("run 7"))));
+}
+
+#[test_case]
+fn test_serial2_queue_push_and_drain() {
+    let _ = COM2_QUEUE.try_init(COMMAND_QUEUE_CAPACITY);    // may already be initialized
+    while pop_byte().is_some() {}   // start from an empty queue
+
+    for byte in b"COM2" {
+        add_byte(*byte);
+    }
+    for byte in b"COM2" {
+        assert_eq!(pop_byte(), Some(*byte));
+    }
+    assert_eq!(pop_byte(), None);
+}
+
+#[test_case]
+fn test_serial_stream_yields_queued_bytes() {
+    use futures_util::task::noop_waker;
+
+    let _ = COM2_QUEUE.try_init(COMMAND_QUEUE_CAPACITY);    // may already be initialized
+    while pop_byte().is_some() {}
+
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut stream = SerialStream::new(&COM2_QUEUE);
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Pending);
+    for byte in b"ok" {
+        add_byte(*byte);
+    }
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Ready(Some(b'o')));
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Ready(Some(b'k')));
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Pending);
+    COM2_QUEUE.waker.take();    // drop the registered noop waker
+}
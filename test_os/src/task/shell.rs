@@ -0,0 +1,157 @@
+/* Interactive shell
+ *
+ * Reads lines typed on the keyboard, echoing them to the VGA text buffer, and
+ * carries out a handful of built-in commands. The shell reads from any stream
+ * of decoded keys, so tests can drive it with canned scancodes instead of the
+ * keyboard interrupt handler.
+ */
+
+use crate::{print, println, interrupts, vga_buffer};
+use super::{keyboard::KeyStream, readline::LineBuffer};
+use alloc::string::String;
+use futures_util::stream::{Stream, StreamExt};
+use pc_keyboard::DecodedKey;
+
+const PROMPT: &str = "> ";
+
+/// A command typed into the shell.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShellCommand {
+    Clear,
+    Uptime,
+    Echo(String),   // the text after the command name
+    Help,
+    Empty,
+    Unknown(String),    // the command name
+}
+
+pub fn parse_shell_command(line: &str) -> ShellCommand {
+    let line = line.trim();
+    let (name, rest) = match line.find(' ') {
+        Some(index) => (&line[..index], line[index..].trim_start()),
+        None => (line, ""),
+    };
+    match name {
+        "" => ShellCommand::Empty,
+        "echo" => ShellCommand::Echo(String::from(rest)),
+        "clear" if rest.is_empty() => ShellCommand::Clear,
+        "uptime" if rest.is_empty() => ShellCommand::Uptime,
+        "help" if rest.is_empty() => ShellCommand::Help,
+        _ => ShellCommand::Unknown(String::from(name)),
+    }
+}
+
+/// Waits for a full line of keys, echoing each as it is typed. Returns None
+/// if the stream ends before the line does.
+async fn read_line<S>(keys: &mut S) -> Option<String>
+where
+    S: Stream<Item = DecodedKey> + Unpin,
+{
+    let mut line = LineBuffer::new();
+    while let Some(key) = keys.next().await {
+        let character = match key {
+            DecodedKey::Unicode(character) => character,
+            DecodedKey::RawKey(_) => continue,  // e.g. arrow keys, which the shell does not use
+        };
+        match character {
+            '\u{8}' | '\u{7f}' if line.is_empty() => {}   // nothing to erase, so leave the prompt alone
+            '\u{8}' | '\u{7f}' => print!("\u{8}"),   // the writer erases the previous character
+            character => print!("{}", character),
+        }
+        let mut bytes = [0; 4];
+        for &byte in character.encode_utf8(&mut bytes).as_bytes() {
+            if let Some(text) = line.push(byte) {
+                return Some(text);
+            }
+        }
+    }
+    None
+}
+
+fn execute(command: ShellCommand) {
+    match command {
+        ShellCommand::Clear => vga_buffer::clear_screen(),
+        ShellCommand::Uptime => {
+            let ms = interrupts::uptime_ms();
+            println!("up {}.{:03} s", ms / 1000, ms % 1000);
+        }
+        ShellCommand::Echo(text) => println!("{}", text),
+        ShellCommand::Help => {
+            println!("clear         clear the screen");
+            println!("uptime        time since boot");
+            println!("echo <text>   print the text");
+            println!("help          list the commands");
+        }
+        ShellCommand::Empty => {}
+        ShellCommand::Unknown(name) => println!("unknown command: {}", name),
+    }
+}
+
+/// Prompts for commands and carries them out, until the key stream ends.
+async fn run_shell_on<S>(mut keys: S)
+where
+    S: Stream<Item = DecodedKey> + Unpin,
+{
+    loop {
+        print!("{}", PROMPT);
+        match read_line(&mut keys).await {
+            Some(line) => execute(parse_shell_command(&line)),
+            None => return,
+        }
+    }
+}
+
+/// Runs the shell on keys from the keyboard. Takes over the scancode queue,
+/// so cannot run together with keyboard::print_keypresses.
+pub async fn run_shell() {
+    run_shell_on(KeyStream::new()).await
+}
+
+#[test_case]
+fn test_parse_shell_command() {
+    assert_eq!(parse_shell_command("clear"), ShellCommand::Clear);
+    assert_eq!(parse_shell_command(" uptime "), ShellCommand::Uptime);
+    assert_eq!(parse_shell_command("echo hello  world"), ShellCommand::Echo(String::from("hello  world")));
+    assert_eq!(parse_shell_command("echo"), ShellCommand::Echo(String::new()));
+    assert_eq!(parse_shell_command(""), ShellCommand::Empty);
+    assert_eq!(parse_shell_command("help me"), ShellCommand::Unknown(String::from("help")));
+    assert_eq!(parse_shell_command("reboot"), ShellCommand::Unknown(String::from("reboot")));
+}
+
+#[test_case]
+fn test_shell_runs_typed_commands() {
+    use super::{Task, keyboard::{Decoder, Layout}, simple_executor::SimpleExecutor};
+    use crate::vga_buffer::{WRITER, BUFFER_HEIGHT};
+    use alloc::vec::Vec;
+    use futures_util::stream;
+
+    // set 1 scancodes of the keys pressed, each followed by its release
+    const TYPED: &[u8] = &[
+        0x2e, 0x26, 0x12, 0x1e, 0x13, 0x1c,                 // clear, enter
+        0x12, 0x2e, 0x23, 0x18, 0x39, 0x23, 0x17, 0x1c,     // echo hi, enter
+        0x30, 0x18, 0x22, 0x16, 0x1f, 0x0e, 0x1f, 0x1c,     // bogus, a backspace, s, enter
+    ];
+    let mut decoder = Decoder::new(Layout::Us104Key);
+    let keys: Vec<DecodedKey> = TYPED.iter()
+        .flat_map(|&scancode| [scancode, scancode | 0x80])
+        .filter_map(|scancode| decoder.decode(scancode))
+        .collect();
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(run_shell_on(stream::iter(keys))));
+    executor.run();     // returns once the shell reaches the end of the keys
+
+    let writer = WRITER.lock();
+    // true if the row holds exactly the text, followed by blanks
+    let shows = |row: usize, text: &[u8]| {
+        text.iter().enumerate().all(|(col, &c)| writer.char_at(row, col) == c)
+            && writer.char_at(row, text.len()) == b' '
+    };
+    // the clear command blanked everything above the later commands
+    assert!(shows(BUFFER_HEIGHT - 6, b""));
+    assert!(shows(BUFFER_HEIGHT - 5, b"> echo hi"));
+    assert!(shows(BUFFER_HEIGHT - 4, b"hi"));
+    assert!(shows(BUFFER_HEIGHT - 3, b"> bogus"));
+    assert!(shows(BUFFER_HEIGHT - 2, b"unknown command: bogus"));
+    assert!(shows(BUFFER_HEIGHT - 1, b">"));
+}
@@ -1,6 +1,6 @@
 use super::Task;
 use alloc::collections::VecDeque;
-use core::task::{Waker, RawWaker, RawWakerVTable, Context, Poll};
+use core::task::{Waker, RawWaker, RawWakerVTable, Poll};
 
 pub struct SimpleExecutor {
     task_queue: VecDeque<Task>,
@@ -20,8 +20,7 @@ impl SimpleExecutor {
     pub fn run(&mut self) {
         while let Some(mut task) = self.task_queue.pop_front() {
             let waker = dummy_waker();
-            let mut context = Context::from_waker(&waker);
-            match task.poll(&mut context) {
+            match task.poll_with_waker(&waker) {
                 Poll::Ready(()) => {}  
                 Poll::Pending => self.task_queue.push_back(task),
             }
@@ -42,3 +41,19 @@ fn dummy_raw_waker() -> RawWaker {
 fn dummy_waker() -> Waker {
     unsafe { Waker::from_raw(dummy_raw_waker()) }
 }
+
+
+#[test_case]
+fn test_simple_executor_runs_trivial_task() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        COMPLETED.fetch_add(1, Ordering::Relaxed);
+    }));
+    executor.run();
+    assert_eq!(COMPLETED.load(Ordering::Relaxed), 1);
+    assert!(executor.task_queue.is_empty());
+}
@@ -0,0 +1,242 @@
+/* Serving thumbnails over serial
+ *
+ * The host sends a PNG over the first serial port, and receives a thumbnail
+ * of it in return. The COM1 interrupt handler only queues the received bytes,
+ * as with scancodes, and the serve_thumbnails task reassembles the PNG and
+ * does the decoding, resampling, and encoding. This keeps the large
+ * allocations and long computation out of interrupt context.
+ */
+
+use crate::{allocator::HeapConfig, interrupts, log, png, serial::{SERIAL1, RawSender}, serial_println, exit_qemu, QemuExitCode};
+use alloc::vec::Vec;
+use super::{serial::{ReceiveQueue, COM1_QUEUE}, timer::sleep};
+use core::{future::Future, pin::Pin, task::Poll, sync::atomic::{AtomicU64, AtomicUsize, Ordering}};
+use futures_util::future::poll_fn;
+
+/// Room for the bytes arriving while the task waits to be polled. A byte
+/// which arrives to a full queue is dropped, which abandons the transfer.
+const PNG_QUEUE_CAPACITY: usize = 4096;
+
+/// Initializes the queue which buffers bytes received on the first serial
+/// port. Must be called outside of interrupt context, before bytes arrive.
+pub fn init_queue() {
+    COM1_QUEUE.try_init(PNG_QUEUE_CAPACITY)
+        .expect("thumbnail::init_queue should only be called once");
+}
+
+/// Returns the byte, or None if the queue has dropped any bytes, since the
+/// PNG they belonged to could then only fail its CRC check later.
+fn check_dropped(queue: &ReceiveQueue, byte: u8) -> Option<u8> {
+    let dropped = queue.take_dropped_bytes();
+    if dropped > 0 {
+        serial_println!("WARNING: dropped {} bytes of PNG input, abandoning the transfer", dropped);
+        return None;
+    }
+    Some(byte)
+}
+
+/// Waits as long as it takes for the next byte.
+async fn receive(queue: &ReceiveQueue) -> Option<u8> {
+    let byte = poll_fn(|cx| queue.poll_byte(cx)).await;
+    check_dropped(queue, byte)
+}
+
+/// Waits for the next byte, or returns None if none arrives within the given
+/// number of timer ticks, so that a stalled transfer is abandoned.
+async fn receive_with_timeout(queue: &ReceiveQueue, timeout_ticks: u64) -> Option<u8> {
+    let mut timeout = sleep(timeout_ticks);
+    let byte = poll_fn(|cx| {
+        if let Poll::Ready(byte) = queue.poll_byte(cx) {
+            return Poll::Ready(Some(byte));     // a queued byte wins even once the timeout has passed
+        }
+        Pin::new(&mut timeout).poll(cx).map(|()| None)
+    }).await;
+    match byte {
+        Some(byte) => check_dropped(queue, byte),
+        None => {
+            log!("Timed out waiting for PNG data");
+            None
+        }
+    }
+}
+
+static READ_TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(interrupts::ms_to_ticks(2_000));
+
+/// Sets how many timer ticks to wait for each byte once a PNG has started
+/// arriving, before abandoning it as stalled.
+pub fn set_read_timeout(timeout_ticks: u64) {
+    READ_TIMEOUT_TICKS.store(timeout_ticks, Ordering::Relaxed);
+}
+
+static THUMBNAIL_MAX_WIDTH: AtomicUsize = AtomicUsize::new(150);
+static THUMBNAIL_MAX_HEIGHT: AtomicUsize = AtomicUsize::new(150);
+
+/// Sets the maximum dimensions of thumbnails generated from serial PNGs.
+pub fn set_thumbnail_size(max_width: usize, max_height: usize) {
+    THUMBNAIL_MAX_WIDTH.store(max_width, Ordering::Relaxed);
+    THUMBNAIL_MAX_HEIGHT.store(max_height, Ordering::Relaxed);
+}
+
+/// Largest PNG accepted over serial. Generating a thumbnail holds several
+/// copies of the image data at once, so this leaves room for them in the heap.
+/// Assumes the default heap if it is not yet initialized.
+fn max_serial_png_size() -> usize {
+    let heap = crate::allocator::heap_config().unwrap_or(HeapConfig::DEFAULT);
+    heap.size / 4
+}
+
+/// Reads a PNG from the queue, waiting as long as it takes for the first byte
+/// to arrive, but at most timeout_ticks for each byte after that.
+async fn read_serial_png(queue: &ReceiveQueue, timeout_ticks: u64) -> Option<Vec<u8>> {
+    let mut raw_data: Vec<u8> = Vec::new();
+    // Verify that first 8 bytes match the png signature
+    for i in 0..8 {
+        let serial_byte = if i == 0 { receive(queue).await? } else { receive_with_timeout(queue, timeout_ticks).await? };
+        raw_data.push(serial_byte);
+        if serial_byte != png::PNG_SIGNATURE[i] {
+            // Invalid png, so print what it was and then return
+            log!("Invalid byte {:02x?}", serial_byte);
+            return None;
+        }
+    }
+    log!("Valid PNG signature");
+    loop {
+        let chunk_start: usize = raw_data.len();
+        let mut length: u32 = 0;
+        let mut type_arr: [u8; 4] = [0; 4];
+        for _ in 0..4 {
+            let new_byte: u8 = receive_with_timeout(queue, timeout_ticks).await?;
+            raw_data.push(new_byte);
+            length <<= 8;
+            length += new_byte as u32;
+        }
+        for i in 0..4 {
+            let new_byte: u8 = receive_with_timeout(queue, timeout_ticks).await?;
+            raw_data.push(new_byte);
+            type_arr[i] = new_byte;
+        }
+        let length: usize = length as usize;
+        if length > png::MAX_CHUNK_LENGTH || raw_data.len() + length + 4 > max_serial_png_size() {
+            // Refuse to buffer the chunk rather than exhausting the heap
+            log!("Chunk {:?} with length {} exceeds the size limit", type_arr, length);
+            return None;
+        }
+        for _ in 0..length+4 {  // include the four crc bytes
+            let new_byte: u8 = receive_with_timeout(queue, timeout_ticks).await?;
+            raw_data.push(new_byte);
+        }
+        if let Err(e) = png::verify_chunk_crc(&raw_data[chunk_start..]) {
+            // Reject a corrupt transfer as soon as the bad chunk arrives
+            log!("Chunk {:?} failed verification: {}", type_arr, e);
+            return None;
+        }
+        if &type_arr == "IEND".as_bytes() {
+            log!("Read IEND chunk, break from loop");
+            break;
+        } else if &type_arr == "IHDR".as_bytes() {
+            log!("Read IHDR chunk");
+        } else if &type_arr == "IDAT".as_bytes() {
+            log!("Read IDAT chunk");
+        } else {
+            log!("Read chunk with unexpected type: {:?}", type_arr);
+        }
+    }
+    return Some(raw_data);
+}
+
+/// Reads PNGs from the first serial port, and sends back a thumbnail of each,
+/// or the original image if no thumbnail can be generated. Exits qemu once a
+/// response has been sent.
+pub async fn serve_thumbnails() {
+    loop {
+        let raw_data = match read_serial_png(&COM1_QUEUE, READ_TIMEOUT_TICKS.load(Ordering::Relaxed)).await {
+            Some(data) => data,
+            None => continue,   // start over from the next byte
+        };
+        let options = png::ThumbnailOptions {
+            max_width: THUMBNAIL_MAX_WIDTH.load(Ordering::Relaxed),
+            max_height: THUMBNAIL_MAX_HEIGHT.load(Ordering::Relaxed),
+            zoom_to_fill: true,
+            ..Default::default()
+        };
+        let new_png: Vec<u8> = match png::generate_thumbnail(raw_data.clone(), &options) {
+            Ok(data) => data,
+            Err(e) => {
+                // A thumbnail cannot be computed, so pass the original image through
+                serial_println!("Error when generating thumbnail: {}", e);
+                raw_data
+            }
+        };
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            SERIAL1.lock().send_bytes(&new_png);
+        });
+        exit_qemu(QemuExitCode::Success);
+    }
+}
+
+#[test_case]
+fn test_read_serial_png_from_queue() {
+    use core::task::Context;
+    use futures_util::task::noop_waker;
+
+    let _ = COM1_QUEUE.try_init(PNG_QUEUE_CAPACITY);    // may already be initialized
+    while COM1_QUEUE.pop_byte().is_some() {}
+    COM1_QUEUE.take_dropped_bytes();
+
+    let image = include_bytes!("../../3x3.png");
+    for byte in image.iter() {
+        COM1_QUEUE.add_byte(*byte);
+    }
+
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut read = alloc::boxed::Box::pin(read_serial_png(&COM1_QUEUE, 1_000_000));
+    // every byte is already queued, so the whole PNG is read in one poll
+    assert_eq!(Pin::new(&mut read).poll(&mut context), Poll::Ready(Some(Vec::from(&image[..]))));
+    assert_eq!(COM1_QUEUE.pop_byte(), None);
+}
+
+#[test_case]
+fn test_read_serial_png_times_out_when_stalled() {
+    use core::task::Context;
+    use futures_util::task::noop_waker;
+
+    let _ = COM1_QUEUE.try_init(PNG_QUEUE_CAPACITY);    // may already be initialized
+    while COM1_QUEUE.pop_byte().is_some() {}
+    COM1_QUEUE.take_dropped_bytes();
+
+    let image = include_bytes!("../../3x3.png");
+    for byte in image[..image.len() / 2].iter() {
+        COM1_QUEUE.add_byte(*byte);
+    }
+
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    // a timeout of 0 ticks has already passed when the sender stalls halfway
+    let mut read = alloc::boxed::Box::pin(read_serial_png(&COM1_QUEUE, 0));
+    assert_eq!(Pin::new(&mut read).poll(&mut context), Poll::Ready(None));
+    assert_eq!(COM1_QUEUE.pop_byte(), None);
+}
+
+#[test_case]
+fn test_read_serial_png_rejects_dropped_bytes() {
+    use core::task::Context;
+    use futures_util::task::noop_waker;
+
+    static SMALL_QUEUE: ReceiveQueue = ReceiveQueue::new();
+    let _ = SMALL_QUEUE.try_init(16);   // may already be initialized
+
+    // the queue holds the signature and the start of IHDR, and drops the byte after
+    let image = include_bytes!("../../3x3.png");
+    for byte in image[..17].iter() {
+        SMALL_QUEUE.add_byte(*byte);
+    }
+
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut read = alloc::boxed::Box::pin(read_serial_png(&SMALL_QUEUE, 1_000_000));
+    // rejected at the first byte read, rather than at the CRC of the chunk
+    assert_eq!(Pin::new(&mut read).poll(&mut context), Poll::Ready(None));
+    assert_eq!(SMALL_QUEUE.take_dropped_bytes(), 0);    // counted once, by the reader
+    while SMALL_QUEUE.pop_byte().is_some() {}
+}
@@ -0,0 +1,126 @@
+/* Sleeping on the timer
+ *
+ * A task waits for a duration by awaiting sleep(ticks), which completes once
+ * interrupts::ticks() reaches its deadline. Each pending Sleep holds one of a
+ * fixed number of static slots, and the timer interrupt handler scans the
+ * slots on every tick, waking those whose deadline has passed. The slots are
+ * static so that the interrupt handler never allocates. If every slot is
+ * taken, a Sleep instead wakes itself on every poll until its deadline, which
+ * is still correct but keeps the executor busy.
+ */
+
+use crate::interrupts;
+use core::{future::Future, pin::Pin, task::{Context, Poll}, sync::atomic::{AtomicU64, Ordering}};
+use futures_util::task::AtomicWaker;
+
+const SLOT_COUNT: usize = 32;
+const FREE: u64 = 0;            // no deadline is 0, since a deadline is at least one tick away
+const ARMING: u64 = u64::MAX;   // claimed, but the waker is not yet registered
+
+static DEADLINES: [AtomicU64; SLOT_COUNT] = [const { AtomicU64::new(FREE) }; SLOT_COUNT];
+static WAKERS: [AtomicWaker; SLOT_COUNT] = [const { AtomicWaker::new() }; SLOT_COUNT];
+
+/// Called by the timer interrupt handler on every tick
+///
+/// Must not block or allocate.
+pub(crate) fn wake_expired(now: u64) {
+    for slot in 0..SLOT_COUNT {
+        let deadline = DEADLINES[slot].load(Ordering::Acquire);
+        if deadline != FREE && now >= deadline {    // ARMING is never reached
+            WAKERS[slot].wake();
+        }
+    }
+}
+
+/// A future which completes once the tick count reaches its deadline.
+pub struct Sleep {
+    deadline: u64,
+    slot: Option<usize>,
+}
+
+/// Returns a future which completes after the given number of timer ticks,
+/// at roughly 18.2 ticks per second (see interrupts::PIT_DIVISOR).
+pub fn sleep(ticks: u64) -> Sleep {
+    Sleep {
+        deadline: interrupts::ticks() + ticks,
+        slot: None,
+    }
+}
+
+impl Sleep {
+    fn claim_slot() -> Option<usize> {
+        (0..SLOT_COUNT).find(|&slot| {
+            DEADLINES[slot].compare_exchange(FREE, ARMING, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        })
+    }
+
+    fn release_slot(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            WAKERS[slot].take();
+            DEADLINES[slot].store(FREE, Ordering::Release);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if interrupts::ticks() >= self.deadline {
+            self.release_slot();
+            return Poll::Ready(());
+        }
+        match self.slot {
+            Some(slot) => WAKERS[slot].register(&cx.waker()),
+            None => match Sleep::claim_slot() {
+                Some(slot) => {
+                    // register before publishing the deadline, so a tick never sees it without a waker
+                    WAKERS[slot].register(&cx.waker());
+                    DEADLINES[slot].store(self.deadline, Ordering::Release);
+                    self.slot = Some(slot);
+                }
+                None => {
+                    cx.waker().wake_by_ref();   // no slot free, so poll again as soon as possible
+                    return Poll::Pending;
+                }
+            },
+        }
+        // the deadline may have passed before the waker was registered
+        if interrupts::ticks() >= self.deadline {
+            self.release_slot();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.release_slot();    // a cancelled sleep must not keep its slot
+    }
+}
+
+#[test_case]
+fn test_sleep_zero_ticks_is_ready() {
+    use futures_util::task::noop_waker;
+
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut sleep = sleep(0);
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Ready(()));
+    assert_eq!(sleep.slot, None);
+}
+
+#[test_case]
+fn test_sleep_slot_released_on_drop() {
+    use futures_util::task::noop_waker;
+
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut sleep = sleep(1_000_000);
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Pending);
+    let slot = sleep.slot.expect("pending sleep holds no slot");
+    assert_eq!(DEADLINES[slot].load(Ordering::Relaxed), sleep.deadline);
+    drop(sleep);
+    assert_eq!(DEADLINES[slot].load(Ordering::Relaxed), FREE);
+}
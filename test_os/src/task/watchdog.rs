@@ -0,0 +1,195 @@
+/* Watchdog for hung tasks
+ *
+ * Tasks are cooperative, so a task which spins without returning from poll
+ * stops every other task, and the kernel stops responding. When enabled, the
+ * watchdog is checked on every timer interrupt, and fires if the executor has
+ * been polling the same task for longer than the deadline. Since the task
+ * never yields, the watchdog acts from the interrupt handler: it reports the
+ * hung task over serial and then calls the configured action, which must not
+ * return, since returning would only resume the hung task.
+ *
+ * The interrupt handler cannot reach the executor, which the hung task is
+ * running inside of. So while the watchdog is enabled, the executor records a
+ * snapshot of its tasks before each pass over the ready tasks, and the
+ * watchdog dumps that snapshot along with its report.
+ */
+
+use super::{TaskId, executor::TaskDebugInfo};
+use crate::{serial_println, hlt_loop, interrupts};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+const NO_TASK: u64 = u64::MAX;
+
+static DEADLINE_TICKS: AtomicU64 = AtomicU64::new(0);  // 0 while disabled
+static RUNNING_TASK: AtomicU64 = AtomicU64::new(NO_TASK);
+static POLL_STARTED_TICK: AtomicU64 = AtomicU64::new(0);
+static ACTION: Mutex<fn(TaskId, u64) -> !> = Mutex::new(halt);
+
+const SNAPSHOT_TASKS: usize = 16;   // further live tasks are counted but not listed
+
+/// The executor's tasks as of the start of its latest pass over ready tasks.
+struct Snapshot {
+    live_tasks: usize,
+    queued_tasks: usize,
+    tasks: [Option<TaskDebugInfo>; SNAPSHOT_TASKS],
+}
+
+static SNAPSHOT: Mutex<Snapshot> = Mutex::new(Snapshot {
+    live_tasks: 0,
+    queued_tasks: 0,
+    tasks: [None; SNAPSHOT_TASKS],
+});
+
+/// Enables the watchdog, which calls the given action with the hung task and
+/// the number of ticks it has been running if any task runs for more than
+/// deadline_ticks timer ticks without yielding.
+pub fn enable(deadline_ticks: u64, action: fn(TaskId, u64) -> !) {
+    use x86_64::instructions::interrupts;
+
+    assert!(deadline_ticks > 0);
+    // The timer interrupt handler falls back to halt if ACTION is locked, so it must not
+    // interrupt us while locked
+    interrupts::without_interrupts(|| {
+        *ACTION.lock() = action;
+    });
+    DEADLINE_TICKS.store(deadline_ticks, Ordering::Relaxed);
+}
+
+pub fn disable() {
+    DEADLINE_TICKS.store(0, Ordering::Relaxed);
+}
+
+pub(crate) fn is_enabled() -> bool {
+    DEADLINE_TICKS.load(Ordering::Relaxed) > 0
+}
+
+/// The default action: stops the kernel, leaving the report on serial.
+pub fn halt(_task_id: TaskId, _running_ticks: u64) -> ! {
+    serial_println!("watchdog: halting");
+    hlt_loop();
+}
+
+/// Called by the executor just before polling a task.
+pub(crate) fn task_started(task_id: TaskId) {
+    POLL_STARTED_TICK.store(interrupts::ticks(), Ordering::Relaxed);
+    RUNNING_TASK.store(task_id.0, Ordering::Relaxed);
+}
+
+/// Called by the executor once a poll returns, whether or not the task completed.
+pub(crate) fn task_yielded() {
+    RUNNING_TASK.store(NO_TASK, Ordering::Relaxed);
+}
+
+/// Called by the executor before each pass over its ready tasks while the
+/// watchdog is enabled, with the state of its live tasks.
+pub(crate) fn record_snapshot(live_tasks: usize, queued_tasks: usize, tasks: &[TaskDebugInfo]) {
+    // The timer interrupt handler only try_locks SNAPSHOT, so it cannot deadlock with us
+    let mut snapshot = SNAPSHOT.lock();
+    snapshot.live_tasks = live_tasks;
+    snapshot.queued_tasks = queued_tasks;
+    for (i, slot) in snapshot.tasks.iter_mut().enumerate() {
+        *slot = tasks.get(i).copied();
+    }
+}
+
+/// Prints the last recorded snapshot over serial, as Executor::debug_dump
+/// prints the live tasks. Safe to call from the interrupt handler.
+fn dump_snapshot() {
+    let snapshot = match SNAPSHOT.try_lock() {
+        Some(snapshot) => snapshot,
+        None => {   // the executor was interrupted while recording, so it is not hung in a task
+            serial_println!("watchdog: task snapshot is being recorded");
+            return;
+        }
+    };
+    serial_println!("watchdog: before its last pass, executor had {} live tasks, {} queued",
+                    snapshot.live_tasks, snapshot.queued_tasks);
+    for info in snapshot.tasks.iter().flatten() {
+        serial_println!("  {}", info);
+    }
+    if snapshot.live_tasks > SNAPSHOT_TASKS {
+        serial_println!("  and {} more", snapshot.live_tasks - SNAPSHOT_TASKS);
+    }
+}
+
+/// Returns the running task and how long it has run, if that exceeds the deadline.
+fn overdue_task(now: u64) -> Option<(TaskId, u64)> {
+    let deadline_ticks = DEADLINE_TICKS.load(Ordering::Relaxed);
+    let running_task = RUNNING_TASK.load(Ordering::Relaxed);
+    if deadline_ticks == 0 || running_task == NO_TASK {
+        return None;
+    }
+    let running_ticks = now.saturating_sub(POLL_STARTED_TICK.load(Ordering::Relaxed));
+    if running_ticks > deadline_ticks {
+        Some((TaskId(running_task), running_ticks))
+    } else {
+        None
+    }
+}
+
+/// Called from the timer interrupt handler with the current tick count.
+pub(crate) fn check(now: u64) {
+    if let Some((task_id, running_ticks)) = overdue_task(now) {
+        disable();  // fire only once
+        serial_println!("watchdog: {:?} has run for {} ticks without yielding", task_id, running_ticks);
+        dump_snapshot();
+        // enable holds ACTION only with interrupts disabled, but never spin in an interrupt handler
+        let action = ACTION.try_lock().map(|action| *action).unwrap_or(halt);
+        action(task_id, running_ticks);
+    }
+}
+
+
+#[test_case]
+fn test_overdue_task_only_after_deadline() {
+    DEADLINE_TICKS.store(0, Ordering::Relaxed);
+    POLL_STARTED_TICK.store(100, Ordering::Relaxed);
+    RUNNING_TASK.store(7, Ordering::Relaxed);
+    assert_eq!(overdue_task(1000), None);   // disabled
+
+    DEADLINE_TICKS.store(10, Ordering::Relaxed);
+    assert_eq!(overdue_task(105), None);
+    assert_eq!(overdue_task(110), None);
+    assert_eq!(overdue_task(111), Some((TaskId(7), 11)));
+
+    RUNNING_TASK.store(NO_TASK, Ordering::Relaxed);
+    assert_eq!(overdue_task(1000), None);   // idle, not hung
+    DEADLINE_TICKS.store(0, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_executor_records_snapshot_while_enabled() {
+    use super::{Task, executor::Executor};
+    use core::task::Poll;
+    use futures_util::future::poll_fn;
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.spawn(Task::new(async move { spawner.shutdown() }));
+    executor.spawn(Task::new(poll_fn(|_| Poll::<()>::Pending)));
+    DEADLINE_TICKS.store(u64::MAX, Ordering::Relaxed);  // enabled, but never fires
+    executor.run();     // the first task shuts the executor down after a single pass
+    DEADLINE_TICKS.store(0, Ordering::Relaxed);
+
+    let snapshot = SNAPSHOT.lock();
+    assert_eq!((snapshot.live_tasks, snapshot.queued_tasks), (2, 2));
+    assert!(snapshot.tasks[..2].iter().all(|info| info.map(|info| info.last_poll) == Some(None)));
+    assert!(snapshot.tasks[2..].iter().all(|info| info.is_none()));
+    drop(snapshot);
+    dump_snapshot();
+}
+
+#[test_case]
+fn test_snapshot_lists_at_most_snapshot_tasks() {
+    let info = TaskDebugInfo { id: TaskId(3), waker_registered: true, last_poll: None };
+    record_snapshot(SNAPSHOT_TASKS + 4, 1, &[info; SNAPSHOT_TASKS + 4]);
+    assert_eq!(SNAPSHOT.lock().tasks, [Some(info); SNAPSHOT_TASKS]);
+    dump_snapshot();    // ends with the count of unlisted tasks
+
+    record_snapshot(1, 0, &[info]);
+    let snapshot = SNAPSHOT.lock();
+    assert_eq!(snapshot.live_tasks, 1);
+    assert_eq!(snapshot.tasks[0], Some(info));
+    assert!(snapshot.tasks[1..].iter().all(|info| info.is_none()));
+}
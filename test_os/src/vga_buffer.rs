@@ -42,7 +42,7 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
+pub(crate) const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
 #[repr(transparent)]  // Ensures same data layout as its field
@@ -54,9 +54,126 @@ struct Buffer {
     // cannot "optimize" away this write.
 }
 
+// The VGA CRT controller's registers are accessed by writing a register
+// index to the address port, then reading or writing the data port
+const CRTC_ADDRESS_PORT: u16 = 0x3d4;
+const CRTC_DATA_PORT: u16 = 0x3d5;
+const CURSOR_START_REGISTER: u8 = 0x0a;     // bit 5 disables the cursor
+const CURSOR_END_REGISTER: u8 = 0x0b;
+const CURSOR_LOCATION_HIGH_REGISTER: u8 = 0x0e;
+const CURSOR_LOCATION_LOW_REGISTER: u8 = 0x0f;
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+fn write_crtc(register: u8, value: u8) {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        Port::new(CRTC_ADDRESS_PORT).write(register);
+        Port::new(CRTC_DATA_PORT).write(value);
+    }
+}
+
+fn read_crtc(register: u8) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        Port::new(CRTC_ADDRESS_PORT).write(register);
+        Port::new(CRTC_DATA_PORT).read()
+    }
+}
+
+/// Shows the hardware cursor as a block covering the given scanlines of its
+/// cell, from 0 at the top to 15 at the bottom.
+pub fn enable_cursor(start_scanline: u8, end_scanline: u8) {
+    // the upper bits of each register are reserved, so preserve them
+    write_crtc(CURSOR_START_REGISTER, (read_crtc(CURSOR_START_REGISTER) & 0xc0) | (start_scanline & 0x1f));
+    write_crtc(CURSOR_END_REGISTER, (read_crtc(CURSOR_END_REGISTER) & 0xe0) | (end_scanline & 0x1f));
+}
+
+pub fn disable_cursor() {
+    write_crtc(CURSOR_START_REGISTER, CURSOR_DISABLE);
+}
+
+/// Returns the cell index, row * BUFFER_WIDTH + column, of the hardware cursor.
+pub fn cursor_position() -> u16 {
+    let high = read_crtc(CURSOR_LOCATION_HIGH_REGISTER) as u16;
+    let low = read_crtc(CURSOR_LOCATION_LOW_REGISTER) as u16;
+    high << 8 | low
+}
+
+pub const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// The number of lines which scrolled off the top of the screen that are kept.
+pub const SCROLLBACK_LINES: usize = 100;
+
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+/// Lines which scrolled off the screen, kept in normal memory rather than the
+/// VGA buffer. They are statically sized, since the writer is used before the
+/// heap is initialized.
+struct Scrollback {
+    lines: [Line; SCROLLBACK_LINES],    // a ring buffer of the most recent lines
+    start: usize,                       // index of the oldest line
+    len: usize,
+    offset: usize,                      // lines scrolled back from the live screen, 0 when showing it
+    live_screen: [Line; BUFFER_HEIGHT], // saved while scrolled back
+}
+
+impl Scrollback {
+    fn push(&mut self, line: Line) {
+        if self.len < SCROLLBACK_LINES {
+            self.lines[(self.start + self.len) % SCROLLBACK_LINES] = line;
+            self.len += 1;
+        } else {
+            self.lines[self.start] = line;  // overwrite the oldest line
+            self.start = (self.start + 1) % SCROLLBACK_LINES;
+        }
+    }
+
+    /// Returns a line of the history followed by the saved live screen,
+    /// counting from the oldest.
+    fn line(&self, index: usize) -> &Line {
+        if index < self.len {
+            &self.lines[(self.start + index) % SCROLLBACK_LINES]
+        } else {
+            &self.live_screen[index - self.len]
+        }
+    }
+}
+
+const MAX_CSI_PARAMS: usize = 4;   // further parameters are ignored
+
+/// Progress through an ANSI escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    Ground,     // not in an escape sequence
+    Escape,     // after ESC
+    Csi {       // after ESC [, reading numeric parameters separated by ;
+        params: [usize; MAX_CSI_PARAMS],
+        index: usize,
+    },
+}
+
+/// Maps the ANSI color numbers 0 to 7, as in SGR codes 30-37 and 40-47, to
+/// the closest VGA colors.
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,   // dark yellow in VGA
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+
 pub struct Writer {
     column_position: usize,     // Stores current position in row
+    row_position: usize,        // Row being written, the bottom row unless moved by an escape sequence
     color_code: ColorCode,      // Stores current foreground and background color
+    tab_width: usize,           // Tab stops are at multiples of this column
+    escape_state: EscapeState,
+    scrollback: Scrollback,
     buffer: &'static mut Buffer,    // buffer is valid for the whole program run time
 }
 
@@ -64,21 +181,44 @@ impl Writer {
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),  // printable ASCII byte or newline
+                0x20..=0x7e | b'\n' | b'\t' | 0x08 | 0x1b => self.write_byte(byte),  // printable ASCII byte, newline, tab, backspace, or escape
                 _ => self.write_byte(0xfe),  // not part of printable ASCII range
             }
         }
     }
 
     pub fn write_byte(&mut self, byte: u8) {
+        self.scroll_to_bottom();    // output always goes to the live screen
+        if self.escape_state != EscapeState::Ground || byte == 0x1b {
+            self.handle_escape(byte);
+            return;
+        }
         match byte {
             b'\n' => self.new_line(),
+            b'\t' => {
+                let tab_stop = (self.column_position / self.tab_width + 1) * self.tab_width;
+                if tab_stop > BUFFER_WIDTH {
+                    self.new_line();    // the next tab stop is beyond the end of the row
+                } else {
+                    while self.column_position < tab_stop {
+                        self.write_byte(b' ');
+                    }
+                }
+            }
+            0x08 => {
+                // erase the previous character, leaving the cursor on it; a no-op at the start of the row
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                    let blank = self.blank();
+                    self.buffer.chars[self.row_position][self.column_position].write(blank);
+                }
+            }
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
@@ -89,9 +229,103 @@ impl Writer {
                 self.column_position += 1;
             }
         }
+        self.update_cursor();
+    }
+
+    /// Consumes a byte of an ANSI escape sequence. Supports setting colors
+    /// with SGR (ESC [ n m, where n is 0, 30-37, or 40-47) and moving the
+    /// cursor with CUP (ESC [ row ; col H, counting from 1). Other sequences
+    /// are consumed without effect.
+    fn handle_escape(&mut self, byte: u8) {
+        self.escape_state = match (self.escape_state, byte) {
+            (_, 0x1b) => EscapeState::Escape,   // starts over
+            (EscapeState::Escape, b'[') => EscapeState::Csi { params: [0; MAX_CSI_PARAMS], index: 0 },
+            (EscapeState::Csi { mut params, index }, b'0'..=b'9') => {
+                if index < MAX_CSI_PARAMS {
+                    params[index] = (params[index] * 10 + (byte - b'0') as usize).min(9999);
+                }
+                EscapeState::Csi { params, index }
+            }
+            (EscapeState::Csi { params, index }, b';') => EscapeState::Csi { params, index: index + 1 },
+            (EscapeState::Csi { params, index }, 0x40..=0x7e) => {  // the final byte selects the command
+                let count = (index + 1).min(MAX_CSI_PARAMS);
+                match byte {
+                    b'm' => self.select_graphic_rendition(&params[..count]),
+                    b'H' | b'f' => self.move_cursor(params[0], params[1]),
+                    _ => {}
+                }
+                EscapeState::Ground
+            }
+            (state @ EscapeState::Csi { .. }, _) => state,  // e.g. private markers like ?
+            _ => EscapeState::Ground,   // an unsupported escape sequence, or one byte after ESC
+        };
+        self.update_cursor();
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[usize]) {
+        for &param in params {
+            let ColorCode(code) = self.color_code;
+            match param {
+                0 => self.set_color(Color::LightGray, Color::Black),
+                30..=37 => self.color_code = ColorCode((code & 0xf0) | ANSI_COLORS[param - 30] as u8),
+                40..=47 => self.color_code = ColorCode((code & 0x0f) | (ANSI_COLORS[param - 40] as u8) << 4),
+                _ => {}
+            }
+        }
+    }
+
+    /// Moves to the given row and column, counting from 1 as in CUP, where 0
+    /// also means the first. Positions off the screen are clamped to it.
+    fn move_cursor(&mut self, row: usize, col: usize) {
+        self.row_position = row.max(1).min(BUFFER_HEIGHT) - 1;
+        self.column_position = col.max(1).min(BUFFER_WIDTH) - 1;
+    }
+
+    /// Moves the hardware cursor to the cell the next byte will be written to.
+    fn update_cursor(&self) {
+        let col = self.column_position.min(BUFFER_WIDTH - 1);  // a full row wraps only on the next byte
+        let position = (self.row_position * BUFFER_WIDTH + col) as u16;
+        write_crtc(CURSOR_LOCATION_HIGH_REGISTER, (position >> 8) as u8);
+        write_crtc(CURSOR_LOCATION_LOW_REGISTER, position as u8);
+    }
+
+    /// Writes the byte at the given row and column, without moving the
+    /// cursor. Control bytes are displayed like other non-printable bytes.
+    pub fn write_byte_at(&mut self, row: usize, col: usize, byte: u8) {
+        assert!(row < BUFFER_HEIGHT && col < BUFFER_WIDTH, "position ({}, {}) is off the screen", row, col);
+        self.scroll_to_bottom();
+        let ascii_character = match byte {
+            0x20..=0x7e => byte,
+            _ => 0xfe,
+        };
+        self.buffer.chars[row][col].write(ScreenChar {
+            ascii_character,
+            color_code: self.color_code,
+        });
+    }
+
+    /// Writes the string starting at the given row and column, without moving
+    /// the cursor. Text beyond the end of the row is cut off.
+    pub fn write_string_at(&mut self, row: usize, col: usize, s: &str) {
+        assert!(row < BUFFER_HEIGHT && col < BUFFER_WIDTH, "position ({}, {}) is off the screen", row, col);
+        for (i, byte) in s.bytes().take(BUFFER_WIDTH - col).enumerate() {
+            self.write_byte_at(row, col + i, byte);
+        }
+    }
+
+    /// Returns the byte displayed at the given row and column.
+    pub(crate) fn char_at(&self, row: usize, col: usize) -> u8 {
+        self.buffer.chars[row][col].read().ascii_character
     }
 
     fn new_line(&mut self) {
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;     // moved up by an escape sequence, so there is room below
+            self.column_position = 0;
+            return;
+        }
+        let top_line: [ScreenChar; BUFFER_WIDTH] = core::array::from_fn(|col| self.buffer.chars[0][col].read());
+        self.scrollback.push(top_line);
         for row in 1..BUFFER_HEIGHT {  // Shift all rows "up" except the current top row
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
@@ -102,11 +336,76 @@ impl Writer {
         self.column_position = 0;
     }
     
-    fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
+    /// Sets the distance between tab stops, which must be positive.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        assert!(tab_width > 0);
+        self.tab_width = tab_width;
+    }
+
+    /// Sets the color of subsequently written characters.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    /// Blanks every row with the current color, and returns to the start of
+    /// the bottom row.
+    pub fn clear_screen(&mut self) {
+        self.scroll_to_bottom();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.row_position = BUFFER_HEIGHT - 1;
+        self.column_position = 0;
+        self.update_cursor();
+    }
+
+    /// Scrolls the screen back through the history by the given number of
+    /// lines, as far as the oldest line kept.
+    pub fn scroll_up(&mut self, lines: usize) {
+        if self.scrollback.offset == 0 {
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    self.scrollback.live_screen[row][col] = self.buffer.chars[row][col].read();
+                }
+            }
+        }
+        self.scrollback.offset = (self.scrollback.offset + lines).min(self.scrollback.len);
+        self.redraw();
+    }
+
+    /// Scrolls the screen forward by the given number of lines, as far as the
+    /// live screen.
+    pub fn scroll_down(&mut self, lines: usize) {
+        if self.scrollback.offset > 0 {
+            self.scrollback.offset = self.scrollback.offset.saturating_sub(lines);
+            self.redraw();
+        }
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll_down(self.scrollback.offset);
+    }
+
+    /// Draws the window of the history and live screen selected by the offset.
+    fn redraw(&mut self) {
+        let top = self.scrollback.len - self.scrollback.offset;
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let character = self.scrollback.line(top + row)[col];
+                self.buffer.chars[row][col].write(character);
+            }
+        }
+    }
+
+    fn blank(&self) -> ScreenChar {
+        ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
-        };
+        }
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        let blank = self.blank();
         for col in 0..BUFFER_WIDTH {
             self.buffer.chars[row][col].write(blank);
         }
@@ -131,10 +430,25 @@ impl fmt::Write for Writer {
 // calculate a static variable, which is exactly what we need.
 // However, it is still immutable. Thus, we use the spinlock Mutex, which adds
 // safe interior mutability to the static WRITER.
+const BLANK: ScreenChar = ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode(0x07),    // light gray on black
+};
+
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
+        row_position: BUFFER_HEIGHT - 1,
         color_code: ColorCode::new(Color::LightGray, Color::Black),
+        tab_width: DEFAULT_TAB_WIDTH,
+        escape_state: EscapeState::Ground,
+        scrollback: Scrollback {
+            lines: [[BLANK; BUFFER_WIDTH]; SCROLLBACK_LINES],
+            start: 0,
+            len: 0,
+            offset: 0,
+            live_screen: [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        },
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },  // This is the one piece of unsafe code
     });
 }
@@ -158,6 +472,68 @@ macro_rules! println {
     // the print! macro if we only want to use println!
 }
 
+/// Like println!, but in the given foreground and background colors, after
+/// which the previous color is restored.
+#[macro_export]
+macro_rules! println_colored {
+    ($foreground:expr, $background:expr, $($arg:tt)*) => (
+        $crate::vga_buffer::_print_colored($foreground, $background, format_args!("{}\n", format_args!($($arg)*)))
+    );
+}
+
+#[macro_export]
+macro_rules! clear {
+    () => ($crate::vga_buffer::clear_screen());
+}
+
+/// Sets the color of everything printed from now on.
+pub fn set_color(foreground: Color, background: Color) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().set_color(foreground, background);
+    });
+}
+
+/// Prints the string at the given row and column, e.g. for a status line,
+/// without moving the cursor.
+pub fn print_at(row: usize, col: usize, s: &str) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().write_string_at(row, col, s);
+    });
+}
+
+/// Scrolls the screen back through earlier output by the given number of
+/// lines. The next output returns to the live screen.
+pub fn scroll_up(lines: usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().scroll_up(lines);
+    });
+}
+
+/// Scrolls the screen forward towards the live screen by the given number of
+/// lines.
+pub fn scroll_down(lines: usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().scroll_down(lines);
+    });
+}
+
+/// Blanks the whole screen.
+pub fn clear_screen() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().clear_screen();
+    });
+}
+
 #[doc(hidden)]  // Needs to be public to allow macros to work, but it's internal, so hide it from documentation
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
@@ -170,6 +546,20 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+#[doc(hidden)]
+pub fn _print_colored(foreground: Color, background: Color, args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();     // held throughout, so no other output takes the color
+        let previous = writer.color_code;
+        writer.set_color(foreground, background);
+        writer.write_fmt(args).unwrap();
+        writer.color_code = previous;
+    });
+}
+
 #[test_case]
 fn test_println_simple() {
     println!("It's a simple spell, but quite unbreakable.");
@@ -197,3 +587,171 @@ fn test_println_output() {
         }
     });
 }
+
+#[test_case]
+fn test_clear_screen() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "Some text\nto clear").expect("writeln failed");
+        writer.write_string("partial line");
+        writer.clear_screen();
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let screen_char = writer.buffer.chars[row][col].read();
+                assert_eq!(screen_char.ascii_character, b' ');
+                assert_eq!(screen_char.color_code, writer.color_code);
+            }
+        }
+        assert_eq!(writer.column_position, 0);
+    });
+}
+
+#[test_case]
+fn test_set_color() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let previous = writer.color_code;
+        writer.write_byte(b'\n');
+        writer.set_color(Color::Red, Color::Black);
+        writer.write_byte(b'X');
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 1][0].read();
+        assert_eq!(screen_char.ascii_character, b'X');
+        assert_eq!(screen_char.color_code, ColorCode::new(Color::Red, Color::Black));
+        assert_eq!(screen_char.color_code.0, 0x04);
+        writer.color_code = previous;
+    });
+}
+
+#[test_case]
+fn test_cursor_follows_output() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\nabc");
+        let bottom_row_start = ((BUFFER_HEIGHT - 1) * BUFFER_WIDTH) as u16;
+        assert_eq!(cursor_position(), bottom_row_start + 3);
+        writer.write_byte(b'\n');
+        assert_eq!(cursor_position(), bottom_row_start);
+    });
+}
+
+#[test_case]
+fn test_backspace_erases_previous_character() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\nab\x08");
+        assert_eq!(writer.char_at(BUFFER_HEIGHT - 1, 0), b'a');
+        assert_eq!(writer.char_at(BUFFER_HEIGHT - 1, 1), b' ');
+        assert_eq!(writer.column_position, 1);
+        writer.write_string("\x08\x08");   // the second backspace is at column 0
+        assert_eq!(writer.char_at(BUFFER_HEIGHT - 1, 0), b' ');
+        assert_eq!(writer.column_position, 0);
+    });
+}
+
+#[test_case]
+fn test_tab_advances_to_tab_stop() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\na\tb");
+        assert_eq!(writer.char_at(BUFFER_HEIGHT - 1, DEFAULT_TAB_WIDTH), b'b');
+        for col in 1..DEFAULT_TAB_WIDTH {
+            assert_eq!(writer.char_at(BUFFER_HEIGHT - 1, col), b' ');
+        }
+        writer.set_tab_width(4);
+        writer.write_byte(b'\t');     // from column 9 to 12
+        assert_eq!(writer.column_position, 12);
+        writer.set_tab_width(DEFAULT_TAB_WIDTH);
+    });
+}
+
+#[test_case]
+fn test_write_string_at_position() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let column_position = writer.column_position;
+        writer.write_string_at(0, 10, "status");
+        for (i, byte) in "status".bytes().enumerate() {
+            assert_eq!(writer.char_at(0, 10 + i), byte);
+        }
+        assert_eq!(writer.column_position, column_position);
+
+        let next_row_start = writer.char_at(2, 0);
+        writer.write_string_at(1, BUFFER_WIDTH - 2, "cut off");
+        assert_eq!(writer.char_at(1, BUFFER_WIDTH - 1), b'u');
+        assert_eq!(writer.char_at(2, 0), next_row_start);  // the rest does not wrap
+    });
+}
+
+#[test_case]
+fn test_scroll_up_shows_earlier_lines() {
+    use alloc::format;
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    // whether row 0 shows "line {number}"
+    fn top_row_shows(writer: &Writer, number: usize) -> bool {
+        let expected = format!("line {} ", number);
+        expected.bytes().enumerate().all(|(col, byte)| writer.char_at(0, col) == byte)
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for i in 0..50 {
+            writeln!(writer, "line {}", i).expect("writeln failed");
+        }
+        // the bottom row is empty, so the rows above show lines 26 to 49
+        assert!(top_row_shows(&writer, 26));
+        writer.scroll_up(10);
+        assert!(top_row_shows(&writer, 16));
+        writer.scroll_down(5);
+        assert!(top_row_shows(&writer, 21));
+        writer.write_string("x");  // output returns to the live screen
+        assert!(top_row_shows(&writer, 26));
+        assert_eq!(writer.char_at(BUFFER_HEIGHT - 1, 0), b'x');
+    });
+}
+
+#[test_case]
+fn test_ansi_color_sequence() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let previous = writer.color_code;
+        writer.write_string("\n\x1b[31mX\x1b[0m");
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 1][0].read();
+        assert_eq!(screen_char.ascii_character, b'X');
+        assert_eq!(screen_char.color_code, ColorCode::new(Color::Red, Color::Black));
+        assert_eq!(writer.column_position, 1);  // the sequences themselves print nothing
+        assert_eq!(writer.color_code, ColorCode::new(Color::LightGray, Color::Black));
+        writer.color_code = previous;
+    });
+}
+
+#[test_case]
+fn test_ansi_cursor_position_sequence() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\x1b[3;5HY\x1b[?25l");   // the second sequence is unsupported
+        assert_eq!(writer.char_at(2, 4), b'Y');
+        assert_eq!((writer.row_position, writer.column_position), (2, 5));
+        assert_eq!(cursor_position(), (2 * BUFFER_WIDTH + 5) as u16);
+        writer.write_string("\x1b[25;1H");    // back to the bottom row
+        assert_eq!(writer.row_position, BUFFER_HEIGHT - 1);
+    });
+}
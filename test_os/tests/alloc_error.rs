@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{alloc::Layout, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{fmt::{self, Write}, panic::PanicInfo};
+use spin::Mutex;
+use test_os::{exit_qemu, QemuExitCode, MessageBuffer, serial_print, serial_println};
+use test_os::allocator::{self, HeapConfig, HEAP_START};
+
+entry_point!(main);
+
+const SMALL_HEAP: HeapConfig = HeapConfig { start: HEAP_START, size: 1024 * 1024 };
+const CHUNK_SIZE: usize = 16 * 1024;   // small enough to come from the heap, not the large region
+
+static REPORT: Mutex<MessageBuffer> = Mutex::new(MessageBuffer::new());
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("alloc_error::report_on_exhausted_heap...\t");
+
+    test_os::init_with_heap(boot_info, SMALL_HEAP);
+    allocator::set_alloc_error_writer(capture_report);
+    allocator::set_alloc_error_action(check_alloc_error);
+
+    // room for every chunk up front, so that only the chunks themselves can fail
+    let mut chunks: Vec<Vec<u8>> = Vec::with_capacity(SMALL_HEAP.size / CHUNK_SIZE);
+    while chunks.len() < chunks.capacity() {
+        chunks.push(Vec::with_capacity(CHUNK_SIZE));
+    }
+
+    serial_println!("[no allocation error after {} chunks]", chunks.len());
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// Collects the report instead of printing it, without allocating.
+fn capture_report(args: fmt::Arguments) {
+    let _ = REPORT.lock().write_fmt(args);
+}
+
+/// Called once the report has been written, rather than halting.
+fn check_alloc_error(layout: Layout) -> ! {
+    assert_eq!(layout.size(), CHUNK_SIZE);
+    let report = REPORT.lock();
+    // a literal, since the heap has no room left to format one
+    let expected = "allocation error: 16384 bytes aligned to 1 from the linked list fallback";
+    assert!(report.contains(expected), "unexpected report: {:?}", core::str::from_utf8(report.as_bytes()));
+    assert!(report.contains("bytes outstanding"));
+    assert!(report.contains("free blocks:"));
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::test_panic_handler(info)
+}
@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use test_os::task::{Task, executor::Executor, serial};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("application_default_input::run_without_host_input...\t");
+    test_os::init(boot_info);
+
+    serial::set_run_timeout(1);     // the host sends nothing, so give up on the next tick
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(serial::handle_commands(application)));
+    executor.run();
+    test_os::hlt_loop();
+}
+
+async fn application(input: u32) {
+    if input == serial::DEFAULT_APPLICATION_INPUT {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[application received {} instead of {}]", input, serial::DEFAULT_APPLICATION_INPUT);
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::test_panic_handler(info)
+}
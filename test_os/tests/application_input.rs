@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use test_os::task::{Task, executor::Executor, serial};
+
+entry_point!(main);
+
+const INPUT: u32 = 7;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("application_input::run_with_host_input...\t");
+    test_os::init(boot_info);
+
+    for byte in b"run 7\n" {
+        serial::add_byte(*byte);    // simulate the host sending a command over COM2
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(serial::handle_commands(application)));
+    executor.run();
+    test_os::hlt_loop();
+}
+
+async fn application(input: u32) {
+    if input == INPUT {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[application received {} instead of {}]", input, INPUT);
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::test_panic_handler(info)
+}
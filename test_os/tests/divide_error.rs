@@ -0,0 +1,30 @@
+#![no_std]
+#![no_main]
+
+use core::{arch::asm, panic::PanicInfo};
+use test_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("divide_error::divide_error...\t");
+
+    test_os::gdt::init();
+    test_os::interrupts::init_idt();    // the kernel's IDT, whose handler should panic
+
+    unsafe {
+        asm!(
+            "xor ecx, ecx",
+            "div ecx",  // divides edx:eax by zero
+            out("eax") _, out("ecx") _, out("edx") _,
+        );
+    }
+
+    serial_println!("[no exception]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::expected_panic_handler(info, "EXCEPTION: DIVIDE ERROR")
+}
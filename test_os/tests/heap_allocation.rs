@@ -8,23 +8,13 @@ extern crate alloc;
 
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use test_os::allocator::HEAP_SIZE;
+use test_os::allocator::{self, HEAP_SIZE};
 use alloc::{boxed::Box, vec::Vec};
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> !{
-    use test_os::allocator;
-    use test_os::memory::{self, BootInfoFrameAllocator};
-    use x86_64::VirtAddr;
-
-    test_os::init();
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heapp initialization failed");
+    test_os::init(boot_info);   // also initializes the heap
 
     test_main();
     loop {}
@@ -70,3 +60,119 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1);
 }
+
+#[test_case]
+fn large_allocation_through_paging_fallback() {
+    let size = 8 * 1024 * 1024;
+    let mut buffer: Vec<u8> = Vec::with_capacity(size);
+    let start = buffer.as_ptr() as usize;
+    assert!(start >= allocator::LARGE_HEAP_START);
+    assert!(start < allocator::LARGE_HEAP_START + allocator::LARGE_HEAP_SIZE);
+    for i in 0..size {
+        buffer.push(i as u8);   // touches every page, mapping each on demand
+    }
+    for page in (0..size).step_by(4096) {
+        assert_eq!(buffer[page], page as u8);
+    }
+}
+
+#[test_case]
+fn large_allocation_reuses_freed_range() {
+    let size = 1024 * 1024;
+    let first = Vec::<u8>::with_capacity(size);
+    let first_start = first.as_ptr();
+    drop(first);
+    let second = Vec::<u8>::with_capacity(size);
+    assert_eq!(second.as_ptr(), first_start);
+}
+
+#[test_case]
+fn realloc_within_block_size_keeps_pointer() {
+    use alloc::alloc::{alloc, dealloc, realloc, Layout};
+
+    unsafe {
+        let layout = Layout::from_size_align(17, 1).unwrap();  // served by 32-byte blocks
+        let ptr = alloc(layout);
+        ptr.write(7);
+        let grown = realloc(ptr, layout, 32);
+        assert_eq!(grown, ptr);
+        let moved = realloc(grown, Layout::from_size_align(32, 1).unwrap(), 33);    // needs a 64-byte block
+        assert_ne!(moved, ptr);
+        assert_eq!(moved.read(), 7);    // contents are copied to the new block
+        dealloc(moved, Layout::from_size_align(33, 1).unwrap());
+    }
+}
+
+#[test_case]
+fn stats_track_outstanding_bytes() {
+    let before = allocator::stats();
+    let boxed = Box::new([0u8; 100]);
+    let vec: Vec<u8> = Vec::with_capacity(1000);
+    let during = allocator::stats();
+    assert_eq!(during.bytes_outstanding - before.bytes_outstanding, 1100);
+    assert_eq!(during.bytes_allocated - before.bytes_allocated, 1100);
+    drop(boxed);
+    drop(vec);
+    let after = allocator::stats();
+    assert_eq!(after.bytes_outstanding, before.bytes_outstanding);
+    assert_eq!(after.bytes_freed - before.bytes_freed, 1100);
+}
+
+#[test_case]
+fn translate_heap_address() {
+    use test_os::memory::{self, MEMORY_MANAGER};
+    use x86_64::VirtAddr;
+    use x86_64::structures::paging::Translate;
+
+    let heap_value = Box::new(0x_1234_5678_u64);
+    let manager = MEMORY_MANAGER.lock();
+    let mapper = &manager.as_ref().expect("memory manager not initialized").mapper;
+    let virt = VirtAddr::from_ptr(&*heap_value);
+    let phys = memory::translate_addr(virt, mapper).expect("heap address is not mapped");
+    assert_eq!(phys.as_u64() % 4096, virt.as_u64() % 4096);
+    // all of physical memory is also mapped at the offset, so the value is visible there too
+    let alias = (mapper.phys_offset() + phys.as_u64()).as_ptr::<u64>();
+    assert_eq!(unsafe { alias.read_volatile() }, 0x_1234_5678);
+    assert_eq!(Some(phys), mapper.translate_addr(virt));    // agrees with the full page table walk
+
+    let past_heap = VirtAddr::new((allocator::HEAP_START + HEAP_SIZE) as u64);
+    assert_eq!(memory::translate_addr(past_heap, mapper), None);
+}
+
+#[test_case]
+fn heap_is_mapped_with_huge_pages() {
+    use test_os::{allocator::HEAP_START, memory::MEMORY_MANAGER};
+    use x86_64::VirtAddr;
+    use x86_64::structures::paging::{Translate, mapper::{MappedFrame, TranslateResult}};
+
+    let manager = MEMORY_MANAGER.lock();
+    let mapper = &manager.as_ref().expect("memory manager not initialized").mapper;
+    let mut entries = 0;
+    let mut addr = HEAP_START as u64;
+    while addr < (HEAP_START + HEAP_SIZE) as u64 {
+        let page_size = match mapper.translate(VirtAddr::new(addr)) {
+            TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), .. } => 2 * 1024 * 1024,
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), .. } => 4096,
+            _ => panic!("heap address {:#x} is not mapped with 4KiB or 2MiB pages", addr),
+        };
+        addr = (addr / page_size + 1) * page_size;  // the start of the next page
+        entries += 1;
+    }
+    // only the unaligned start and end of the heap need 4KiB pages
+    assert!(entries < allocator::heap_config().expect("heap not initialized").page_total() / 10, "heap uses {} page table entries", entries);
+}
+
+#[cfg(feature = "poison-freed-blocks")]
+#[test_case]
+fn freed_block_is_poisoned() {
+    use core::mem::size_of;
+    use test_os::allocator::fixed_size_block::POISON_BYTE;
+
+    let block = Box::new([0u8; 32]);   // exactly fills a 32-byte block
+    let ptr = &*block as *const [u8; 32] as *const u8;
+    drop(block);
+    // the start of the free block holds the free list's next pointer
+    for offset in size_of::<usize>()..32 {
+        assert_eq!(unsafe { ptr.add(offset).read_volatile() }, POISON_BYTE);
+    }
+}
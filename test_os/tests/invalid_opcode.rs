@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+
+use core::{arch::asm, panic::PanicInfo};
+use test_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("invalid_opcode::invalid_opcode...\t");
+
+    test_os::gdt::init();
+    test_os::interrupts::init_idt();    // the kernel's IDT, whose handler should panic
+
+    unsafe {
+        asm!("ud2");    // an instruction defined to be invalid
+    }
+
+    serial_println!("[no exception]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::expected_panic_handler(info, "EXCEPTION: INVALID OPCODE")
+}
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(test_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const LAZY_START: u64 = 0x_5555_0000_0000;
+const LAZY_SIZE: u64 = 16 * 4096;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    test_os::init(boot_info);
+    test_os::memory::register_lazy_region(VirtAddr::new(LAZY_START), LAZY_SIZE);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn lazily_mapped_page_is_zeroed() {
+    let ptr = LAZY_START as *const u64;
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+}
+
+#[test_case]
+fn execution_continues_after_lazy_fault() {
+    for page in 1..(LAZY_SIZE / 4096) {
+        let ptr = (LAZY_START + page * 4096 + 8) as *mut u64;
+        unsafe {
+            ptr.write_volatile(page);
+            assert_eq!(ptr.read_volatile(), page);
+        }
+    }
+}
@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use test_os::task::{Task, executor::Executor, serial};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("serial_commands::shutdown...\t");
+    test_os::init(boot_info);
+
+    for byte in b"shutdown\n" {
+        serial::add_byte(*byte);    // simulate the host sending a command over COM2
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(serial::handle_commands(|_| async {})));
+    executor.spawn(Task::new(fail_if_still_running()));
+    executor.run();
+    test_os::hlt_loop();
+}
+
+/// Runs after the command handler has consumed all queued input, so reaching
+/// this point means the shutdown command was not honored.
+async fn fail_if_still_running() {
+    serial_println!("[kernel did not shut down]");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::test_panic_handler(info)
+}
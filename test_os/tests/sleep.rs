@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_os::{exit_qemu, QemuExitCode, serial_print, serial_println, interrupts};
+use test_os::task::{Task, executor::Executor, timer::sleep};
+
+entry_point!(main);
+
+const SLEEP_TICKS: u64 = 3;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("sleep::wakes_after_ticks...\t");
+    test_os::init(boot_info);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(sleeper()));
+    executor.run();
+    test_os::hlt_loop();
+}
+
+async fn sleeper() {
+    let start = interrupts::ticks();
+    sleep(SLEEP_TICKS).await;
+    let elapsed = interrupts::ticks() - start;
+    if elapsed >= SLEEP_TICKS && elapsed <= SLEEP_TICKS + 1 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[slept {} ticks instead of {}]", elapsed, SLEEP_TICKS);
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::test_panic_handler(info)
+}
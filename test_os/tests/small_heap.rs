@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(test_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_os::allocator::{self, HeapConfig, HEAP_START};
+use alloc::vec::Vec;
+
+entry_point!(main);
+
+const SMALL_HEAP: HeapConfig = HeapConfig { start: HEAP_START, size: 1024 * 1024 };
+const CHUNK_SIZE: usize = 16 * 1024;   // small enough to come from the heap, not the large region
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    test_os::init_with_heap(boot_info, SMALL_HEAP);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn heap_config_is_recorded() {
+    assert_eq!(allocator::heap_config(), Some(SMALL_HEAP));
+    assert_eq!(allocator::heap_config().map(|config| config.page_total()), Some(256));
+}
+
+#[test_case]
+fn allocations_fail_past_capacity() {
+    let max_chunks = SMALL_HEAP.size / CHUNK_SIZE;
+    let mut chunks: Vec<Vec<u8>> = Vec::with_capacity(max_chunks);
+    loop {
+        let mut chunk: Vec<u8> = Vec::new();
+        // try_reserve reports the failure instead of calling the alloc error handler
+        if chunk.try_reserve_exact(CHUNK_SIZE).is_err() {
+            break;
+        }
+        assert!(chunks.len() < max_chunks, "allocated more than the heap holds");
+        chunks.push(chunk);
+    }
+    // the free lists and the list of chunks take some of the heap
+    assert!(chunks.len() >= max_chunks / 2, "only {} chunks fit", chunks.len());
+
+    // freeing the chunks makes room again
+    drop(chunks);
+    let mut chunk: Vec<u8> = Vec::new();
+    assert!(chunk.try_reserve_exact(CHUNK_SIZE).is_ok());
+}
@@ -2,23 +2,43 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
-use core::panic::PanicInfo;
-use test_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use core::{arch::asm, panic::PanicInfo};
+use test_os::{exit_qemu, QemuExitCode, serial_print, serial_println, gdt};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    serial_print!("stack_overflow::stack_overflow...\t");
+    serial_print!("stack_overflow::page_fault_on_dedicated_stack...\t");
 
-    test_os::gdt::init();
-    init_test_idt();    // use this instead of interrupts::init_idt in order to register custom double fault handler
+    gdt::init();
+    init_test_idt();    // use this instead of interrupts::init_idt in order to register custom fault handlers
 
     stack_overflow();
 
     panic!("Execution continued after stack overflow");
 }
 
+/// Checks that the guard page fault was delivered on the page fault stack,
+/// then causes a double fault to check that its stack works too.
+extern "x86-interrupt" fn test_page_fault_handler(stack_frame: InterruptStackFrame, _error_code: PageFaultErrorCode) {
+    use x86_64::registers::control::Cr2;
+
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp); }
+    let stack_top = gdt::interrupt_stack_top(gdt::PAGE_FAULT_IST_INDEX).as_u64();
+    assert!(rsp < stack_top && rsp >= stack_top - gdt::PAGE_FAULT_STACK_SIZE as u64,
+            "page fault handler is not on its dedicated stack");
+    // the faulting access was to the guard page, just below the overflowing stack
+    let fault_distance = Cr2::read().as_u64() as i64 - stack_frame.stack_pointer.as_u64() as i64;
+    assert!(fault_distance.abs() < 4096, "page fault was not on the stack's guard page");
+    serial_println!("[ok]");
+
+    serial_print!("stack_overflow::double_fault_on_dedicated_stack...\t");
+    unsafe { asm!("int3"); }    // the breakpoint gate is not present, and neither is that of the resulting fault
+    panic!("Execution continued after double fault");
+}
+
 extern "x86-interrupt" fn test_double_fault_handler(_stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
     serial_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
@@ -29,8 +49,10 @@ lazy_static! {
     static ref TEST_IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         unsafe {
+            idt.page_fault.set_handler_fn(test_page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
             idt.double_fault.set_handler_fn(test_double_fault_handler)
-                .set_stack_index(test_os::gdt::DOUBLE_FAULT_IST_INDEX);
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt
     };
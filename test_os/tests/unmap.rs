@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_os::{exit_qemu, QemuExitCode, serial_print, serial_println, memory::{self, MEMORY_MANAGER}};
+use lazy_static::lazy_static;
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::UnmapError},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+const TEST_PAGE: u64 = 0x_7777_0000_0000;   // outside of the heap and lazy regions
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("unmap::access_after_unmap_faults...\t");
+
+    test_os::init(boot_info);
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(TEST_PAGE));
+    let ptr = TEST_PAGE as *mut u64;
+    {
+        let mut manager = MEMORY_MANAGER.lock();
+        let memory::MemoryManager { mapper, frame_allocator } = manager.as_mut().expect("memory manager not initialized");
+        let frame = frame_allocator.allocate_frame().expect("no frames left");
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator).expect("could not map test page").flush();
+            ptr.write_volatile(42);
+        }
+        assert_eq!(unsafe { ptr.read_volatile() }, 42);
+
+        unsafe {
+            memory::unmap_pages(page, 1, mapper, frame_allocator).expect("could not unmap test page");
+            assert!(matches!(memory::unmap_pages(page, 1, mapper, frame_allocator), Err(UnmapError::PageNotMapped)));
+        }
+        assert_eq!(memory::translate_addr(VirtAddr::new(TEST_PAGE), mapper), None);
+        // the freed frame is reused first
+        assert_eq!(frame_allocator.allocate_frame(), Some(frame));
+    }
+
+    x86_64::instructions::interrupts::disable();    // the test IDT has no handlers for them
+    init_test_idt();
+    unsafe { ptr.read_volatile(); }
+
+    serial_println!("[no page fault]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(_stack_frame: InterruptStackFrame, _error_code: PageFaultErrorCode) {
+    use x86_64::registers::control::Cr2;
+
+    assert_eq!(Cr2::read(), VirtAddr::new(TEST_PAGE));
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::test_panic_handler(info)
+}
@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use test_os::task::{Task, TaskId, executor::Executor, watchdog};
+
+entry_point!(main);
+
+const DEADLINE_TICKS: u64 = 5;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("watchdog::runaway_task...\t");
+    test_os::init(boot_info);
+
+    watchdog::enable(DEADLINE_TICKS, watchdog_fired);
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(runaway_task()));
+    executor.run();
+    serial_println!("[runaway task returned]");
+    exit_qemu(QemuExitCode::Failed);
+    test_os::hlt_loop();
+}
+
+async fn runaway_task() {
+    loop {}     // never yields
+}
+
+fn watchdog_fired(_task_id: TaskId, running_ticks: u64) -> ! {
+    // Fires on the first timer tick after the deadline passes
+    if running_ticks == DEADLINE_TICKS + 1 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[fired after {} ticks instead of {}]", running_ticks, DEADLINE_TICKS + 1);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    test_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_os::test_panic_handler(info)
+}